@@ -0,0 +1,709 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Stdout, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::Ordering},
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+use crate::{
+    http::Request,
+    redaction::Redactor,
+    statics::{REOPEN_ACCESS_LOG, SHUTDOWN_SERVER},
+    telemetry::force_export_telemetry,
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    // host ident authuser [time] "request line" status bytes
+    Common,
+    // common plus the referer and user agent
+    Combined,
+    // one JSON object per line
+    Json,
+    // `template` with $variables replaced
+    Custom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogOutput {
+    Stdout,
+    File,
+    // log records through the OpenTelemetry log bridge, with the fields as attributes
+    Otel,
+    None,
+}
+
+// see [access_log] in config.example.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    // ex: "$remote_addr $method $path $status $duration_ms"
+    pub template: Option<String>,
+    pub output: AccessLogOutput,
+    // required with the file output, reopened on SIGUSR1 after logrotate moves it
+    pub path: Option<PathBuf>,
+    // lines are written once this much is buffered or every flush interval
+    pub buffer_bytes: usize,
+    pub flush_interval_ms: u64,
+    // request headers added to JSON lines and available to templates as $http_<name>,
+    // every other header is left out of the log
+    pub headers: Vec<String>,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        return AccessLogConfig {
+            format: AccessLogFormat::Combined,
+            template: None,
+            output: AccessLogOutput::Otel,
+            path: None,
+            buffer_bytes: 64 * 1024,
+            flush_interval_ms: 1000,
+            headers: Vec::new(),
+        };
+    }
+}
+
+// what's known about a request once its response is written
+pub struct AccessLogEntry<'a> {
+    pub time: SystemTime,
+    // "-" for unix socket peers
+    pub remote_address: String,
+    pub listener: &'a str,
+    pub request: &'a Request,
+    pub status: u16,
+    pub body_bytes: u64,
+    pub duration: Duration,
+    pub request_id: Option<&'a str>,
+}
+
+enum TemplatePart {
+    Literal(String),
+    Variable(String),
+}
+
+enum Sink {
+    Stdout(Mutex<BufWriter<Stdout>>),
+    File {
+        path: PathBuf,
+        writer: Mutex<BufWriter<File>>,
+    },
+    Otel,
+    None,
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    template: Vec<TemplatePart>,
+    // lowercase names
+    headers: Vec<String>,
+    redactor: Arc<Redactor>,
+    sink: Arc<Sink>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig, redactor: Arc<Redactor>) -> Self {
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        let template = match (config.format, &config.template) {
+            (AccessLogFormat::Custom, Some(template)) => parse_template(template, &headers),
+            (AccessLogFormat::Custom, None) => {
+                error!("Custom access log format configured without a template");
+                force_export_telemetry(false);
+                panic!("Custom access log format configured without a template");
+            }
+            _ => Vec::new(),
+        };
+
+        let sink = match (config.output, &config.path) {
+            (AccessLogOutput::Stdout, _) => Sink::Stdout(Mutex::new(BufWriter::with_capacity(
+                config.buffer_bytes,
+                io::stdout(),
+            ))),
+            (AccessLogOutput::File, Some(path)) => Sink::File {
+                path: path.clone(),
+                writer: Mutex::new(BufWriter::with_capacity(
+                    config.buffer_bytes,
+                    open_log_file(path),
+                )),
+            },
+            (AccessLogOutput::File, None) => {
+                error!("File access log configured without a path");
+                force_export_telemetry(false);
+                panic!("File access log configured without a path");
+            }
+            (AccessLogOutput::Otel, _) => Sink::Otel,
+            (AccessLogOutput::None, _) => Sink::None,
+        };
+        let sink = Arc::new(sink);
+
+        if matches!(*sink, Sink::Stdout(_) | Sink::File { .. }) {
+            let flush_sink = sink.clone();
+            let flush_interval = Duration::from_millis(config.flush_interval_ms);
+            std::thread::spawn(move || {
+                loop {
+                    sleep(flush_interval);
+                    if REOPEN_ACCESS_LOG.swap(false, Ordering::SeqCst) {
+                        flush_sink.reopen();
+                    }
+                    flush_sink.flush();
+                    if let Ok(flag) = SHUTDOWN_SERVER.read()
+                        && *flag
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+
+        return AccessLog {
+            format: config.format,
+            template,
+            headers,
+            redactor,
+            sink,
+        };
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let line = self.line(entry);
+        let result = match &*self.sink {
+            Sink::Stdout(writer) => write_line(writer, &line),
+            Sink::File { writer, .. } => write_line(writer, &line),
+            Sink::Otel => {
+                let request = entry.request;
+                info!(
+                    target: "access_log",
+                    remote_address = entry.remote_address.as_str(),
+                    listener = entry.listener,
+                    method = request.method.as_str(),
+                    path = request.path.as_str(),
+                    status = entry.status,
+                    body_bytes = entry.body_bytes,
+                    duration_ms = entry.duration.as_secs_f64() * 1000.0,
+                    request_id = entry.request_id;
+                    "{}", line
+                );
+                Ok(())
+            }
+            Sink::None => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!(error = e.to_string().as_str(); "Couldn't write to the access log");
+        }
+    }
+
+    fn line(&self, entry: &AccessLogEntry) -> String {
+        return match self.format {
+            AccessLogFormat::Common => common_line(entry, &self.redactor),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                common_line(entry, &self.redactor),
+                escape_quoted(
+                    &referer(entry.request, &self.redactor).unwrap_or_else(|| "-".to_string())
+                ),
+                escape_quoted(entry.request.header("user-agent").unwrap_or("-"))
+            ),
+            AccessLogFormat::Json => self.json_line(entry),
+            AccessLogFormat::Custom => self.template_line(entry),
+        };
+    }
+
+    // writes out buffered lines, called at shutdown once the request handlers have finished
+    pub fn flush(&self) {
+        self.sink.flush();
+    }
+
+    fn json_line(&self, entry: &AccessLogEntry) -> String {
+        let request = entry.request;
+        let mut line = json!({
+            "time": iso8601_time(entry.time),
+            "remote_addr": entry.remote_address,
+            "listener": entry.listener,
+            "method": request.method,
+            "path": request.path,
+            "query": query(request, &self.redactor),
+            "protocol": format!("HTTP/{}", request.version),
+            "status": entry.status,
+            "body_bytes": entry.body_bytes,
+            "duration_ms": entry.duration.as_secs_f64() * 1000.0,
+            "request_id": entry.request_id,
+            "route": request.route.as_ref().map(|route| route.pattern.as_str()),
+            "referer": referer(request, &self.redactor),
+            "user_agent": request.header("user-agent"),
+        });
+        if !self.headers.is_empty() {
+            let headers = self
+                .headers
+                .iter()
+                .filter_map(|name| {
+                    request
+                        .header(name)
+                        .map(|value| (name.clone(), Value::from(self.redactor.header(name, value))))
+                })
+                .collect::<Map<String, Value>>();
+            line["headers"] = Value::Object(headers);
+        }
+        return line.to_string();
+    }
+
+    fn template_line(&self, entry: &AccessLogEntry) -> String {
+        let mut line = String::new();
+        for part in &self.template {
+            match part {
+                TemplatePart::Literal(text) => line.push_str(text),
+                // templates can quote any variable, the client sends most of them
+                TemplatePart::Variable(name) => line.push_str(&escape_quoted(
+                    &template_value(entry, name, &self.redactor).unwrap_or_else(|| "-".to_string()),
+                )),
+            }
+        }
+        return line;
+    }
+}
+
+impl Sink {
+    fn flush(&self) {
+        let result = match self {
+            Sink::Stdout(writer) => lock(writer).flush(),
+            Sink::File { writer, .. } => lock(writer).flush(),
+            Sink::Otel | Sink::None => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!(error = e.to_string().as_str(); "Couldn't flush the access log");
+        }
+    }
+
+    // logrotate renames the file and signals, lines after that go to a new file at the path
+    fn reopen(&self) {
+        if let Sink::File { path, writer } = self {
+            let mut writer = lock(writer);
+            if let Err(e) = writer.flush() {
+                warn!(error = e.to_string().as_str(); "Couldn't flush the access log before reopening it");
+            }
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => {
+                    *writer = BufWriter::with_capacity(writer.capacity(), file);
+                    info!(path = path.display().to_string().as_str(); "Reopened the access log");
+                }
+                Err(e) => {
+                    error!(path = path.display().to_string().as_str(), error = e.to_string().as_str(); "Couldn't reopen the access log");
+                }
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn write_line(writer: &Mutex<BufWriter<impl Write>>, line: &str) -> io::Result<()> {
+    let mut writer = lock(writer);
+    writer.write_all(line.as_bytes())?;
+    return writer.write_all(b"\n");
+}
+
+fn open_log_file(path: &PathBuf) -> File {
+    return match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!(path = path.display().to_string().as_str(), error = e.to_string().as_str(); "Couldn't open the access log");
+            force_export_telemetry(false);
+            panic!("Couldn't open the access log {} | {}", path.display(), e);
+        }
+    };
+}
+
+/*
+Variables are written as $name, ex: "$remote_addr [$time_local] $status".
+$http_referer and $http_user_agent are always available, other headers as
+$http_<name> with dashes as underscores, only if they're in `headers`.
+*/
+fn parse_template(template: &str, headers: &[String]) -> Vec<TemplatePart> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            literal.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        while let Some(next) = chars.peek()
+            && (next.is_ascii_alphanumeric() || *next == '_')
+        {
+            name.push(*next);
+            chars.next();
+        }
+        if name.is_empty() {
+            literal.push('$');
+            continue;
+        }
+
+        let is_known = TEMPLATE_VARIABLES.contains(&name.as_str())
+            || name.strip_prefix("http_").is_some_and(|header| {
+                headers
+                    .iter()
+                    .any(|allowed| allowed.replace('-', "_") == header)
+            });
+        if !is_known {
+            error!(variable = name.as_str(); "Unknown access log template variable");
+            force_export_telemetry(false);
+            panic!(
+                "Unknown access log template variable ${}, headers must be listed in access_log.headers",
+                name
+            );
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(TemplatePart::Variable(name));
+    }
+    if !literal.is_empty() {
+        parts.push(TemplatePart::Literal(literal));
+    }
+    return parts;
+}
+
+const TEMPLATE_VARIABLES: [&str; 17] = [
+    "remote_addr",
+    "time_local",
+    "time_iso8601",
+    "request",
+    "method",
+    "path",
+    "query",
+    "protocol",
+    "status",
+    "body_bytes_sent",
+    "request_time",
+    "duration_ms",
+    "request_id",
+    "listener",
+    "route",
+    "http_referer",
+    "http_user_agent",
+];
+
+fn template_value(entry: &AccessLogEntry, name: &str, redactor: &Redactor) -> Option<String> {
+    let request = entry.request;
+    return match name {
+        "remote_addr" => Some(entry.remote_address.clone()),
+        "time_local" => Some(clf_time(entry.time)),
+        "time_iso8601" => Some(iso8601_time(entry.time)),
+        "request" => Some(request_line(request, redactor)),
+        "method" => Some(request.method.clone()),
+        "path" => Some(request.path.clone()),
+        "query" => query(request, redactor),
+        "protocol" => Some(format!("HTTP/{}", request.version)),
+        "status" => Some(entry.status.to_string()),
+        "body_bytes_sent" => Some(entry.body_bytes.to_string()),
+        // seconds with millisecond resolution, like nginx
+        "request_time" => Some(format!("{:.3}", entry.duration.as_secs_f64())),
+        "duration_ms" => Some(format!("{:.3}", entry.duration.as_secs_f64() * 1000.0)),
+        "request_id" => entry.request_id.map(|id| id.to_string()),
+        "listener" => Some(entry.listener.to_string()),
+        "route" => request.route.as_ref().map(|route| route.pattern.clone()),
+        "http_referer" => referer(request, redactor),
+        header => header.strip_prefix("http_").and_then(|header| {
+            let header = header.replace('_', "-");
+            request
+                .header(&header)
+                .map(|value| redactor.header(&header, value))
+        }),
+    };
+}
+
+fn common_line(entry: &AccessLogEntry, redactor: &Redactor) -> String {
+    // CLF writes empty bodies as "-"
+    let body_bytes = match entry.body_bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+    return format!(
+        "{} - - [{}] \"{}\" {} {}",
+        entry.remote_address,
+        clf_time(entry.time),
+        escape_quoted(&request_line(entry.request, redactor)),
+        entry.status,
+        body_bytes
+    );
+}
+
+fn request_line(request: &Request, redactor: &Redactor) -> String {
+    return format!(
+        "{} {} HTTP/{}",
+        request.method,
+        redactor.url(&request.target),
+        request.version
+    );
+}
+
+fn query(request: &Request, redactor: &Redactor) -> Option<String> {
+    request
+        .target
+        .split_once('?')
+        .map(|(_, query)| redactor.query(query))
+}
+
+// links from other sites can carry tokens in their query string too
+fn referer(request: &Request, redactor: &Redactor) -> Option<String> {
+    request
+        .header("referer")
+        .map(|referer| redactor.url(referer))
+}
+
+// quotes and control characters in client supplied values would break up the line
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+// UTC, ex: "18/Oct/2026:22:30:31 +0000"
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc_parts(time);
+    return format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    );
+}
+
+// ex: "2026-10-18T22:30:31.123Z"
+fn iso8601_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc_parts(time);
+    return format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    );
+}
+
+// year, month, day, hour, minute, second, millisecond
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as i64;
+    let (days, day_seconds) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // civil date from days since 1970-01-01, see howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return (
+        year,
+        month,
+        day,
+        (day_seconds / 3600) as u32,
+        (day_seconds % 3600 / 60) as u32,
+        (day_seconds % 60) as u32,
+        since_epoch.subsec_millis(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_sdk::{
+        logs::SdkLoggerProvider, metrics::SdkMeterProvider, trace::SdkTracerProvider,
+    };
+
+    use super::*;
+    use crate::{
+        http::{RequestBody, RequestHead},
+        redaction::RedactionConfig,
+        statics::{LOGGER_PROVIDER, METER_PROVIDER, TRACER_PROVIDER},
+    };
+
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        return UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis);
+    }
+
+    fn access_log(format: AccessLogFormat, template: Option<&str>) -> AccessLog {
+        let config = AccessLogConfig {
+            format,
+            template: template.map(str::to_string),
+            output: AccessLogOutput::None,
+            headers: vec!["X-Tenant".to_string()],
+            ..AccessLogConfig::default()
+        };
+        return AccessLog::new(
+            &config,
+            Arc::new(Redactor::new(&RedactionConfig::default())),
+        );
+    }
+
+    fn request() -> Request {
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "/items?page=2&token=abc".to_string(),
+            version: "1.1".to_string(),
+            headers: [
+                ("Referer", "https://example.test/?token=abc"),
+                ("User-Agent", "curl/8.5 \"quoted\"\x07"),
+                ("X-Tenant", "acme\" injected=\"1"),
+            ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        };
+        return head.into_request(RequestBody::from_bytes(Vec::new()));
+    }
+
+    fn entry(request: &Request) -> AccessLogEntry<'_> {
+        return AccessLogEntry {
+            // 2024-02-29 12:34:56.789 UTC
+            time: at(1_709_210_096, 789),
+            remote_address: "192.0.2.7".to_string(),
+            listener: "ipv4",
+            request,
+            status: 200,
+            body_bytes: 512,
+            duration: Duration::from_micros(1500),
+            request_id: Some("4bf92f3577b34da6"),
+        };
+    }
+
+    #[test]
+    fn utc_parts_handle_leap_days_and_year_boundaries() {
+        assert_eq!(utc_parts(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0, 0));
+        assert_eq!(
+            utc_parts(at(1_709_210_096, 789)),
+            (2024, 2, 29, 12, 34, 56, 789)
+        );
+        assert_eq!(utc_parts(at(951_782_400, 0)), (2000, 2, 29, 0, 0, 0, 0));
+        assert_eq!(
+            utc_parts(at(1_767_225_599, 999)),
+            (2025, 12, 31, 23, 59, 59, 999)
+        );
+        assert_eq!(utc_parts(at(1_767_225_600, 0)), (2026, 1, 1, 0, 0, 0, 0));
+
+        assert_eq!(
+            clf_time(at(1_709_210_096, 789)),
+            "29/Feb/2024:12:34:56 +0000"
+        );
+        assert_eq!(clf_time(at(1_767_225_600, 0)), "01/Jan/2026:00:00:00 +0000");
+        assert_eq!(
+            iso8601_time(at(1_767_225_599, 5)),
+            "2025-12-31T23:59:59.005Z"
+        );
+    }
+
+    #[test]
+    fn standard_formats_match_their_layout() {
+        let request = request();
+        let entry = entry(&request);
+        let common = "192.0.2.7 - - [29/Feb/2024:12:34:56 +0000] \"GET /items?page=2&token=[REDACTED] HTTP/1.1\" 200 512";
+        assert_eq!(
+            access_log(AccessLogFormat::Common, None).line(&entry),
+            common
+        );
+        assert_eq!(
+            access_log(AccessLogFormat::Combined, None).line(&entry),
+            format!(
+                "{} \"https://example.test/?token=[REDACTED]\" \"curl/8.5 \\\"quoted\\\"\\x07\"",
+                common
+            )
+        );
+        assert_eq!(
+            access_log(AccessLogFormat::Json, None).line(&entry),
+            concat!(
+                r#"{"body_bytes":512,"duration_ms":1.5,"headers":{"x-tenant":"acme\" injected=\"1"},"#,
+                r#""listener":"ipv4","method":"GET","path":"/items","protocol":"HTTP/1.1","#,
+                r#""query":"page=2&token=[REDACTED]","referer":"https://example.test/?token=[REDACTED]","#,
+                r#""remote_addr":"192.0.2.7","request_id":"4bf92f3577b34da6","route":null,"status":200,"#,
+                r#""time":"2024-02-29T12:34:56.789Z","user_agent":"curl/8.5 \"quoted\"\u0007"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn template_values_are_escaped() {
+        let log = access_log(
+            AccessLogFormat::Custom,
+            Some(
+                "$remote_addr \"$request\" $status \"$http_user_agent\" tenant=\"$http_x_tenant\" $route",
+            ),
+        );
+        let request = request();
+        assert_eq!(
+            log.line(&entry(&request)),
+            "192.0.2.7 \"GET /items?page=2&token=[REDACTED] HTTP/1.1\" 200 \"curl/8.5 \\\"quoted\\\"\\x07\" tenant=\"acme\\\" injected=\\\"1\" -"
+        );
+    }
+
+    #[test]
+    fn templates_split_into_literals_and_variables() {
+        let parts = parse_template("$method $ $$-$status.", &[]);
+        let parts = parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(text) => format!("'{}'", text),
+                TemplatePart::Variable(name) => name.clone(),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(parts, ["method", "' $ $$-'", "status", "'.'"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown access log template variable $http_cookie")]
+    fn unlisted_headers_are_unknown_variables() {
+        // telemetry is flushed before the panic
+        METER_PROVIDER.get_or_init(|| SdkMeterProvider::builder().build());
+        TRACER_PROVIDER.get_or_init(|| SdkTracerProvider::builder().build());
+        LOGGER_PROVIDER.get_or_init(|| SdkLoggerProvider::builder().build());
+        parse_template("$method $http_cookie", &["x-tenant".to_string()]);
+    }
+
+    #[test]
+    fn quoted_values_escape_quotes_and_control_characters() {
+        assert_eq!(escape_quoted("plain value"), "plain value");
+        assert_eq!(escape_quoted("a \"b\" \\c"), "a \\\"b\\\" \\\\c");
+        assert_eq!(
+            escape_quoted("line\nbreak\ttab\u{7f}"),
+            "line\\x0abreak\\x09tab\\x7f"
+        );
+        assert_eq!(escape_quoted("ünïcode"), "ünïcode");
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    fmt::Debug,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicI64, AtomicU8, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use crossbeam_channel::Receiver;
+use log::{LevelFilter, info, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    config::ServerConfig,
+    http::{Request, Response},
+    listener::AcceptedConnection,
+    otlp::ExporterConfig,
+    router::{Handler, Router},
+    sse::{ADMIN_EVENTS, EventStream},
+    static_files::StaticFiles,
+    statics::DRAINING,
+};
+
+pub const ADMIN_TOKEN_ENV: &str = "HTTP_SERVER_ADMIN_TOKEN";
+
+/*
+Adds a listener serving the admin endpoints and an API to inspect and control the server, ex:
+curl -H "Authorization: Bearer $HTTP_SERVER_ADMIN_TOKEN" http://127.0.0.1:9901/admin/stats
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub address: SocketAddr,
+    // required, falls back to the HTTP_SERVER_ADMIN_TOKEN environment variable
+    pub token: Option<String>,
+    // the API is served under this path, ex: "/admin/stats"
+    pub prefix: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        return AdminConfig {
+            enabled: false,
+            address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9901),
+            token: None,
+            prefix: "/admin".to_string(),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum WorkerState {
+    Idle,
+    Busy,
+    Stopped,
+}
+
+impl WorkerState {
+    fn name(self) -> &'static str {
+        match self {
+            WorkerState::Idle => "idle",
+            WorkerState::Busy => "busy",
+            WorkerState::Stopped => "stopped",
+        }
+    }
+}
+
+// runtime counters shared by the handler threads and the stats endpoint
+pub struct ServerStats {
+    started: Instant,
+    // one per handler thread, indexed by thread id
+    workers: Vec<AtomicU8>,
+    // connections accepted but not picked up by a handler, None in reuse port accept mode
+    queue: Option<Receiver<AcceptedConnection>>,
+    requests: AtomicU64,
+    open_connections: AtomicI64,
+    upgraded_connections: AtomicI64,
+}
+
+impl ServerStats {
+    pub fn new(worker_count: usize, queue: Option<Receiver<AcceptedConnection>>) -> Self {
+        return ServerStats {
+            started: Instant::now(),
+            workers: (0..worker_count)
+                .map(|_| AtomicU8::new(WorkerState::Idle as u8))
+                .collect(),
+            queue,
+            requests: AtomicU64::new(0),
+            open_connections: AtomicI64::new(0),
+            upgraded_connections: AtomicI64::new(0),
+        };
+    }
+
+    pub fn set_worker_state(&self, thread_id: usize, state: WorkerState) {
+        if let Some(worker) = self.workers.get(thread_id) {
+            worker.store(state as u8, Ordering::Relaxed);
+        }
+    }
+
+    // held by a handler thread, marks it stopped when the thread ends, also by panicking
+    pub fn worker(&self, thread_id: usize) -> Worker<'_> {
+        return Worker {
+            stats: self,
+            thread_id,
+        };
+    }
+
+    // handler threads that ended, before shutdown only a panic ends one
+    pub fn stopped_workers(&self) -> usize {
+        return self
+            .workers
+            .iter()
+            .filter(|state| state.load(Ordering::Relaxed) == WorkerState::Stopped as u8)
+            .count();
+    }
+
+    pub fn queue_depth(&self) -> Option<usize> {
+        return self.queue.as_ref().map(|queue| queue.len());
+    }
+
+    // counted until the returned guard is dropped
+    pub fn open_connection(self: &Arc<Self>) -> OpenConnection {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        return OpenConnection {
+            stats: self.clone(),
+            upgraded: false,
+        };
+    }
+
+    fn to_json(&self) -> Value {
+        let workers = self
+            .workers
+            .iter()
+            .enumerate()
+            .map(|(id, state)| {
+                let state = match state.load(Ordering::Relaxed) {
+                    0 => WorkerState::Idle,
+                    1 => WorkerState::Busy,
+                    _ => WorkerState::Stopped,
+                };
+                json!({ "id": id, "state": state.name() })
+            })
+            .collect::<Vec<Value>>();
+        return json!({
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "draining": DRAINING.load(Ordering::SeqCst),
+            "workers": workers,
+            "queue_depth": self.queue_depth(),
+            "requests": self.requests.load(Ordering::Relaxed),
+            "open_connections": self.open_connections.load(Ordering::Relaxed),
+            "upgraded_connections": self.upgraded_connections.load(Ordering::Relaxed),
+        });
+    }
+}
+
+pub struct Worker<'a> {
+    stats: &'a ServerStats,
+    thread_id: usize,
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .set_worker_state(self.thread_id, WorkerState::Stopped);
+    }
+}
+
+// moved into the thread of an upgraded connection, so it stays counted until that closes
+pub struct OpenConnection {
+    stats: Arc<ServerStats>,
+    upgraded: bool,
+}
+
+impl OpenConnection {
+    // false once `max` connections are upgraded, the connection then stays a plain one
+    pub fn upgrade(&mut self, max: usize) -> bool {
+        let reserved = self.stats.upgraded_connections.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |upgraded| (upgraded < max as i64).then_some(upgraded + 1),
+        );
+        self.upgraded = reserved.is_ok();
+        return self.upgraded;
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.stats.open_connections.fetch_sub(1, Ordering::Relaxed);
+        if self.upgraded {
+            self.stats
+                .upgraded_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Config,
+    Stats,
+    Events,
+    StaticFiles,
+    ReloadStaticFiles,
+    Shutdown,
+    LogLevel,
+    SetLogLevel,
+}
+
+struct AdminState {
+    token: String,
+    // what the server started with, see `config_view`
+    config: Value,
+    stats: Arc<ServerStats>,
+    events: EventStream,
+    // None in dev mode
+    static_files: Option<Arc<StaticFiles>>,
+}
+
+pub struct AdminApi {
+    endpoint: Endpoint,
+    state: Arc<AdminState>,
+}
+
+pub fn route_admin_api(
+    router: &mut Router,
+    config: &ServerConfig,
+    stats: Arc<ServerStats>,
+    static_files: Option<Arc<StaticFiles>>,
+) {
+    if !config.admin.enabled {
+        return;
+    }
+    let token = match &config.admin.token {
+        Some(token) if !token.is_empty() => token.clone(),
+        // checked when the server is initialized
+        _ => return,
+    };
+    let state = Arc::new(AdminState {
+        token,
+        config: config_view(config),
+        stats,
+        events: EventStream::new(ADMIN_EVENTS.clone(), &config.events),
+        static_files,
+    });
+    let prefix = config.admin.prefix.trim_end_matches('/');
+    for (method, path, endpoint) in [
+        ("GET", "config", Endpoint::Config),
+        ("GET", "stats", Endpoint::Stats),
+        ("GET", "events", Endpoint::Events),
+        ("GET", "static-files", Endpoint::StaticFiles),
+        ("POST", "static-files/reload", Endpoint::ReloadStaticFiles),
+        ("POST", "shutdown", Endpoint::Shutdown),
+        ("GET", "log-level", Endpoint::LogLevel),
+        ("PUT", "log-level", Endpoint::SetLogLevel),
+    ] {
+        router.route(
+            method,
+            &format!("{}/{}", prefix, path),
+            AdminApi {
+                endpoint,
+                state: state.clone(),
+            },
+        );
+    }
+}
+
+impl Handler for AdminApi {
+    fn handle(&self, req: &Request) -> Response {
+        if !self.is_authorized(req) {
+            warn!(path = req.path.as_str(); "Unauthorized admin API request");
+            return Response::text(401, "Unauthorized")
+                .with_header("WWW-Authenticate", "Bearer realm=\"admin\"");
+        }
+
+        return match self.endpoint {
+            Endpoint::Config => json_response(200, &self.state.config),
+            Endpoint::Stats => json_response(200, &self.state.stats.to_json()),
+            Endpoint::Events => self.state.events.handle(req),
+            Endpoint::StaticFiles => match &self.state.static_files {
+                Some(static_files) => {
+                    let paths = static_files.paths();
+                    json_response(200, &json!({ "count": paths.len(), "files": paths }))
+                }
+                None => Response::text(404, "Static files aren't served in dev mode"),
+            },
+            Endpoint::ReloadStaticFiles => match &self.state.static_files {
+                Some(static_files) => match static_files.reload() {
+                    Ok(count) => json_response(200, &json!({ "count": count })),
+                    Err(e) => {
+                        warn!(error = e.as_str(); "Couldn't reload the static files");
+                        json_response(500, &json!({ "error": e }))
+                    }
+                },
+                None => Response::text(404, "Static files aren't served in dev mode"),
+            },
+            // browsers always send Origin on cross-site POSTs, a page that got hold of the token
+            // through a proxy or extension can't stop the server from a visitor's browser
+            Endpoint::Shutdown if req.header("origin").is_some() => {
+                warn!("Admin API shutdown request with an Origin header rejected");
+                Response::text(403, "Forbidden")
+            }
+            Endpoint::Shutdown => {
+                info!("Shutdown requested through the admin API");
+                // same as the first SIGINT or SIGTERM, the server drains then stops
+                DRAINING.store(true, Ordering::SeqCst);
+                Response::text(202, "Shutting down")
+            }
+            Endpoint::LogLevel => json_response(
+                200,
+                &json!({ "level": log::max_level().as_str().to_lowercase() }),
+            ),
+            Endpoint::SetLogLevel => {
+                let body = match req.body.to_bytes() {
+                    Ok(body) => body,
+                    Err(_) => return Response::text(400, "Bad Request"),
+                };
+                let level = String::from_utf8_lossy(&body).trim().to_string();
+                match LevelFilter::from_str(&level) {
+                    Ok(level) => {
+                        log::set_max_level(level);
+                        info!(level = level.as_str(); "Log level changed through the admin API");
+                        json_response(200, &json!({ "level": level.as_str().to_lowercase() }))
+                    }
+                    Err(_) => Response::text(
+                        400,
+                        "Log level must be one of off, error, warn, info, debug or trace",
+                    ),
+                }
+            }
+        };
+    }
+}
+
+impl AdminApi {
+    fn is_authorized(&self, req: &Request) -> bool {
+        return match req
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => constant_time_eq(token.trim().as_bytes(), self.state.token.as_bytes()),
+            None => false,
+        };
+    }
+}
+
+// lets operators follow upstreams going down and coming back on the admin event stream
+pub fn publish_upstream_state(pool: &str, upstream: &str, state: &str) {
+    let data = json!({
+        "pool": pool,
+        "upstream": upstream,
+        "state": state,
+    });
+    ADMIN_EVENTS.publish("upstream", &data.to_string());
+}
+
+// the time taken doesn't depend on where the tokens differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0;
+}
+
+fn json_response(status: u16, body: &Value) -> Response {
+    return Response::new(status)
+        .with_header("Cache-Control", "no-store")
+        .with_body("application/json", body.to_string().into_bytes());
+}
+
+/*
+The settings shown by the config endpoint, picked one by one so a new secret added to the
+configuration isn't exposed by default. Tokens and keys are left out, exporter headers usually
+hold collector credentials so only their names are shown.
+*/
+fn config_view(config: &ServerConfig) -> Value {
+    let listeners = config
+        .listeners
+        .iter()
+        .map(|listener| {
+            json!({
+                "name": listener.name,
+                "address": listener.address.to_string(),
+                "tls": listener.tls,
+                "admin": listener.admin,
+                "reuse_port": listener.reuse_port,
+                "redirect": listener.redirect.is_some(),
+                "optional": listener.optional,
+            })
+        })
+        .collect::<Vec<Value>>();
+    let proxies = config
+        .proxies
+        .iter()
+        .map(|proxy| {
+            json!({
+                "prefix": proxy.prefix,
+                "upstreams": proxy.upstreams,
+                "balance": name(&proxy.balance),
+                "health_check": proxy.health_check.as_ref().map(|check| check.path.clone()),
+                "retries": proxy.retries,
+                "strip_prefix": proxy.strip_prefix,
+            })
+        })
+        .collect::<Vec<Value>>();
+    let telemetry = &config.telemetry;
+    let exporter = |exporter: &ExporterConfig| {
+        json!({
+            "exporter": name(&exporter.exporter),
+            "endpoint": exporter.endpoint,
+            "headers": exporter.headers.keys().collect::<Vec<&String>>(),
+            "timeout_ms": exporter.timeout_ms,
+            "compression": name(&exporter.compression),
+        })
+    };
+    return json!({
+        "static_files": config.static_files.display().to_string(),
+        "dev_mode": config.dev_mode,
+        "timeout_ms": config.timeout_ms,
+        "max_request_body_bytes": config.max_request_body_bytes,
+        "max_upgraded_connections": config.max_upgraded_connections,
+        "accept_mode": name(&config.accept_mode),
+        "listeners": listeners,
+        "proxies": proxies,
+        "telemetry": {
+            "logs": exporter(&telemetry.logs),
+            "metrics": exporter(&telemetry.metrics),
+            "traces": exporter(&telemetry.traces),
+            "prometheus_path": telemetry.prometheus.path,
+            "trace_response": telemetry.trace_response,
+            "sampler": format!("{:?}", telemetry.sampler),
+        },
+        "access_log": {
+            "format": name(&config.access_log.format),
+            "output": name(&config.access_log.output),
+            "path": config.access_log.path.as_ref().map(|path| path.display().to_string()),
+        },
+        "redaction": {
+            "mode": name(&config.redaction.mode),
+            "headers": config.redaction.headers,
+            "query_parameters": config.redaction.query_parameters,
+            "include_defaults": config.redaction.include_defaults,
+        },
+        "health": {
+            "enabled": config.health.enabled,
+            "drain_ms": config.health.drain_ms,
+            "max_queue_depth": config.health.max_queue_depth,
+        },
+        "admin": {
+            "address": config.admin.address.to_string(),
+            "prefix": config.admin.prefix,
+        },
+        "tls": tls_view(config),
+    });
+}
+
+#[cfg(feature = "tls")]
+fn tls_view(config: &ServerConfig) -> Value {
+    return match &config.tls {
+        Some(tls) => json!({
+            "certificates": tls.certificates.len(),
+            "alpn_protocols": tls.alpn_protocols,
+            "client_auth": tls.client_auth.as_ref().map(|client_auth| name(&client_auth.mode)),
+            "hsts": tls.hsts.is_some(),
+        }),
+        None => Value::Null,
+    };
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_view(_config: &ServerConfig) -> Value {
+    return Value::Null;
+}
+
+// snake case name of a unit enum variant, as written in the config file, ex: "least_connections"
+fn name(variant: &impl Debug) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", variant).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    return name;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        http::{Body, RequestBody, RequestHead},
+        otlp::ExporterKind,
+        serve::AcceptMode,
+        sse::{Event, SERVER_EVENTS},
+    };
+
+    fn admin_router(config: &ServerConfig) -> Router {
+        let mut router = Router::new();
+        route_admin_api(
+            &mut router,
+            config,
+            Arc::new(ServerStats::new(1, None)),
+            None,
+        );
+        return router;
+    }
+
+    fn admin_config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.admin.enabled = true;
+        config.admin.token = Some("admin-token".to_string());
+        return config;
+    }
+
+    fn send(router: &Router, method: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<(String, String)>>();
+        headers.push((
+            "Authorization".to_string(),
+            "Bearer admin-token".to_string(),
+        ));
+        let head = RequestHead {
+            method: method.to_string(),
+            target: path.to_string(),
+            version: "1.1".to_string(),
+            headers,
+        };
+        let mut req = head.into_request(RequestBody::from_bytes(Vec::new()));
+        return router.handle(&mut req);
+    }
+
+    #[test]
+    fn the_config_view_leaves_secrets_out() {
+        let mut config = admin_config();
+        config.redaction.hash_key = Some("redaction-key".to_string());
+        config.telemetry.traces.headers = BTreeMap::from([(
+            "authorization".to_string(),
+            "Bearer collector-token".to_string(),
+        )]);
+        let router = admin_router(&config);
+
+        let res = send(&router, "GET", "/admin/config", &[]);
+        assert_eq!(res.status, 200);
+        let body = match res.body {
+            Body::Bytes(body) => String::from_utf8(body).unwrap(),
+            _ => panic!("expected a JSON body"),
+        };
+        assert!(!body.contains("admin-token"));
+        assert!(!body.contains("redaction-key"));
+        assert!(!body.contains("collector-token"));
+
+        let view: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(view["accept_mode"], "channel");
+        assert_eq!(
+            view["telemetry"]["traces"]["headers"],
+            json!(["authorization"])
+        );
+        assert_eq!(view["listeners"][0]["address"], "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn cross_origin_shutdown_requests_are_rejected() {
+        let router = admin_router(&admin_config());
+        let res = send(
+            &router,
+            "POST",
+            "/admin/shutdown",
+            &[("Origin", "https://example.com")],
+        );
+        assert_eq!(res.status, 403);
+        assert!(!DRAINING.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn upstream_states_are_only_streamed_by_the_admin_api() {
+        publish_upstream_state("api", "10.0.0.5:8080", "ejected");
+        let names_upstream =
+            |events: Vec<Arc<Event>>| events.iter().any(|event| event.data.contains("10.0.0.5"));
+        assert!(names_upstream(ADMIN_EVENTS.subscribe(Some(0)).0));
+        assert!(!names_upstream(SERVER_EVENTS.subscribe(Some(0)).0));
+
+        let router = admin_router(&admin_config());
+        let res = send(&router, "GET", "/admin/events", &[]);
+        assert_eq!(res.status, 200);
+        assert!(res.upgrade.is_some());
+
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "/admin/events".to_string(),
+            version: "1.1".to_string(),
+            headers: Vec::new(),
+        };
+        let mut req = head.into_request(RequestBody::from_bytes(Vec::new()));
+        assert_eq!(router.handle(&mut req).status, 401);
+    }
+
+    #[test]
+    fn variant_names_match_the_config_file() {
+        assert_eq!(name(&AcceptMode::ReusePort), "reuse_port");
+        assert_eq!(name(&ExporterKind::OtlpGrpc), "otlp_grpc");
+        assert_eq!(name(&ExporterKind::None), "none");
+    }
+
+    #[test]
+    fn upgrades_past_the_limit_are_refused() {
+        let stats = Arc::new(ServerStats::new(1, None));
+        let mut first = stats.open_connection();
+        let mut second = stats.open_connection();
+        assert!(first.upgrade(1));
+        assert!(!second.upgrade(1));
+        assert_eq!(stats.to_json()["upgraded_connections"], 1);
+
+        drop(first);
+        drop(second);
+        assert!(stats.open_connection().upgrade(1));
+        assert_eq!(stats.to_json()["upgraded_connections"], 0);
+        assert_eq!(stats.to_json()["open_connections"], 0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    env, fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{
+    access_log::AccessLogConfig,
+    admin::{ADMIN_TOKEN_ENV, AdminConfig},
+    health::HealthConfig,
+    listener::ListenerConfig,
+    middleware::MiddlewareConfig,
+    otlp::TelemetryConfig,
+    proxy::ProxyConfig,
+    redaction::{REDACTION_KEY_ENV, RedactionConfig},
+    serve::AcceptMode,
+    sse::EventsConfig,
+    websocket::WebSocketConfig,
+};
+
+/*
+Configuration is read from a TOML file, see config.example.toml.
+The file is passed with `--config <path>` or the HTTP_SERVER_CONFIG environment variable,
+without either the defaults below are used. `--dev` turns on development mode.
+*/
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub static_files: PathBuf,
+    // also the time a client has to finish the TLS handshake, and to send the whole request head
+    pub timeout_ms: u64,
+    // larger request bodies are answered with a 413
+    pub max_request_body_bytes: usize,
+    // WebSockets and event streams, more upgrades are answered with a 503
+    pub max_upgraded_connections: usize,
+    pub accept_mode: AcceptMode,
+    pub listeners: Vec<ListenerConfig>,
+    pub middleware: MiddlewareConfig,
+    pub proxies: Vec<ProxyConfig>,
+    pub websocket: WebSocketConfig,
+    pub events: EventsConfig,
+    pub telemetry: TelemetryConfig,
+    pub access_log: AccessLogConfig,
+    pub redaction: RedactionConfig,
+    pub health: HealthConfig,
+    pub admin: AdminConfig,
+    pub dev: DevConfig,
+    // set with `--dev`, serves the Vite dev server instead of the static files
+    #[serde(skip)]
+    pub dev_mode: bool,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let ipv4 = ListenerConfig::inet("ipv4", SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080));
+        let mut ipv6 =
+            ListenerConfig::inet("ipv6", SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 8080));
+        ipv6.ipv6_only = true;
+        // plenty of containers have no IPv6 loopback, the server still starts on IPv4 there
+        ipv6.optional = true;
+
+        return ServerConfig {
+            static_files: PathBuf::from("../client/dist"),
+            timeout_ms: 400,
+            max_request_body_bytes: 1024 * 1024,
+            max_upgraded_connections: 1024,
+            accept_mode: AcceptMode::Channel,
+            listeners: vec![ipv4, ipv6],
+            middleware: MiddlewareConfig::default(),
+            proxies: Vec::new(),
+            websocket: WebSocketConfig::default(),
+            events: EventsConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            access_log: AccessLogConfig::default(),
+            redaction: RedactionConfig::default(),
+            health: HealthConfig::default(),
+            admin: AdminConfig::default(),
+            dev: DevConfig::default(),
+            dev_mode: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DevConfig {
+    // where `deno task dev` serves the client
+    pub vite_address: String,
+}
+
+impl Default for DevConfig {
+    fn default() -> Self {
+        return DevConfig {
+            vite_address: "127.0.0.1:5173".to_string(),
+        };
+    }
+}
+
+impl ServerConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+// runs before telemetry is initialized, so failures can only be reported by panicking
+pub fn load_config() -> ServerConfig {
+    let mut config_path = env::var_os("HTTP_SERVER_CONFIG").map(PathBuf::from);
+    let mut dev_mode = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => panic!("--config requires a path"),
+            },
+            "--dev" => dev_mode = true,
+            _ => (),
+        }
+    }
+
+    let mut config = match config_path {
+        Some(path) => read_config_file(&path),
+        None => ServerConfig::default(),
+    };
+    config.dev_mode = dev_mode;
+    if config.redaction.hash_key.is_none() {
+        config.redaction.hash_key = env::var(REDACTION_KEY_ENV).ok();
+    }
+    if config.admin.enabled {
+        if config.admin.token.is_none() {
+            config.admin.token = env::var(ADMIN_TOKEN_ENV).ok();
+        }
+        let mut admin_listener = ListenerConfig::inet("admin", config.admin.address);
+        admin_listener.admin = true;
+        config.listeners.push(admin_listener);
+    }
+    return config;
+}
+
+fn read_config_file(path: &Path) -> ServerConfig {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => panic!("Couldn't read config file {} | {}", path.display(), e),
+    };
+    return match toml::from_str(&contents) {
+        Ok(config) => config,
+        Err(e) => panic!("Couldn't parse config file {} | {}", path.display(), e),
+    };
+}
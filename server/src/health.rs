@@ -0,0 +1,227 @@
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    admin::ServerStats,
+    http::{Request, Response},
+    router::{Handler, Router},
+    statics::DRAINING,
+};
+
+// see [health] in config.example.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    pub healthz_path: String,
+    pub readyz_path: String,
+    pub livez_path: String,
+    // probes run every few seconds, they're left out of the access log and traces unless these are set
+    pub access_log: bool,
+    pub sample: bool,
+    // after the first SIGINT or SIGTERM requests are still served for this long while /readyz
+    // fails, so load balancers stop sending traffic before the listeners close
+    pub drain_ms: u64,
+    // /readyz fails while more accepted connections than this wait for a handler thread,
+    // only in the channel accept mode
+    pub max_queue_depth: usize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        return HealthConfig {
+            enabled: true,
+            healthz_path: "/healthz".to_string(),
+            readyz_path: "/readyz".to_string(),
+            livez_path: "/livez".to_string(),
+            access_log: false,
+            sample: false,
+            drain_ms: 0,
+            max_queue_depth: 1024,
+        };
+    }
+}
+
+impl HealthConfig {
+    pub fn is_probe(&self, path: &str) -> bool {
+        return self.enabled
+            && [&self.healthz_path, &self.readyz_path, &self.livez_path]
+                .iter()
+                .any(|probe_path| probe_path.as_str() == path);
+    }
+
+    pub fn drain(&self) -> Duration {
+        return Duration::from_millis(self.drain_ms);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Probe {
+    // the process is up and answering requests
+    Health,
+    // none of the handler threads died, restarting the process is the only way to get them back
+    Live,
+    // live, not draining and the connection queue isn't backed up
+    Ready,
+}
+
+pub struct HealthCheck {
+    probe: Probe,
+    started: Instant,
+    stats: Arc<ServerStats>,
+    max_queue_depth: usize,
+}
+
+pub fn route_health_checks(router: &mut Router, config: &HealthConfig, stats: Arc<ServerStats>) {
+    if !config.enabled {
+        return;
+    }
+    let started = Instant::now();
+    for (path, probe) in [
+        (&config.healthz_path, Probe::Health),
+        (&config.livez_path, Probe::Live),
+        (&config.readyz_path, Probe::Ready),
+    ] {
+        router.route(
+            "GET",
+            path,
+            HealthCheck {
+                probe,
+                started,
+                stats: stats.clone(),
+                max_queue_depth: config.max_queue_depth,
+            },
+        );
+    }
+}
+
+impl Handler for HealthCheck {
+    fn handle(&self, req: &Request) -> Response {
+        let stopped_workers = self.stats.stopped_workers();
+        let queue_depth = self.stats.queue_depth();
+        let draining = DRAINING.load(Ordering::SeqCst);
+        let live = stopped_workers == 0;
+        let queue_backed_up = queue_depth.is_some_and(|depth| depth > self.max_queue_depth);
+        let ready = live && !draining && !queue_backed_up;
+
+        let (ok, failure) = match self.probe {
+            Probe::Health => (true, ""),
+            Probe::Live => (live, "not live"),
+            Probe::Ready => (ready, "not ready"),
+        };
+        let (status, message) = match ok {
+            true => (200, "ok"),
+            false => (503, failure),
+        };
+        if !wants_details(req) {
+            return Response::text(status, message).with_header("Cache-Control", "no-store");
+        }
+
+        let body = json!({
+            "status": message,
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "checks": {
+                "stopped_workers": stopped_workers,
+                "queue_depth": queue_depth,
+                "draining": draining,
+            },
+            "live": live,
+            "ready": ready,
+        });
+        return Response::new(status)
+            .with_header("Cache-Control", "no-store")
+            .with_body("application/json", body.to_string().into_bytes());
+    }
+}
+
+// `?verbose` or asking for JSON, otherwise probes get a plain "ok"
+fn wants_details(req: &Request) -> bool {
+    let verbose = req.target.split_once('?').is_some_and(|(_, query)| {
+        query
+            .split('&')
+            .any(|parameter| parameter == "verbose" || parameter.starts_with("verbose="))
+    });
+    let json = req
+        .header("accept")
+        .is_some_and(|accept| accept.contains("application/json"));
+    return verbose || json;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixStream, thread};
+
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+    use crate::{
+        http::{RequestBody, RequestHead},
+        listener::{AcceptedConnection, ListenerConfig},
+    };
+
+    fn probe(router: &Router, path: &str) -> u16 {
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: path.to_string(),
+            version: "1.1".to_string(),
+            headers: Vec::new(),
+        };
+        let mut req = head.into_request(RequestBody::from_bytes(Vec::new()));
+        return router.handle(&mut req).status;
+    }
+
+    fn health_router(stats: Arc<ServerStats>, max_queue_depth: usize) -> Router {
+        let mut router = Router::new();
+        let config = HealthConfig {
+            max_queue_depth,
+            ..HealthConfig::default()
+        };
+        route_health_checks(&mut router, &config, stats);
+        return router;
+    }
+
+    #[test]
+    fn a_dead_worker_fails_liveness() {
+        let stats = Arc::new(ServerStats::new(2, None));
+        let router = health_router(stats.clone(), 1024);
+        assert_eq!(probe(&router, "/livez"), 200);
+        assert_eq!(probe(&router, "/readyz"), 200);
+
+        let worker_stats = stats.clone();
+        let worker = thread::spawn(move || {
+            let _worker = worker_stats.worker(1);
+            panic!("handler thread died");
+        });
+        assert!(worker.join().is_err());
+        assert_eq!(stats.stopped_workers(), 1);
+        assert_eq!(probe(&router, "/healthz"), 200);
+        assert_eq!(probe(&router, "/livez"), 503);
+        assert_eq!(probe(&router, "/readyz"), 503);
+    }
+
+    #[test]
+    fn a_backed_up_queue_fails_readiness() {
+        let (sender, receiver) = unbounded();
+        let stats = Arc::new(ServerStats::new(1, Some(receiver)));
+        let router = health_router(stats, 1);
+        let listener = Arc::new(ListenerConfig::inet("test", "127.0.0.1:0".parse().unwrap()));
+        let mut clients = Vec::new();
+        for expected in [200, 503] {
+            let (client, server) = UnixStream::pair().unwrap();
+            clients.push(client);
+            sender
+                .send(AcceptedConnection {
+                    fd: server.into(),
+                    listener: listener.clone(),
+                })
+                .unwrap();
+            assert_eq!(probe(&router, "/readyz"), expected);
+            assert_eq!(probe(&router, "/livez"), 200);
+        }
+    }
+}
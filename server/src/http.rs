@@ -0,0 +1,747 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::connection::Stream;
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    // the raw request target, ex: "/assets/index.js?v=2"
+    pub target: String,
+    pub path: String,
+    // from the request line, ex: "1.1"
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    // read from the connection when a handler asks for it
+    pub body: RequestBody,
+    // captured by `:name` and `**name` route segments
+    pub params: HashMap<String, String>,
+    // set by the router once a route is matched
+    pub route: Option<MatchedRoute>,
+    // None for unix socket peers
+    pub remote_address: Option<SocketAddr>,
+    // received over a TLS listener
+    pub secure: bool,
+}
+
+#[derive(Clone)]
+pub struct MatchedRoute {
+    // the route's pattern, ex: "/users/:id", low cardinality so it can name spans and label metrics
+    pub pattern: String,
+    // set with `Route::span_name`, replaces the `{method} {pattern}` span name
+    pub span_name: Option<String>,
+}
+
+impl Request {
+    // header names are case insensitive, the first matching header wins
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+}
+
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+    }
+
+    // ex: "chunked", lowercase
+    pub fn transfer_encoding(&self) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"))
+            .map(|(_, value)| value.trim().to_ascii_lowercase())
+    }
+
+    pub fn into_request(self, body: RequestBody) -> Request {
+        let path = match self.target.split_once('?') {
+            Some((path, _query)) => path.to_string(),
+            None => self.target.clone(),
+        };
+        return Request {
+            method: self.method,
+            target: self.target,
+            path,
+            version: self.version,
+            headers: self.headers,
+            body,
+            params: HashMap::new(),
+            route: None,
+            remote_address: None,
+            secure: false,
+        };
+    }
+}
+
+/*
+The request body, read from the client connection on demand so a handler like the proxy can
+stream it on instead of holding it in memory. Clones share the same connection, once the
+response is ready the connection is taken back with the bytes that weren't read.
+*/
+#[derive(Clone)]
+pub struct RequestBody {
+    state: Arc<Mutex<BodyState>>,
+    // the Content-Length, None for chunked bodies
+    length: Option<u64>,
+}
+
+struct BodyState {
+    // None once the connection was taken back
+    reader: Option<BodyReader>,
+    read: u64,
+    max_bytes: u64,
+    too_large: bool,
+}
+
+enum BodyReader {
+    Length(io::Take<BodySource>),
+    Chunked(ChunkedReader<BufReader<BodySource>>),
+}
+
+// bytes read past the request head, then the rest of the connection
+struct BodySource {
+    buffered: Cursor<Vec<u8>>,
+    stream: Option<Stream>,
+    // the client sent `Expect: 100-continue` and waits for an interim response before the body
+    expects_continue: bool,
+}
+
+impl Read for BodySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        match &mut self.stream {
+            Some(stream) => {
+                if self.expects_continue {
+                    self.expects_continue = false;
+                    stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    stream.flush()?;
+                }
+                stream.read(buf)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl BodySource {
+    fn into_parts(self, mut unread: Vec<u8>) -> (Option<Stream>, Vec<u8>) {
+        let position = self.buffered.position() as usize;
+        unread.extend_from_slice(&self.buffered.get_ref()[position..]);
+        return (self.stream, unread);
+    }
+}
+
+impl RequestBody {
+    // `buffered` holds whatever was read past the request head, chunked bodies are decoded
+    pub fn new(stream: Stream, buffered: Vec<u8>, head: &RequestHead, max_bytes: usize) -> Self {
+        let chunked = head.transfer_encoding().is_some();
+        let length = head.content_length().map(|length| length as u64);
+        let expects_continue = head.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("expect") && value.eq_ignore_ascii_case("100-continue")
+        });
+        let source = BodySource {
+            buffered: Cursor::new(buffered),
+            stream: Some(stream),
+            expects_continue: expects_continue && (chunked || length.unwrap_or(0) > 0),
+        };
+        return match chunked {
+            true => RequestBody::from_reader(
+                BodyReader::Chunked(ChunkedReader::new(BufReader::new(source))),
+                None,
+                max_bytes,
+            ),
+            false => {
+                let length = length.unwrap_or(0);
+                RequestBody::from_reader(
+                    BodyReader::Length(source.take(length)),
+                    Some(length),
+                    max_bytes,
+                )
+            }
+        };
+    }
+
+    // a body that's already in memory, ex: a request built by a test
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let length = bytes.len() as u64;
+        let source = BodySource {
+            buffered: Cursor::new(bytes),
+            stream: None,
+            expects_continue: false,
+        };
+        return RequestBody::from_reader(
+            BodyReader::Length(source.take(length)),
+            Some(length),
+            usize::MAX,
+        );
+    }
+
+    fn from_reader(reader: BodyReader, length: Option<u64>, max_bytes: usize) -> Self {
+        return RequestBody {
+            state: Arc::new(Mutex::new(BodyState {
+                reader: Some(reader),
+                read: 0,
+                max_bytes: max_bytes as u64,
+                too_large: false,
+            })),
+            length,
+        };
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.length.is_none()
+    }
+
+    // bytes handed to handlers so far
+    pub fn bytes_read(&self) -> u64 {
+        self.lock().read
+    }
+
+    // a chunked body went past max_request_body_bytes, answered with a 413
+    pub fn is_too_large(&self) -> bool {
+        self.lock().too_large
+    }
+
+    // reads what's left of the body into memory
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut reader = self;
+        reader.read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+
+    // the trailers of a chunked body, empty until it was read to the end
+    pub fn trailers(&self) -> Vec<(String, String)> {
+        match &self.lock().reader {
+            Some(BodyReader::Chunked(reader)) => reader.trailers().to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    // the client connection and the bytes read from it that aren't part of the body,
+    // ex: WebSocket frames sent right after the handshake
+    pub fn take_connection(&self) -> Option<(Stream, Vec<u8>)> {
+        let (stream, unread) = match self.lock().reader.take()? {
+            BodyReader::Length(reader) => reader.into_inner().into_parts(Vec::new()),
+            BodyReader::Chunked(reader) => {
+                let reader = reader.into_inner();
+                let unread = reader.buffer().to_vec();
+                reader.into_inner().into_parts(unread)
+            }
+        };
+        return stream.map(|stream| (stream, unread));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BodyState> {
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Read for &RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        let read_size = match &mut state.reader {
+            Some(BodyReader::Length(reader)) => reader.read(buf)?,
+            Some(BodyReader::Chunked(reader)) => reader.read(buf)?,
+            None => 0,
+        };
+        state.read += read_size as u64;
+        // Content-Length bodies were checked against the limit before the request was handled
+        if state.read > state.max_bytes {
+            state.too_large = true;
+            return Err(invalid_data("request body too large".to_string()));
+        }
+        return Ok(read_size);
+    }
+}
+
+// finds the end of the request head, the index after the blank line
+pub fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+pub fn parse_request_head(head: &[u8]) -> Result<RequestHead, String> {
+    let head = match std::str::from_utf8(head) {
+        Ok(head) => head,
+        Err(_) => return Err("request head is not valid UTF-8".to_string()),
+    };
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut request_line_parts = request_line.split(' ');
+    let (method, target, version) = match (
+        request_line_parts.next(),
+        request_line_parts.next(),
+        request_line_parts.next(),
+        request_line_parts.next(),
+    ) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && target.starts_with('/') && version.starts_with("HTTP/") =>
+        {
+            (method, target, &version["HTTP/".len()..])
+        }
+        // the query string is left out, it can hold credentials
+        _ => {
+            let without_query = request_line.split('?').next().unwrap_or_default();
+            return Err(format!("invalid request line | {}", without_query));
+        }
+    };
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with(' ') => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            // just the name, the value can be a credential
+            Some((name, _)) => return Err(format!("invalid header line | {}", name)),
+            None => return Err("invalid header line".to_string()),
+        }
+    }
+
+    return Ok(RequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    });
+}
+
+// bodies without a known length are sent with chunked transfer encoding
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // `length` bytes of the file starting at `offset`, read as it's sent
+    File {
+        file: File,
+        offset: u64,
+        length: u64,
+    },
+    // copied to the client as it's read, ex: a proxied upstream response
+    Stream {
+        reader: Box<dyn Read>,
+        length: Option<u64>,
+    },
+    // each chunk is sent as soon as it's produced, chunks from another thread can be passed
+    // as `receiver.into_iter().map(Ok)`, an error aborts the response without ending the body
+    Chunked(Box<dyn Iterator<Item = io::Result<Chunk>>>),
+}
+
+pub enum Chunk {
+    Data(Vec<u8>),
+    // header fields sent after the last chunk, ends the body
+    Trailers(Vec<(String, String)>),
+}
+
+impl Body {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Body::Empty => true,
+            Body::Bytes(bytes) => bytes.is_empty(),
+            Body::File { length, .. } => *length == 0,
+            Body::Stream { length, .. } => *length == Some(0),
+            Body::Chunked(_) => false,
+        }
+    }
+}
+
+// takes over the client connection once the response head is written, ex: a WebSocket after
+// a 101 or an event stream, runs on its own thread. The bytes are what the client sent after
+// the request, ex: WebSocket frames that arrived with the handshake
+pub type Upgrade = Box<dyn FnOnce(Stream, Vec<u8>) + Send>;
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        return Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            upgrade: None,
+        };
+    }
+
+    // plain text response, used for errors and simple endpoints
+    pub fn text(status: u16, message: &str) -> Self {
+        return Response::new(status)
+            .with_body("text/html; charset=UTF-8", message.as_bytes().to_vec());
+    }
+
+    pub fn with_body(mut self, content_type: &str, body: Vec<u8>) -> Self {
+        self.set_header("Content-Type", content_type);
+        self.body = Body::Bytes(body);
+        return self;
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        return self;
+    }
+
+    // replaces every header with the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .retain(|(header_name, _)| !header_name.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // returns the number of body bytes sent, not counting chunk framing
+    pub fn write_to(&mut self, writer: &mut impl Write) -> io::Result<u64> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match &self.body {
+            // informational and 204 responses never have a body
+            Body::Empty | Body::Bytes(_) if self.status < 200 || self.status == 204 => (),
+            // the upgrade writes the rest of the response, which ends when it closes the connection
+            _ if self.upgrade.is_some() => head.push_str("Connection: close\r\n"),
+            Body::Empty => head.push_str("Content-Length: 0\r\n"),
+            Body::Bytes(bytes) => head.push_str(&format!("Content-Length: {}\r\n", bytes.len())),
+            Body::File { length, .. }
+            | Body::Stream {
+                length: Some(length),
+                ..
+            } => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            Body::Stream { length: None, .. } | Body::Chunked(_) => {
+                head.push_str("Transfer-Encoding: chunked\r\n")
+            }
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+
+        let body_bytes = match &mut self.body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => {
+                writer.write_all(bytes)?;
+                bytes.len() as u64
+            }
+            Body::File {
+                file,
+                offset,
+                length,
+            } => {
+                file.seek(SeekFrom::Start(*offset))?;
+                copy_body(&mut (&*file).take(*length), writer, *length)?
+            }
+            Body::Stream {
+                reader,
+                length: Some(length),
+            } => copy_body(reader, writer, *length)?,
+            Body::Stream {
+                reader,
+                length: None,
+            } => {
+                let mut buf = vec![0u8; 16 * 1024];
+                let mut sent = 0;
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(read_size) => {
+                            write_chunk(writer, &buf[..read_size])?;
+                            sent += read_size as u64;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                writer.write_all(b"0\r\n\r\n")?;
+                sent
+            }
+            Body::Chunked(chunks) => {
+                let mut trailers = Vec::new();
+                let mut sent = 0;
+                for chunk in chunks {
+                    match chunk? {
+                        Chunk::Data(data) => {
+                            write_chunk(writer, &data)?;
+                            sent += data.len() as u64;
+                        }
+                        Chunk::Trailers(fields) => {
+                            trailers = fields;
+                            break;
+                        }
+                    }
+                }
+                let mut end = "0\r\n".to_string();
+                for (name, value) in trailers {
+                    end.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                end.push_str("\r\n");
+                writer.write_all(end.as_bytes())?;
+                sent
+            }
+        };
+        writer.flush()?;
+        return Ok(body_bytes);
+    }
+}
+
+// errors when the body is shorter than the Content-Length that was already sent
+fn copy_body(reader: &mut impl Read, writer: &mut impl Write, length: u64) -> io::Result<u64> {
+    let copied = io::copy(reader, writer)?;
+    if copied < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "body ended before its content length",
+        ));
+    }
+    return Ok(copied);
+}
+
+// flushed right away so streamed content reaches the client as it's produced
+pub fn write_chunk(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    // an empty chunk would end the body
+    if data.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(format!("{:x}\r\n", data.len()).as_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")?;
+    return writer.flush();
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+// longer chunk size, trailer and upstream header lines are rejected
+const MAX_LINE_BYTES: usize = 8 * 1024;
+// the trailers of a chunked body all together
+const MAX_TRAILER_BYTES: usize = 16 * 1024;
+
+// decodes a chunked transfer coded body, trailers are kept for `trailers`
+pub struct ChunkedReader<R> {
+    inner: R,
+    // bytes left in the current chunk
+    remaining: u64,
+    done: bool,
+    trailers: Vec<(String, String)>,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        return ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+            trailers: Vec::new(),
+        };
+    }
+
+    // the terminating chunk and trailers were read
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // header fields sent after the last chunk, empty until the body was read to the end
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // chunk size line, ex: "1a;name=value"
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = match u64::from_str_radix(size, 16) {
+                Ok(size) => size,
+                Err(_) => return Err(invalid_data(format!("invalid chunk size | {}", line))),
+            };
+            if self.remaining == 0 {
+                let mut trailer_bytes = 0;
+                loop {
+                    let line = read_line(&mut self.inner)?;
+                    if line.is_empty() {
+                        break;
+                    }
+                    trailer_bytes += line.len();
+                    if trailer_bytes > MAX_TRAILER_BYTES {
+                        return Err(invalid_data("trailers are too large".to_string()));
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        self.trailers
+                            .push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max_read = buf.len().min(self.remaining as usize);
+        let read_size = self.inner.read(&mut buf[..max_read])?;
+        if read_size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= read_size as u64;
+        if self.remaining == 0 && !read_line(&mut self.inner)?.is_empty() {
+            return Err(invalid_data(
+                "chunk data is longer than its size".to_string(),
+            ));
+        }
+        return Ok(read_size);
+    }
+}
+
+// reads a CRLF terminated line without the line ending, at most MAX_LINE_BYTES long
+pub fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = Vec::new();
+    if reader
+        .take(MAX_LINE_BYTES as u64 + 2)
+        .read_until(b'\n', &mut line)?
+        == 0
+    {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    if !line.ends_with(b"\n") && line.len() > MAX_LINE_BYTES {
+        return Err(invalid_data("line is too long".to_string()));
+    }
+    if line.ends_with(b"\r\n") {
+        line.truncate(line.len() - 2);
+    } else if line.ends_with(b"\n") {
+        line.truncate(line.len() - 1);
+    }
+    return String::from_utf8(line)
+        .map_err(|_| invalid_data("line is not valid UTF-8".to_string()));
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn decode(body: Vec<u8>) -> (io::Result<Vec<u8>>, Vec<(String, String)>) {
+        let mut reader = ChunkedReader::new(Cursor::new(body));
+        let mut decoded = Vec::new();
+        let result = reader.read_to_end(&mut decoded).map(|_| decoded);
+        return (result, reader.trailers().to_vec());
+    }
+
+    #[test]
+    fn chunked_bodies_keep_their_trailers() {
+        let (body, trailers) =
+            decode(b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n".to_vec());
+        assert_eq!(body.unwrap(), b"hello world");
+        assert_eq!(trailers, [("Checksum".to_string(), "abc".to_string())]);
+    }
+
+    #[test]
+    fn long_chunk_size_lines_are_rejected() {
+        let mut body = b"5;".to_vec();
+        body.extend(vec![b'x'; MAX_LINE_BYTES]);
+        body.extend(b"\r\nhello\r\n0\r\n\r\n");
+        let (body, _) = decode(body);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a line right at the limit is still read
+        let mut body = b"5;".to_vec();
+        body.extend(vec![b'x'; MAX_LINE_BYTES - 2]);
+        body.extend(b"\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(decode(body).0.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn large_trailers_are_rejected() {
+        let mut body = b"0\r\n".to_vec();
+        let trailer = format!("X-Padding: {}\r\n", "a".repeat(1000));
+        for _ in 0..(MAX_TRAILER_BYTES / 1000 + 1) {
+            body.extend(trailer.as_bytes());
+        }
+        body.extend(b"\r\n");
+        let (body, _) = decode(body);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
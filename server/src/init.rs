@@ -1,18 +1,47 @@
 use std::{
     collections::{BTreeSet, HashSet},
-    fs::read_dir,
-    os::fd::{AsRawFd, OwnedFd},
+    fs::{Permissions, read_dir, remove_file, set_permissions, symlink_metadata},
+    io,
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::UnixStream,
+        },
+    },
     path::PathBuf,
 };
 
-use log::{error, info};
-use nix::sys::socket::{
-    AddressFamily, Backlog, SockFlag, SockType, SockaddrIn, bind, listen, socket,
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    libc::SOMAXCONN,
+    sys::socket::{
+        AddressFamily, Backlog, SockFlag, SockType, SockaddrStorage, UnixAddr, bind, listen,
+        setsockopt, socket, sockopt,
+    },
+    unistd::{Gid, Uid, chown},
 };
 
-use crate::telemetry::force_export_telemetry;
+use crate::{
+    listener::{ListenAddress, ListenerConfig, UnixSocketOptions},
+    telemetry::force_export_telemetry,
+};
 
 pub fn get_static_file_paths(path: PathBuf) -> HashSet<PathBuf> {
+    return match read_static_file_paths(path) {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!(error = format!("{}", e).as_str(); "Could Not Read Directory");
+            force_export_telemetry(false);
+            panic!("Could Not Read Directory | {}", e);
+        }
+    };
+}
+
+// same walk without panicking, used when the index is reloaded at runtime
+pub fn read_static_file_paths(path: PathBuf) -> io::Result<HashSet<PathBuf>> {
     /*
     Assumption:
     - The parent directory exists and has files in it.
@@ -23,30 +52,9 @@ pub fn get_static_file_paths(path: PathBuf) -> HashSet<PathBuf> {
     let mut path_bufs: HashSet<PathBuf> = HashSet::new();
     let mut check_directories = BTreeSet::from([path]);
 
-    while !check_directories.is_empty() {
-        let directory_path = match check_directories.pop_first() {
-            Some(path) => path,
-            None => break,
-        };
-        let dir_reader = match read_dir(directory_path) {
-            Ok(reader) => reader,
-            Err(e) => {
-                error!(error = format!("{}", e).as_str(); "Could Not Read Directory");
-                force_export_telemetry(false);
-                panic!("Could Not Read Directory | {}", e);
-            }
-        };
-
-        for result in dir_reader {
-            let entry = match result {
-                Ok(entry) => entry,
-                Err(e) => {
-                    error!(error = format!("{}", e).as_str(); "Could Not Read Directory Entry");
-                    force_export_telemetry(false);
-                    panic!("Could Not Read Directory Entry | {}", e);
-                }
-            };
-
+    while let Some(directory_path) = check_directories.pop_first() {
+        for entry in read_dir(directory_path)? {
+            let entry = entry?;
             if entry.path().is_dir() {
                 check_directories.insert(entry.path());
             } else {
@@ -55,19 +63,29 @@ pub fn get_static_file_paths(path: PathBuf) -> HashSet<PathBuf> {
         }
     }
 
-    return path_bufs;
+    return Ok(path_bufs);
 }
 
-pub fn setup_listening_socket(sock_addr: SockaddrIn, conn_backlog: Backlog) -> OwnedFd {
-    let sock_fd = match socket(
-        AddressFamily::Inet,
-        SockType::Stream,
-        SockFlag::empty(),
-        None,
-    ) {
+pub fn setup_listening_socket(config: &ListenerConfig) -> Option<OwnedFd> {
+    let address_family = match &config.address {
+        ListenAddress::Inet(SocketAddr::V4(_)) => AddressFamily::Inet,
+        ListenAddress::Inet(SocketAddr::V6(_)) => AddressFamily::Inet6,
+        ListenAddress::Unix(_) => AddressFamily::Unix,
+    };
+    if let ListenAddress::Unix(options) = &config.address
+        && options.remove_stale
+    {
+        remove_stale_socket_file(config, options);
+    }
+
+    let sock_fd = match socket(address_family, SockType::Stream, SockFlag::empty(), None) {
         Ok(fd) => fd,
+        Err(e) if config.optional && is_unavailable_address(e) => {
+            warn!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Skipping optional listener - address family not supported");
+            return None;
+        }
         Err(e) => {
-            error!(errno = format!("{}", e).as_str(); "Couldn't create listening socket - Cross reference nix & socket(2) docs.");
+            error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't create listening socket - Cross reference nix & socket(2) docs.");
             force_export_telemetry(false);
             panic!(
                 "Couldn't create listening socket - Cross reference nix & socket(2) docs. | {}",
@@ -76,8 +94,52 @@ pub fn setup_listening_socket(sock_addr: SockaddrIn, conn_backlog: Backlog) -> O
         }
     };
 
-    if let Err(e) = bind(sock_fd.as_raw_fd(), &sock_addr) {
-        error!(errno = format!("{}", e).as_str(); "Couldn't bind listening socket to Address - Cross reference nix & bind(2) docs.");
+    if config.reuse_address
+        && address_family != AddressFamily::Unix
+        && let Err(e) = setsockopt(&sock_fd, sockopt::ReuseAddr, &true)
+    {
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't set SO_REUSEADDR on listening socket - Cross reference nix & setsockopt(2) docs.");
+        force_export_telemetry(false);
+        panic!(
+            "Couldn't set SO_REUSEADDR on listening socket - Cross reference nix & setsockopt(2) docs. | {}",
+            e
+        );
+    }
+
+    if config.reuse_port
+        && let Err(e) = setsockopt(&sock_fd, sockopt::ReusePort, &true)
+    {
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't set SO_REUSEPORT on listening socket - Cross reference nix & socket(7) docs.");
+        force_export_telemetry(false);
+        panic!(
+            "Couldn't set SO_REUSEPORT on listening socket - Cross reference nix & socket(7) docs. | {}",
+            e
+        );
+    }
+
+    if address_family == AddressFamily::Inet6
+        && let Err(e) = setsockopt(&sock_fd, sockopt::Ipv6V6Only, &config.ipv6_only)
+    {
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't set IPV6_V6ONLY on listening socket - Cross reference nix & ipv6(7) docs.");
+        force_export_telemetry(false);
+        panic!(
+            "Couldn't set IPV6_V6ONLY on listening socket - Cross reference nix & ipv6(7) docs. | {}",
+            e
+        );
+    }
+
+    let bind_result = match &config.address {
+        ListenAddress::Inet(addr) => bind(sock_fd.as_raw_fd(), &SockaddrStorage::from(*addr)),
+        ListenAddress::Unix(options) => {
+            UnixAddr::new(&options.path).and_then(|addr| bind(sock_fd.as_raw_fd(), &addr))
+        }
+    };
+    if let Err(e) = bind_result {
+        if config.optional && is_unavailable_address(e) {
+            warn!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Skipping optional listener - address not available");
+            return None;
+        }
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't bind listening socket to Address - Cross reference nix & bind(2) docs.");
         force_export_telemetry(false);
         panic!(
             "Couldn't bind listening socket to Address - Cross reference nix & bind(2) docs. | {}",
@@ -85,8 +147,25 @@ pub fn setup_listening_socket(sock_addr: SockaddrIn, conn_backlog: Backlog) -> O
         );
     };
 
-    if let Err(e) = listen(&sock_fd, conn_backlog) {
-        error!(errno = format!("{}", e).as_str(); "Couldn't configure listening socket to listen - Cross reference nix & listen(2) docs.");
+    if let ListenAddress::Unix(options) = &config.address {
+        set_socket_file_permissions(config, options);
+    }
+
+    let backlog = match config.backlog {
+        // Backlog::new rejects SOMAXCONN itself
+        SOMAXCONN => Ok(Backlog::MAXCONN),
+        backlog => Backlog::new(backlog),
+    };
+    let backlog = match backlog {
+        Ok(backlog) => backlog,
+        Err(e) => {
+            error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Invalid listen backlog");
+            force_export_telemetry(false);
+            panic!("Invalid listen backlog | {}", e);
+        }
+    };
+    if let Err(e) = listen(&sock_fd, backlog) {
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't configure listening socket to listen - Cross reference nix & listen(2) docs.");
         force_export_telemetry(false);
         panic!(
             "Couldn't configure listening socket to listen - Cross reference nix & listen(2) docs. | {}",
@@ -94,6 +173,69 @@ pub fn setup_listening_socket(sock_addr: SockaddrIn, conn_backlog: Backlog) -> O
         );
     };
 
-    info!(listening_address = *sock_addr.to_string().as_str(); "Server listening for requests");
-    return sock_fd;
+    info!(
+        listener = config.name.as_str(),
+        listening_address = config.address.to_string().as_str();
+        "Server listening for requests"
+    );
+    return Some(sock_fd);
+}
+
+// the host has no interface or protocol support for the address, not a misconfiguration
+fn is_unavailable_address(e: Errno) -> bool {
+    return matches!(e, Errno::EAFNOSUPPORT | Errno::EADDRNOTAVAIL);
+}
+
+fn remove_stale_socket_file(config: &ListenerConfig, options: &UnixSocketOptions) {
+    let metadata = match symlink_metadata(&options.path) {
+        Ok(metadata) => metadata,
+        // nothing to clean up
+        Err(_) => return,
+    };
+    if !metadata.file_type().is_socket() {
+        error!(listener = config.name.as_str(), path = options.path.display().to_string().as_str(); "Unix socket path exists and is not a socket");
+        force_export_telemetry(false);
+        panic!(
+            "Unix socket path exists and is not a socket | {}",
+            options.path.display()
+        );
+    }
+    // a successful connection means another process still owns the socket
+    if UnixStream::connect(&options.path).is_ok() {
+        error!(listener = config.name.as_str(), path = options.path.display().to_string().as_str(); "Unix socket is already in use");
+        force_export_telemetry(false);
+        panic!("Unix socket is already in use | {}", options.path.display());
+    }
+
+    if let Err(e) = remove_file(&options.path) {
+        error!(error = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't remove stale unix socket file");
+        force_export_telemetry(false);
+        panic!("Couldn't remove stale unix socket file | {}", e);
+    }
+    warn!(listener = config.name.as_str(), path = options.path.display().to_string().as_str(); "Removed stale unix socket file");
+}
+
+fn set_socket_file_permissions(config: &ListenerConfig, options: &UnixSocketOptions) {
+    if let Some(mode) = options.mode
+        && let Err(e) = set_permissions(&options.path, Permissions::from_mode(mode))
+    {
+        error!(error = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't set unix socket file mode");
+        force_export_telemetry(false);
+        panic!("Couldn't set unix socket file mode | {}", e);
+    }
+
+    if (options.owner.is_some() || options.group.is_some())
+        && let Err(e) = chown(
+            &options.path,
+            options.owner.map(Uid::from_raw),
+            options.group.map(Gid::from_raw),
+        )
+    {
+        error!(errno = format!("{}", e).as_str(), listener = config.name.as_str(); "Couldn't set unix socket file owner - Cross reference nix & chown(2) docs.");
+        force_export_telemetry(false);
+        panic!(
+            "Couldn't set unix socket file owner - Cross reference nix & chown(2) docs. | {}",
+            e
+        );
+    }
 }
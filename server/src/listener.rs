@@ -0,0 +1,188 @@
+use std::{
+    fmt,
+    fs::remove_file,
+    net::SocketAddr,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use log::{info, warn};
+use nix::{
+    libc::SOMAXCONN,
+    sys::socket::{SockaddrStorage, UnixCredentials, getpeername, getsockopt, sockopt},
+};
+use serde::Deserialize;
+
+use crate::{init::setup_listening_socket, redirect::RedirectConfig};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ListenAddress {
+    Inet(SocketAddr),
+    Unix(UnixSocketOptions),
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Inet(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(options) => write!(f, "unix:{}", options.path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnixSocketOptions {
+    pub path: PathBuf,
+    // permission bits applied to the socket file after binding, ex: 0o660
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    // remove a leftover socket file if no process is listening on it
+    #[serde(default = "default_true")]
+    pub remove_stale: bool,
+}
+
+// per listener socket options, one of these is created for every address the server listens on
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub address: ListenAddress,
+    #[serde(default = "default_backlog")]
+    pub backlog: i32,
+    // the options below only apply to IP sockets
+    #[serde(default = "default_true")]
+    pub reuse_address: bool,
+    // only applies to IPv6 addresses, false gives a dual-stack socket
+    #[serde(default)]
+    pub ipv6_only: bool,
+    // set by the server in reuse port accept mode
+    #[serde(skip)]
+    pub reuse_port: bool,
+    // terminate TLS on this listener with the certificates in the [tls] section
+    #[serde(default)]
+    pub tls: bool,
+    // redirect every request to HTTPS instead of serving it
+    pub redirect: Option<RedirectConfig>,
+    // serve only the admin endpoints, ex: Prometheus scrapes, instead of the site
+    #[serde(default)]
+    pub admin: bool,
+    // skipped with a warning when the host doesn't support the address, ex: no IPv6 loopback in a container
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl ListenerConfig {
+    pub fn inet(name: &str, address: SocketAddr) -> Self {
+        return ListenerConfig {
+            name: name.to_string(),
+            address: ListenAddress::Inet(address),
+            backlog: default_backlog(),
+            reuse_address: true,
+            ipv6_only: false,
+            reuse_port: false,
+            tls: false,
+            redirect: None,
+            admin: false,
+            optional: false,
+        };
+    }
+}
+
+fn default_backlog() -> i32 {
+    SOMAXCONN
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub struct Listener {
+    pub config: Arc<ListenerConfig>,
+    sock: OwnedFd,
+}
+
+impl Listener {
+    // None when an optional listener's address isn't available
+    pub fn bind(config: ListenerConfig) -> Option<Self> {
+        let sock = setup_listening_socket(&config)?;
+        return Some(Listener {
+            config: Arc::new(config),
+            sock,
+        });
+    }
+
+    pub fn as_fd(&self) -> BorrowedFd<'_> {
+        self.sock.as_fd()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // the socket file outlives the socket, so clean it up for the next start
+        if let ListenAddress::Unix(options) = &self.config.address {
+            match remove_file(&options.path) {
+                Ok(_) => {
+                    info!(listener = self.config.name.as_str(); "Removed unix socket file")
+                }
+                Err(e) => {
+                    warn!(listener = self.config.name.as_str(), error = format!("{}", e).as_str(); "Couldn't remove unix socket file")
+                }
+            }
+        }
+    }
+}
+
+pub enum Peer {
+    Inet(SockaddrStorage),
+    Unix(UnixCredentials),
+}
+
+impl Peer {
+    pub fn socket_address(&self) -> Option<SocketAddr> {
+        match self {
+            Peer::Inet(addr) => match (addr.as_sockaddr_in(), addr.as_sockaddr_in6()) {
+                (Some(addr), _) => Some(SocketAddr::V4((*addr).into())),
+                (_, Some(addr)) => Some(SocketAddr::V6((*addr).into())),
+                (None, None) => None,
+            },
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Inet(addr) => write!(f, "{}", addr),
+            Peer::Unix(creds) => write!(
+                f,
+                "pid={} uid={} gid={}",
+                creds.pid(),
+                creds.uid(),
+                creds.gid()
+            ),
+        }
+    }
+}
+
+// a connection accepted by one of the listeners, sent to the request handlers
+pub struct AcceptedConnection {
+    pub fd: OwnedFd,
+    pub listener: Arc<ListenerConfig>,
+}
+
+impl AcceptedConnection {
+    // unix sockets have no meaningful peer address, so report the peer process credentials instead
+    pub fn peer(&self) -> Option<Peer> {
+        match self.listener.address {
+            ListenAddress::Inet(_) => getpeername::<SockaddrStorage>(self.fd.as_raw_fd())
+                .ok()
+                .map(Peer::Inet),
+            ListenAddress::Unix(_) => getsockopt(&self.fd, sockopt::PeerCredentials)
+                .ok()
+                .map(Peer::Unix),
+        }
+    }
+}
@@ -1,39 +1,47 @@
+mod access_log;
+mod admin;
+mod config;
+mod connection;
+mod health;
+mod http;
 mod init;
+mod listener;
+mod middleware;
+mod otlp;
+mod prometheus;
+mod proxy;
+mod redaction;
+mod redirect;
+mod router;
+mod sampling;
 mod serve;
 mod signal;
+mod sse;
+mod static_files;
 mod statics;
 mod telemetry;
-use nix::sys::socket::SockaddrIn;
+#[cfg(feature = "tls")]
+mod tls;
+mod upstream;
+mod websocket;
+use config::load_config;
 use serve::Server;
 use signal::setup_sig_handler;
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-    time::Duration,
-};
 use telemetry::{init_telemetry, shutdown_telemetry};
 
 fn main() {
-    let (log_provider, metrics_provider, tracer_provider) = init_telemetry();
+    let config = load_config();
+    let (log_provider, metrics_provider, tracer_provider) = init_telemetry(&config.telemetry);
     setup_sig_handler();
     /*
     TODO Start:
     test?? - only file gathering and integration test
     - use an atomic for the shutdown signal
     - dockerize the application.
-    - next time have an abstraction over the server router
-    - export to a optel collector
-    - put the path of static files in the logs
     - reorg init module into serve module
-    - remove hardcoding from routering function
     - ignore the syscall interrupted signals when flag is set
     */
-    let mut server = {
-        let sock_addr = SockaddrIn::from(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080));
-        let static_files_location = PathBuf::from("../client/dist");
-        let timeout = Duration::from_millis(400);
-        Server::init_server(timeout, static_files_location, sock_addr)
-    };
+    let mut server = Server::init_server(&config);
     server.begin_connection_handlers();
     server.accept_connections_and_send_to_handlers();
     server.wait_for_handlers_to_finish();
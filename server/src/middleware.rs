@@ -0,0 +1,430 @@
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::Arc,
+    time::Instant,
+};
+
+use log::error;
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+use serde::Deserialize;
+
+use crate::http::{Request, Response, reason_phrase};
+
+/*
+A layer wraps everything after it in the chain. It can:
+- inspect or modify the request before calling `next.run`
+- short-circuit by returning a response without calling `next.run`
+- post-process the response returned by `next.run`
+*/
+pub trait Layer: Send + Sync {
+    fn handle(&self, req: &mut Request, next: Next<'_>) -> Response;
+}
+
+// the rest of the chain, ending with the endpoint (the router)
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Layer>],
+    endpoint: &'a dyn Fn(&mut Request) -> Response,
+}
+
+impl Next<'_> {
+    pub fn run(self, req: &mut Request) -> Response {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer.handle(
+                req,
+                Next {
+                    layers: rest,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+// layers run in the order they are added, the first layer added is the outermost
+#[derive(Default)]
+pub struct MiddlewareChain {
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> Self {
+        return MiddlewareChain { layers: Vec::new() };
+    }
+
+    pub fn layer(&mut self, layer: impl Layer + 'static) -> &mut Self {
+        self.layers.push(Arc::new(layer));
+        return self;
+    }
+
+    pub fn run(&self, req: &mut Request, endpoint: &dyn Fn(&mut Request) -> Response) -> Response {
+        Next {
+            layers: &self.layers,
+            endpoint,
+        }
+        .run(req)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MiddlewareConfig {
+    pub request_id: bool,
+    pub timing: bool,
+    pub security_headers: SecurityHeadersConfig,
+    pub error_pages: bool,
+}
+
+impl Default for MiddlewareConfig {
+    fn default() -> Self {
+        return MiddlewareConfig {
+            request_id: true,
+            timing: true,
+            security_headers: SecurityHeadersConfig::default(),
+            error_pages: true,
+        };
+    }
+}
+
+pub fn build_middleware(config: &MiddlewareConfig) -> MiddlewareChain {
+    let mut chain = MiddlewareChain::new();
+    if config.request_id {
+        chain.layer(RequestId);
+    }
+    if config.timing {
+        chain.layer(Timing);
+    }
+    if config.security_headers.enabled {
+        chain.layer(SecurityHeaders::new(&config.security_headers));
+    }
+    // innermost so the layers above still see the mapped response
+    if config.error_pages {
+        chain.layer(ErrorMapping);
+    }
+    return chain;
+}
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// keeps a sane incoming X-Request-Id or generates one, and echoes it on the response
+pub struct RequestId;
+
+impl Layer for RequestId {
+    fn handle(&self, req: &mut Request, next: Next<'_>) -> Response {
+        let incoming = req.header(REQUEST_ID_HEADER).filter(|id| {
+            !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        });
+        let request_id = match incoming {
+            Some(id) => id.to_string(),
+            None => RandomIdGenerator::default().new_trace_id().to_string(),
+        };
+        req.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+        req.headers
+            .push((REQUEST_ID_HEADER.to_string(), request_id.clone()));
+
+        let mut resp = next.run(req);
+        resp.set_header(REQUEST_ID_HEADER, &request_id);
+        return resp;
+    }
+}
+
+// reports the time spent in the rest of the chain with a Server-Timing header
+pub struct Timing;
+
+impl Layer for Timing {
+    fn handle(&self, req: &mut Request, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let mut resp = next.run(req);
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+        resp.set_header("Server-Timing", &format!("app;dur={:.3}", duration_ms));
+        return resp;
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub cross_origin_opener_policy: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        return SecurityHeadersConfig {
+            enabled: true,
+            content_type_options: Some("nosniff".to_string()),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            content_security_policy: None,
+            cross_origin_opener_policy: Some("same-origin".to_string()),
+        };
+    }
+}
+
+// adds the configured headers unless the handler already set them
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        let headers = [
+            ("X-Content-Type-Options", &config.content_type_options),
+            ("X-Frame-Options", &config.frame_options),
+            ("Referrer-Policy", &config.referrer_policy),
+            ("Content-Security-Policy", &config.content_security_policy),
+            (
+                "Cross-Origin-Opener-Policy",
+                &config.cross_origin_opener_policy,
+            ),
+        ]
+        .into_iter()
+        // an empty value turns a default header off
+        .filter_map(|(name, value)| value.clone().map(|value| (name, value)))
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+
+        return SecurityHeaders { headers };
+    }
+}
+
+impl Layer for SecurityHeaders {
+    fn handle(&self, req: &mut Request, next: Next<'_>) -> Response {
+        let mut resp = next.run(req);
+        for (name, value) in &self.headers {
+            if resp.header(name).is_none() {
+                resp.set_header(name, value);
+            }
+        }
+        return resp;
+    }
+}
+
+// turns a panicking handler into a 500 and gives empty error responses a body
+pub struct ErrorMapping;
+
+impl Layer for ErrorMapping {
+    fn handle(&self, req: &mut Request, next: Next<'_>) -> Response {
+        let method = req.method.clone();
+        let path = req.path.clone();
+        let resp = match catch_unwind(AssertUnwindSafe(|| next.run(req))) {
+            Ok(resp) => resp,
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                error!(method = method.as_str(), path = path.as_str(), error = message.as_str(); "Handler panicked");
+                Response::new(500)
+            }
+        };
+
+        if resp.status >= 400 && resp.body.is_empty() {
+            let message = format!("{} {}", resp.status, reason_phrase(resp.status));
+            let status = resp.status;
+            let mut mapped = Response::text(status, &message);
+            for (name, value) in resp.headers {
+                if !name.eq_ignore_ascii_case("content-type") {
+                    mapped.headers.push((name, value));
+                }
+            }
+            return mapped;
+        }
+        return resp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::http::{Body, RequestBody, RequestHead};
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "/items".to_string(),
+            version: "1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        return head.into_request(RequestBody::from_bytes(Vec::new()));
+    }
+
+    fn body(resp: &Response) -> &[u8] {
+        match &resp.body {
+            Body::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    // records when it runs, before and after the rest of the chain
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Layer for Recorder {
+        fn handle(&self, req: &mut Request, next: Next<'_>) -> Response {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            let resp = next.run(req);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+            return resp;
+        }
+    }
+
+    #[test]
+    fn valid_request_ids_are_kept() {
+        let mut chain = MiddlewareChain::new();
+        chain.layer(RequestId);
+        let mut req = request(&[("x-request-id", "checkout-42.retry_1")]);
+        let resp = chain.run(&mut req, &|req: &mut Request| {
+            assert_eq!(req.header(REQUEST_ID_HEADER), Some("checkout-42.retry_1"));
+            Response::new(204)
+        });
+        assert_eq!(resp.header(REQUEST_ID_HEADER), Some("checkout-42.retry_1"));
+    }
+
+    #[test]
+    fn invalid_request_ids_are_replaced() {
+        let mut chain = MiddlewareChain::new();
+        chain.layer(RequestId);
+        let too_long = "a".repeat(129);
+        for incoming in ["", "has space", "quote\"d", too_long.as_str()] {
+            let mut req = request(&[("X-Request-Id", incoming)]);
+            let resp = chain.run(&mut req, &|_: &mut Request| Response::new(204));
+            let generated = resp.header(REQUEST_ID_HEADER).unwrap();
+            assert_eq!(generated.len(), 32);
+            assert!(generated.chars().all(|c| c.is_ascii_hexdigit()));
+            // handlers and the access log see the same id, only once
+            assert_eq!(req.header(REQUEST_ID_HEADER), Some(generated));
+            assert_eq!(req.headers.len(), 1);
+        }
+    }
+
+    #[test]
+    fn security_headers_are_added_unless_disabled() {
+        let config = SecurityHeadersConfig {
+            content_security_policy: Some("default-src 'self'".to_string()),
+            referrer_policy: Some(String::new()),
+            ..SecurityHeadersConfig::default()
+        };
+        let mut chain = MiddlewareChain::new();
+        chain.layer(SecurityHeaders::new(&config));
+        let resp = chain.run(&mut request(&[]), &|_: &mut Request| {
+            Response::new(200).with_header("X-Frame-Options", "SAMEORIGIN")
+        });
+        assert_eq!(resp.header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(
+            resp.header("Content-Security-Policy"),
+            Some("default-src 'self'")
+        );
+        assert_eq!(
+            resp.header("Cross-Origin-Opener-Policy"),
+            Some("same-origin")
+        );
+        // set by the handler, or turned off with an empty value
+        assert_eq!(resp.header("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(resp.header("Referrer-Policy"), None);
+
+        let config = MiddlewareConfig {
+            security_headers: SecurityHeadersConfig {
+                enabled: false,
+                ..SecurityHeadersConfig::default()
+            },
+            ..MiddlewareConfig::default()
+        };
+        let resp =
+            build_middleware(&config).run(&mut request(&[]), &|_: &mut Request| Response::new(200));
+        assert_eq!(resp.header("X-Content-Type-Options"), None);
+        assert_eq!(resp.header("X-Frame-Options"), None);
+    }
+
+    #[test]
+    fn failed_handlers_get_error_pages() {
+        let mut chain = MiddlewareChain::new();
+        chain.layer(ErrorMapping);
+
+        let resp = chain.run(&mut request(&[]), &|_: &mut Request| -> Response {
+            panic!("handler bug")
+        });
+        assert_eq!(resp.status, 500);
+        assert_eq!(body(&resp), b"500 Internal Server Error");
+
+        let resp = chain.run(&mut request(&[]), &|_: &mut Request| {
+            Response::new(503).with_header("Retry-After", "5")
+        });
+        assert_eq!(resp.status, 503);
+        assert_eq!(body(&resp), b"503 Service Unavailable");
+        assert_eq!(resp.header("Retry-After"), Some("5"));
+        assert_eq!(
+            resp.header("Content-Type"),
+            Some("text/html; charset=UTF-8")
+        );
+
+        // bodies the handler wrote are kept
+        let resp = chain.run(&mut request(&[]), &|_: &mut Request| {
+            Response::new(502).with_body("application/json", b"{}".to_vec())
+        });
+        assert_eq!(body(&resp), b"{}");
+        assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn layers_run_in_the_order_they_were_added() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = MiddlewareChain::new();
+        for name in ["outer", "inner"] {
+            chain.layer(Recorder {
+                name,
+                calls: calls.clone(),
+            });
+        }
+        chain.run(&mut request(&[]), &|_: &mut Request| {
+            calls.lock().unwrap().push("endpoint".to_string());
+            Response::new(204)
+        });
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer before",
+                "inner before",
+                "endpoint",
+                "inner after",
+                "outer after"
+            ]
+        );
+    }
+
+    #[test]
+    fn error_pages_run_inside_the_other_layers() {
+        let chain = build_middleware(&MiddlewareConfig::default());
+        let resp = chain.run(&mut request(&[]), &|_: &mut Request| -> Response {
+            panic!("handler bug")
+        });
+        // the mapped 500 still goes through the request id, timing and security header layers
+        assert_eq!(resp.status, 500);
+        assert!(resp.header(REQUEST_ID_HEADER).is_some());
+        assert!(resp.header("Server-Timing").is_some());
+        assert_eq!(resp.header("X-Content-Type-Options"), Some("nosniff"));
+    }
+}
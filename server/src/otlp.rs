@@ -0,0 +1,531 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Write,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use flate2::{Compression as GzipLevel, write::GzEncoder};
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{
+    ExporterBuildError, LogExporter, MetricExporter, SpanExporter, WithExportConfig,
+    WithHttpConfig, WithTonicConfig,
+};
+use serde::Deserialize;
+use tokio::runtime::{self, Runtime};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+
+use crate::{prometheus::PrometheusConfig, sampling::SamplerConfig, statics::OTLP_RUNTIME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExporterKind {
+    Stdout,
+    OtlpGrpc,
+    OtlpHttp,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExporterConfig {
+    pub exporter: ExporterKind,
+    // the collector's base URL, only plain http is supported, otlp_http appends the signal's
+    // path, defaults to http://127.0.0.1:4317 for otlp_grpc and http://127.0.0.1:4318 for otlp_http
+    pub endpoint: Option<String>,
+    // sent with every export, ex: an authorization header
+    pub headers: BTreeMap<String, String>,
+    pub timeout_ms: u64,
+    pub compression: Compression,
+    pub batch: BatchConfig,
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        return ExporterConfig {
+            exporter: ExporterKind::Stdout,
+            endpoint: None,
+            headers: BTreeMap::new(),
+            timeout_ms: 10_000,
+            compression: Compression::None,
+            batch: BatchConfig::default(),
+        };
+    }
+}
+
+// how logs and spans are queued between exports, metrics only use `scheduled_delay_ms`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    // records arriving while the queue is full are dropped
+    pub max_queue_size: usize,
+    pub max_export_batch_size: usize,
+    // time between exports, metrics are collected and exported on this interval
+    pub scheduled_delay_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        return BatchConfig {
+            max_queue_size: 2048,
+            max_export_batch_size: 512,
+            scheduled_delay_ms: 1000,
+        };
+    }
+}
+
+impl BatchConfig {
+    pub fn scheduled_delay(&self) -> Duration {
+        Duration::from_millis(self.scheduled_delay_ms)
+    }
+}
+
+// exporters for each signal and the Prometheus scrape endpoint, see [telemetry] in config.example.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub logs: ExporterConfig,
+    pub metrics: ExporterConfig,
+    pub traces: ExporterConfig,
+    pub prometheus: PrometheusConfig,
+    // echo the request span's context in a traceresponse header
+    pub trace_response: bool,
+    pub sampler: SamplerConfig,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        let mut metrics = ExporterConfig::default();
+        metrics.batch.scheduled_delay_ms = 60_000;
+        return TelemetryConfig {
+            logs: ExporterConfig::default(),
+            metrics,
+            traces: ExporterConfig::default(),
+            prometheus: PrometheusConfig::default(),
+            trace_response: false,
+            sampler: SamplerConfig::default(),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Signal {
+    Logs,
+    Metrics,
+    Traces,
+}
+
+impl Signal {
+    fn http_path(&self) -> &'static str {
+        match self {
+            Signal::Logs => "/v1/logs",
+            Signal::Metrics => "/v1/metrics",
+            Signal::Traces => "/v1/traces",
+        }
+    }
+}
+
+impl ExporterConfig {
+    fn endpoint(&self, signal: Signal) -> String {
+        return match (&self.endpoint, self.exporter) {
+            (Some(endpoint), ExporterKind::OtlpGrpc) => endpoint.clone(),
+            (None, ExporterKind::OtlpGrpc) => "http://127.0.0.1:4317".to_string(),
+            (endpoint, _) => format!(
+                "{}{}",
+                endpoint
+                    .as_deref()
+                    .unwrap_or("http://127.0.0.1:4318")
+                    .trim_end_matches('/'),
+                signal.http_path()
+            ),
+        };
+    }
+
+    // headers that aren't valid gRPC metadata are left out, like the HTTP exporter does
+    fn metadata(&self) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                MetadataKey::from_bytes(name.to_ascii_lowercase().as_bytes()),
+                MetadataValue::try_from(value.as_str()),
+            ) {
+                metadata.insert(name, value);
+            }
+        }
+        return metadata;
+    }
+
+    fn with_tonic_config<B: WithExportConfig + WithTonicConfig>(
+        &self,
+        builder: B,
+        signal: Signal,
+    ) -> B {
+        let builder = builder
+            .with_endpoint(self.endpoint(signal))
+            .with_timeout(Duration::from_millis(self.timeout_ms))
+            .with_metadata(self.metadata());
+        return match self.compression {
+            Compression::None => builder,
+            Compression::Gzip => builder.with_compression(opentelemetry_otlp::Compression::Gzip),
+        };
+    }
+
+    fn with_http_config<B: WithExportConfig + WithHttpConfig>(
+        &self,
+        builder: B,
+        signal: Signal,
+    ) -> B {
+        return builder
+            .with_endpoint(self.endpoint(signal))
+            .with_timeout(Duration::from_millis(self.timeout_ms))
+            .with_headers(HashMap::from_iter(self.headers.clone()))
+            .with_http_client(OtlpHttpClient::new(self));
+    }
+}
+
+// runs before telemetry is initialized, so failures can only be reported by panicking
+fn exporter_or_panic<E>(exporter: Result<E, ExporterBuildError>, signal: Signal) -> E {
+    return match exporter {
+        Ok(exporter) => exporter,
+        Err(e) => panic!("Couldn't build the OTLP {:?} exporter | {}", signal, e),
+    };
+}
+
+thread_local! {
+    static EXPORT_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/*
+The exporters' connections run on this runtime, while the SDK's batch processors and periodic
+reader block on exports from their own threads. Its threads only ever export.
+*/
+fn export_runtime() -> &'static Runtime {
+    return OTLP_RUNTIME.get_or_init(|| {
+        match runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("otlp-export")
+            .on_thread_start(|| EXPORT_THREAD.with(|export_thread| export_thread.set(true)))
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => panic!("Couldn't start the OTLP export runtime | {}", e),
+        }
+    });
+}
+
+/*
+reqwest, hyper and tonic log while they send an export, bridging those records would queue
+another export for every export. The log bridge leaves out records logged on the export runtime.
+*/
+pub fn is_export_thread() -> bool {
+    return EXPORT_THREAD.with(|export_thread| export_thread.get());
+}
+
+pub fn span_exporter(config: &ExporterConfig) -> SpanExporter {
+    let exporter = match config.exporter {
+        ExporterKind::OtlpGrpc => {
+            let _runtime = export_runtime().enter();
+            config
+                .with_tonic_config(SpanExporter::builder().with_tonic(), Signal::Traces)
+                .build()
+        }
+        _ => config
+            .with_http_config(SpanExporter::builder().with_http(), Signal::Traces)
+            .build(),
+    };
+    return exporter_or_panic(exporter, Signal::Traces);
+}
+
+pub fn metric_exporter(config: &ExporterConfig) -> MetricExporter {
+    let exporter = match config.exporter {
+        ExporterKind::OtlpGrpc => {
+            let _runtime = export_runtime().enter();
+            config
+                .with_tonic_config(MetricExporter::builder().with_tonic(), Signal::Metrics)
+                .build()
+        }
+        _ => config
+            .with_http_config(MetricExporter::builder().with_http(), Signal::Metrics)
+            .build(),
+    };
+    return exporter_or_panic(exporter, Signal::Metrics);
+}
+
+pub fn log_exporter(config: &ExporterConfig) -> LogExporter {
+    let exporter = match config.exporter {
+        ExporterKind::OtlpGrpc => {
+            let _runtime = export_runtime().enter();
+            config
+                .with_tonic_config(LogExporter::builder().with_tonic(), Signal::Logs)
+                .build()
+        }
+        _ => config
+            .with_http_config(LogExporter::builder().with_http(), Signal::Logs)
+            .build(),
+    };
+    return exporter_or_panic(exporter, Signal::Logs);
+}
+
+/*
+Sends the HTTP exporters' requests from the export runtime. Request bodies are gzipped here
+when compression is on, the exporters only compress gRPC requests themselves.
+*/
+struct OtlpHttpClient {
+    client: reqwest::Client,
+    compression: Compression,
+}
+
+impl fmt::Debug for OtlpHttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtlpHttpClient")
+            .field("compression", &self.compression)
+            .finish()
+    }
+}
+
+impl OtlpHttpClient {
+    fn new(config: &ExporterConfig) -> Self {
+        return match reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+        {
+            Ok(client) => OtlpHttpClient {
+                client,
+                compression: config.compression,
+            },
+            Err(e) => panic!("Couldn't build the OTLP HTTP client | {}", e),
+        };
+    }
+}
+
+#[async_trait]
+impl HttpClient for OtlpHttpClient {
+    async fn send_bytes(&self, request: Request<Bytes>) -> Result<Response<Bytes>, HttpError> {
+        let request = match self.compression {
+            Compression::None => request,
+            Compression::Gzip => {
+                let (mut parts, body) = request.into_parts();
+                let mut encoder = GzEncoder::new(Vec::new(), GzipLevel::default());
+                encoder.write_all(&body)?;
+                parts.headers.insert("content-encoding", "gzip".parse()?);
+                Request::from_parts(parts, Bytes::from(encoder.finish()?))
+            }
+        };
+        let client = self.client.clone();
+        return match export_runtime()
+            .spawn(async move { client.send_bytes(request).await })
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => Err(Box::new(e)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        thread,
+    };
+
+    use crossbeam_channel::{Receiver, Sender, unbounded};
+    use flate2::read::GzDecoder;
+    use opentelemetry::trace::{Tracer, TracerProvider};
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+        trace_service_server::{TraceService, TraceServiceServer},
+    };
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use prost::Message;
+    use tonic::{codec::CompressionEncoding, transport::server::TcpIncoming};
+
+    use super::*;
+
+    // ends one span, the simple processor exports it before `end` returns
+    fn export_span(config: &ExporterConfig, name: &'static str) {
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(span_exporter(config))
+            .build();
+        provider.tracer("test").in_span(name, |_| ());
+        provider.shutdown().unwrap();
+    }
+
+    fn span_names(request: &ExportTraceServiceRequest) -> Vec<String> {
+        return request
+            .resource_spans
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .map(|span| span.name.clone())
+            .collect();
+    }
+
+    struct HttpExport {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    // answers every OTLP/HTTP export with an empty success response
+    fn http_collector() -> (String, Receiver<HttpExport>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                loop {
+                    let mut head = String::new();
+                    while !head.ends_with("\r\n\r\n") {
+                        if reader.read_line(&mut head).unwrap() == 0 {
+                            break;
+                        }
+                    }
+                    if head.is_empty() {
+                        break;
+                    }
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .map(|length| length.parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    let mut body = vec![0u8; length];
+                    reader.read_exact(&mut body).unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap();
+                    sender.send(HttpExport { head, body }).unwrap();
+                }
+            }
+        });
+        return (format!("http://{}", address), receiver);
+    }
+
+    struct GrpcCollector {
+        exports: Sender<(MetadataMap, ExportTraceServiceRequest)>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for GrpcCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let metadata = request.metadata().clone();
+            self.exports.send((metadata, request.into_inner())).unwrap();
+            return Ok(tonic::Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }));
+        }
+    }
+
+    fn grpc_collector() -> (
+        String,
+        Receiver<(MetadataMap, ExportTraceServiceRequest)>,
+        Runtime,
+    ) {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let (exports, receiver) = unbounded();
+        let service = TraceServiceServer::new(GrpcCollector { exports })
+            .accept_compressed(CompressionEncoding::Gzip);
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+        return (format!("http://{}", address), receiver, runtime);
+    }
+
+    #[test]
+    fn spans_are_exported_over_http() {
+        let (endpoint, exports) = http_collector();
+        let config = ExporterConfig {
+            exporter: ExporterKind::OtlpHttp,
+            endpoint: Some(format!("{}/collector/", endpoint)),
+            headers: BTreeMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            compression: Compression::Gzip,
+            ..ExporterConfig::default()
+        };
+        export_span(&config, "GET /http");
+
+        let export = exports.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            export
+                .head
+                .starts_with("POST /collector/v1/traces HTTP/1.1\r\n")
+        );
+        let head = export.head.to_lowercase();
+        assert!(head.contains("content-type: application/x-protobuf\r\n"));
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(head.contains("x-api-key: secret\r\n"));
+
+        let mut body = Vec::new();
+        GzDecoder::new(export.body.as_slice())
+            .read_to_end(&mut body)
+            .unwrap();
+        let request = ExportTraceServiceRequest::decode(body.as_slice()).unwrap();
+        assert_eq!(span_names(&request), ["GET /http"]);
+    }
+
+    #[test]
+    fn spans_are_exported_over_grpc() {
+        let (endpoint, exports, _runtime) = grpc_collector();
+        for compression in [Compression::None, Compression::Gzip] {
+            let config = ExporterConfig {
+                exporter: ExporterKind::OtlpGrpc,
+                endpoint: Some(endpoint.clone()),
+                headers: BTreeMap::from([("X-Api-Key".to_string(), "secret".to_string())]),
+                compression,
+                ..ExporterConfig::default()
+            };
+            export_span(&config, "GET /grpc");
+
+            let (metadata, request) = exports.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(metadata.get("x-api-key").unwrap(), "secret");
+            let encoding = metadata
+                .get("grpc-encoding")
+                .map(|encoding| encoding.to_str().unwrap());
+            match compression {
+                Compression::None => assert_eq!(encoding, None),
+                Compression::Gzip => assert_eq!(encoding, Some("gzip")),
+            }
+            assert_eq!(span_names(&request), ["GET /grpc"]);
+        }
+    }
+
+    #[test]
+    fn only_the_export_runtime_is_an_export_thread() {
+        assert!(!is_export_thread());
+        let runtime = export_runtime();
+        assert!(
+            runtime
+                .block_on(runtime.spawn(async { is_export_thread() }))
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Weak},
+};
+
+use log::{error, warn};
+use opentelemetry::{KeyValue, Value};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, Temporality,
+        data::{Gauge, Histogram, Metric, ResourceMetrics, Sum},
+        reader::MetricReader,
+    },
+};
+use serde::Deserialize;
+
+use crate::{
+    http::{Request, Response},
+    router::Handler,
+    statics::PROMETHEUS_READER,
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PrometheusConfig {
+    // serves the metrics in the Prometheus text format at this path, ex: "/metrics"
+    pub path: Option<String>,
+    // only serve them on listeners with `admin = true`, keeps them off the public port
+    pub admin_only: bool,
+}
+
+/*
+The meter provider takes ownership of its readers, so it gets a handle to a shared
+ManualReader and the scrape handler collects through another one in PROMETHEUS_READER.
+Collections are cumulative, which is what Prometheus expects from counters.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusReader {
+    reader: Arc<ManualReader>,
+}
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.reader.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        return self.reader.collect(rm);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        return self.reader.force_flush();
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        return self.reader.shutdown();
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        return self.reader.temporality(kind);
+    }
+}
+
+// scrape handler, registered at `telemetry.prometheus.path`
+pub struct PrometheusMetrics;
+
+impl Handler for PrometheusMetrics {
+    fn handle(&self, _req: &Request) -> Response {
+        let reader = match PROMETHEUS_READER.get() {
+            Some(reader) => reader,
+            None => return Response::text(404, "Resource Not Found"),
+        };
+        let mut metrics = ResourceMetrics {
+            resource: Resource::builder_empty().build(),
+            scope_metrics: Vec::new(),
+        };
+        if let Err(e) = reader.collect(&mut metrics) {
+            error!(error = e.to_string().as_str(); "Couldn't collect metrics for a Prometheus scrape");
+            return Response::text(500, "Internal Server Error");
+        }
+        return Response::new(200).with_body(CONTENT_TYPE, render(&metrics).into_bytes());
+    }
+}
+
+// every series with the same name has to be listed under one HELP and TYPE line
+struct Family {
+    help: String,
+    kind: &'static str,
+    samples: String,
+}
+
+/*
+Renders the text exposition format, ex:
+# HELP total_started_total Total number of requests started
+# TYPE total_started_total counter
+total_started_total{service_name="http_server",otel_scope_name="requests",listener="ipv4"} 3
+Resource attributes are added as labels to every series.
+*/
+pub fn render(metrics: &ResourceMetrics) -> String {
+    let mut resource_labels = metrics
+        .resource
+        .iter()
+        .map(|(key, value)| (label_name(key.as_str()), value.as_str().to_string()))
+        .collect::<Vec<(String, String)>>();
+    // the resource iterates in hash order, sorted so scrapes are stable
+    resource_labels.sort();
+
+    // scopes are in hash order too, the first one keeps a name that another kind of metric reuses
+    let mut scopes = metrics.scope_metrics.iter().collect::<Vec<_>>();
+    scopes.sort_by(|a, b| a.scope.name().cmp(b.scope.name()));
+
+    let mut families: BTreeMap<String, Family> = BTreeMap::new();
+    for scope_metrics in scopes {
+        let mut labels = resource_labels.clone();
+        labels.push((
+            "otel_scope_name".to_string(),
+            scope_metrics.scope.name().to_string(),
+        ));
+        for metric in &scope_metrics.metrics {
+            render_metric(&mut families, &labels, metric);
+        }
+    }
+
+    let mut text = String::new();
+    for (name, family) in families {
+        let _ = writeln!(text, "# HELP {} {}", name, escape_help(&family.help));
+        let _ = writeln!(text, "# TYPE {} {}", name, family.kind);
+        text.push_str(&family.samples);
+    }
+    return text;
+}
+
+fn render_metric(
+    families: &mut BTreeMap<String, Family>,
+    labels: &[(String, String)],
+    metric: &Metric,
+) {
+    let data = metric.data.as_any();
+    let mut samples = String::new();
+    let mut name = metric_name(&metric.name);
+    if let Some(suffix) = unit_suffix(&metric.unit)
+        && !name.ends_with(suffix)
+    {
+        name.push_str(suffix);
+    }
+    let rendered = render_data::<u64>(&mut samples, &name, labels, data)
+        .or_else(|| render_data::<i64>(&mut samples, &name, labels, data))
+        .or_else(|| render_data::<f64>(&mut samples, &name, labels, data));
+    let (name, kind) = match rendered {
+        Some(rendered) => rendered,
+        // exponential histograms have no text format equivalent
+        None => return,
+    };
+
+    let family = families.entry(name).or_insert_with(|| Family {
+        help: metric.description.to_string(),
+        kind,
+        samples: String::new(),
+    });
+    // a series can't change type, Prometheus would reject the whole scrape
+    if family.kind != kind {
+        warn!(metric = metric.name.as_ref(), family = family.kind, kind = kind; "Leaving a metric out of the Prometheus scrape - its name is used by another kind of metric");
+        return;
+    }
+    family.samples.push_str(&samples);
+}
+
+// the number types instruments record
+trait SampleValue: Copy + 'static {
+    fn format(self) -> String;
+}
+
+impl SampleValue for u64 {
+    fn format(self) -> String {
+        self.to_string()
+    }
+}
+
+impl SampleValue for i64 {
+    fn format(self) -> String {
+        self.to_string()
+    }
+}
+
+impl SampleValue for f64 {
+    fn format(self) -> String {
+        format_float(self)
+    }
+}
+
+// the family name and type, None when the aggregation isn't a gauge, sum or histogram of T
+fn render_data<T: SampleValue>(
+    samples: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    data: &dyn Any,
+) -> Option<(String, &'static str)> {
+    if let Some(gauge) = data.downcast_ref::<Gauge<T>>() {
+        for point in &gauge.data_points {
+            write_sample(
+                samples,
+                name,
+                labels,
+                &point.attributes,
+                None,
+                &point.value.format(),
+            );
+        }
+        return Some((name.to_string(), "gauge"));
+    }
+    if let Some(sum) = data.downcast_ref::<Sum<T>>() {
+        // up down counters can go down, so they're gauges
+        let (name, kind) = match sum.is_monotonic {
+            true if name.ends_with("_total") => (name.to_string(), "counter"),
+            true => (format!("{}_total", name), "counter"),
+            false => (name.to_string(), "gauge"),
+        };
+        for point in &sum.data_points {
+            write_sample(
+                samples,
+                &name,
+                labels,
+                &point.attributes,
+                None,
+                &point.value.format(),
+            );
+        }
+        return Some((name, kind));
+    }
+    if let Some(histogram) = data.downcast_ref::<Histogram<T>>() {
+        let bucket_name = format!("{}_bucket", name);
+        for point in &histogram.data_points {
+            // Prometheus buckets count everything at or below their bound
+            let mut cumulative_count = 0;
+            for (bound, count) in point.bounds.iter().zip(&point.bucket_counts) {
+                cumulative_count += count;
+                let le = ("le", format_float(*bound));
+                write_sample(
+                    samples,
+                    &bucket_name,
+                    labels,
+                    &point.attributes,
+                    Some(le),
+                    &cumulative_count.to_string(),
+                );
+            }
+            let le = ("le", "+Inf".to_string());
+            write_sample(
+                samples,
+                &bucket_name,
+                labels,
+                &point.attributes,
+                Some(le),
+                &point.count.to_string(),
+            );
+            write_sample(
+                samples,
+                &format!("{}_sum", name),
+                labels,
+                &point.attributes,
+                None,
+                &point.sum.format(),
+            );
+            write_sample(
+                samples,
+                &format!("{}_count", name),
+                labels,
+                &point.attributes,
+                None,
+                &point.count.to_string(),
+            );
+        }
+        return Some((name.to_string(), "histogram"));
+    }
+    return None;
+}
+
+fn write_sample(
+    samples: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    attributes: &[KeyValue],
+    extra_label: Option<(&str, String)>,
+    value: &str,
+) {
+    let mut pairs = labels.to_vec();
+    for attribute in attributes {
+        pairs.push((
+            label_name(attribute.key.as_str()),
+            attribute_value(&attribute.value),
+        ));
+    }
+    if let Some((key, value)) = extra_label {
+        pairs.push((key.to_string(), value));
+    }
+
+    samples.push_str(name);
+    if !pairs.is_empty() {
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+            .collect::<Vec<String>>();
+        let _ = write!(samples, "{{{}}}", pairs.join(","));
+    }
+    let _ = writeln!(samples, " {}", value);
+}
+
+fn attribute_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.as_str().to_string(),
+        value => value.to_string(),
+    }
+}
+
+// Prometheus names carry the base unit, annotations like "{request}" aren't units
+fn unit_suffix(unit: &str) -> Option<&'static str> {
+    match unit {
+        "s" => Some("_seconds"),
+        "ms" => Some("_milliseconds"),
+        "By" => Some("_bytes"),
+        "1" => Some("_ratio"),
+        _ => None,
+    }
+}
+
+// metric names allow [a-zA-Z0-9_:], ex: "http.server.active_requests" -> "http_server_active_requests"
+fn metric_name(name: &str) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == ':' {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    return sanitized;
+}
+
+// label names are metric names without colons, ex: "service.name" -> "service_name"
+fn label_name(name: &str) -> String {
+    return metric_name(name).replace(':', "_");
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return match value > 0.0 {
+            true => "+Inf".to_string(),
+            false => "-Inf".to_string(),
+        };
+    }
+    return value.to_string();
+}
+
+fn escape_help(help: &str) -> String {
+    return help.replace('\\', "\\\\").replace('\n', "\\n");
+}
+
+fn escape_label_value(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    use super::*;
+
+    #[test]
+    fn metrics_render_in_the_text_format() {
+        let reader = PrometheusReader::default();
+        let resource = Resource::builder_empty()
+            .with_attribute(KeyValue::new("service.name", "http_server"))
+            .build();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .with_resource(resource.clone())
+            .build();
+        let meter = provider.meter("requests");
+        let attributes = [KeyValue::new("path", "C:\\temp \"x\"\nnext")];
+
+        let started = meter
+            .u64_counter("total_started")
+            .with_description("Total number of requests started")
+            .build();
+        started.add(3, &attributes);
+        let active = meter.i64_up_down_counter("active").build();
+        active.add(2, &[]);
+        active.add(-1, &[]);
+        let body_size = meter.u64_gauge("body.size").with_unit("By").build();
+        body_size.record(512, &[]);
+        let duration = meter
+            .f64_histogram("duration")
+            .with_unit("ms")
+            .with_boundaries(vec![10.0, 100.0])
+            .build();
+        for value in [5.0, 50.0, 500.0] {
+            duration.record(value, &[]);
+        }
+        // another scope reusing a name with a different type is left out
+        provider
+            .meter("workers")
+            .u64_gauge("total_started_total")
+            .build()
+            .record(9, &[]);
+
+        let mut metrics = ResourceMetrics {
+            resource,
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics).unwrap();
+        let labels = "service_name=\"http_server\",otel_scope_name=\"requests\"";
+        let expected = [
+            "# HELP active ".to_string(),
+            "# TYPE active gauge".to_string(),
+            format!("active{{{}}} 1", labels),
+            "# HELP body_size_bytes ".to_string(),
+            "# TYPE body_size_bytes gauge".to_string(),
+            format!("body_size_bytes{{{}}} 512", labels),
+            "# HELP duration_milliseconds ".to_string(),
+            "# TYPE duration_milliseconds histogram".to_string(),
+            format!("duration_milliseconds_bucket{{{},le=\"10\"}} 1", labels),
+            format!("duration_milliseconds_bucket{{{},le=\"100\"}} 2", labels),
+            format!("duration_milliseconds_bucket{{{},le=\"+Inf\"}} 3", labels),
+            format!("duration_milliseconds_sum{{{}}} 555", labels),
+            format!("duration_milliseconds_count{{{}}} 3", labels),
+            "# HELP total_started_total Total number of requests started".to_string(),
+            "# TYPE total_started_total counter".to_string(),
+            format!(
+                "total_started_total{{{},path=\"C:\\\\temp \\\"x\\\"\\nnext\"}} 3",
+                labels
+            ),
+        ];
+        assert_eq!(render(&metrics).lines().collect::<Vec<&str>>(), expected);
+    }
+}
@@ -0,0 +1,1028 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::fd::AsFd,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+};
+use opentelemetry::Context;
+use serde::Deserialize;
+
+use crate::{
+    connection::Stream,
+    http::{Body, Chunk, ChunkedReader, Request, Response, read_line, write_chunk},
+    router::Handler,
+    statics::SHUTDOWN_SERVER,
+    telemetry::{force_export_telemetry, inject_context, propagation_fields},
+    upstream::{
+        BalanceStrategy, ConnectionPoolConfig, Framing, HealthCheckConfig, PooledConnection,
+        UpstreamBody, UpstreamLease, UpstreamPool,
+    },
+};
+
+// requests under `prefix` are forwarded to a pool of HTTP/1.1 upstreams
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    // ex: "/api", matches "/api" and everything below it
+    pub prefix: String,
+    // host and port of every upstream, ex: ["127.0.0.1:3000", "127.0.0.1:3001"]
+    pub upstreams: Vec<String>,
+    #[serde(default = "default_balance")]
+    pub balance: BalanceStrategy,
+    // the consistent hash key, the client IP address is used without it
+    pub hash_header: Option<String>,
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub connection_pool: ConnectionPoolConfig,
+    // consecutive failed requests before an upstream is ejected
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_ejection_ms")]
+    pub ejection_ms: u64,
+    // idempotent requests are retried on another upstream,
+    // other requests only when the connection couldn't be opened
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    // forwards "/api/users" as "/users"
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // sends the client Host header instead of the upstream address
+    #[serde(default)]
+    pub preserve_host: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    // applies to every read and write on the upstream connection
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+}
+
+impl ProxyConfig {
+    // a single upstream with every other option at its default
+    pub fn new(prefix: &str, upstream: &str) -> Self {
+        return ProxyConfig {
+            prefix: prefix.to_string(),
+            upstreams: vec![upstream.to_string()],
+            balance: default_balance(),
+            hash_header: None,
+            health_check: None,
+            connection_pool: ConnectionPoolConfig::default(),
+            max_failures: default_max_failures(),
+            ejection_ms: default_ejection_ms(),
+            retries: default_retries(),
+            strip_prefix: false,
+            preserve_host: false,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            read_timeout_ms: default_read_timeout_ms(),
+        };
+    }
+}
+
+fn default_balance() -> BalanceStrategy {
+    BalanceStrategy::RoundRobin
+}
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+fn default_ejection_ms() -> u64 {
+    30_000
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_read_timeout_ms() -> u64 {
+    30_000
+}
+
+// upstream response heads larger than this are rejected
+const MAX_RESPONSE_HEAD_BYTES: usize = 64 * 1024;
+
+// headers that only apply to a single connection and are never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+enum ProxyError {
+    // answered with a 504
+    Timeout(io::Error),
+    // answered with a 502
+    Failed(String),
+    // the client's body couldn't be read, answered with a 400 or a 413 without blaming the upstream
+    Request(io::Error),
+}
+
+impl ProxyError {
+    fn message(&self) -> String {
+        match self {
+            ProxyError::Timeout(e) | ProxyError::Request(e) => e.to_string(),
+            ProxyError::Failed(message) => message.clone(),
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProxyError::Timeout(e),
+            _ => ProxyError::Failed(e.to_string()),
+        }
+    }
+}
+
+pub struct Proxy {
+    config: ProxyConfig,
+    pool: Arc<UpstreamPool>,
+}
+
+impl Proxy {
+    pub fn new(config: ProxyConfig) -> Self {
+        if config.upstreams.is_empty() {
+            error!(prefix = config.prefix.as_str(); "Proxy configured without upstreams");
+            force_export_telemetry(false);
+            panic!("Proxy configured without upstreams | {}", config.prefix);
+        }
+        let pool = Arc::new(UpstreamPool::new(
+            &config.prefix,
+            &config.upstreams,
+            config.balance,
+            config.max_failures.max(1),
+            Duration::from_millis(config.ejection_ms),
+            config.connection_pool.clone(),
+        ));
+        if let Some(health_check) = &config.health_check {
+            pool.spawn_health_checks(health_check.clone());
+        }
+
+        return Proxy { config, pool };
+    }
+
+    // the router pattern covering the prefix, ex: "/api/**"
+    pub fn route_pattern(&self) -> String {
+        format!("{}/**", self.config.prefix.trim_end_matches('/'))
+    }
+
+    fn connect(&self, upstream_address: &str) -> Result<TcpStream, ProxyError> {
+        let connect_timeout = Duration::from_millis(self.config.connect_timeout_ms);
+        let read_timeout = Duration::from_millis(self.config.read_timeout_ms);
+        let addresses = upstream_address.to_socket_addrs()?;
+
+        let mut last_error = ProxyError::Failed("upstream resolved to no addresses".to_string());
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, connect_timeout) {
+                Ok(upstream) => {
+                    upstream.set_read_timeout(Some(read_timeout))?;
+                    upstream.set_write_timeout(Some(read_timeout))?;
+                    upstream.set_nodelay(true)?;
+                    return Ok(upstream);
+                }
+                Err(e) => last_error = ProxyError::from(e),
+            }
+        }
+        return Err(last_error);
+    }
+
+    fn upstream_target(&self, req: &Request) -> String {
+        if !self.config.strip_prefix {
+            return req.target.clone();
+        }
+        let stripped = req
+            .target
+            .strip_prefix(self.config.prefix.trim_end_matches('/'))
+            .unwrap_or(&req.target);
+        match stripped.starts_with('/') {
+            true => stripped.to_string(),
+            false => format!("/{}", stripped),
+        }
+    }
+
+    fn request_head(&self, req: &Request, upstream_address: &str) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\n", req.method, self.upstream_target(req));
+        // the client's trace context is replaced with the handling span's, which continues it
+        let propagation_fields = propagation_fields();
+        for (name, value) in forwarded_headers(&req.headers) {
+            let lowercase_name = name.to_ascii_lowercase();
+            // the server answers `Expect: 100-continue` itself once the body is read
+            if lowercase_name == "host"
+                || lowercase_name == "expect"
+                || lowercase_name.starts_with("x-forwarded-")
+                || propagation_fields.contains(&lowercase_name)
+            {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        for (name, value) in inject_context(&Context::current()) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        let client_host = req.header("host");
+        let host = match (self.config.preserve_host, client_host) {
+            (true, Some(host)) => host,
+            _ => upstream_address,
+        };
+        head.push_str(&format!("Host: {}\r\n", host));
+
+        let client_ip = req.remote_address.map(|address| address.ip().to_string());
+        let forwarded_for = match (req.header("x-forwarded-for"), client_ip) {
+            (Some(previous), Some(client_ip)) => Some(format!("{}, {}", previous, client_ip)),
+            (Some(previous), None) => Some(previous.to_string()),
+            (None, client_ip) => client_ip,
+        };
+        if let Some(forwarded_for) = forwarded_for {
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+        }
+        let proto = if req.secure { "https" } else { "http" };
+        head.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+        if let Some(client_host) = client_host {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", client_host));
+        }
+
+        // without pooling the upstream closes the connection after responding
+        match upgrade_protocol(req) {
+            Some(protocol) => {
+                head.push_str(&format!("Connection: Upgrade\r\nUpgrade: {}\r\n", protocol))
+            }
+            None if !self.pool.keep_alive() => head.push_str("Connection: close\r\n"),
+            None => (),
+        }
+        match req.body.length() {
+            Some(length) => head.push_str(&format!("Content-Length: {}\r\n\r\n", length)),
+            None => head.push_str("Transfer-Encoding: chunked\r\n\r\n"),
+        }
+        return head;
+    }
+
+    fn hash_key(&self, req: &Request) -> String {
+        let header = self
+            .config
+            .hash_header
+            .as_deref()
+            .and_then(|name| req.header(name));
+        match (header, req.remote_address) {
+            (Some(value), _) => value.to_string(),
+            (None, Some(address)) => address.ip().to_string(),
+            (None, None) => req.path.clone(),
+        }
+    }
+
+    // tries upstreams from the pool until one responds or the retries run out
+    fn forward(&self, req: &Request) -> Result<Response, ProxyError> {
+        let is_idempotent = is_idempotent(&req.method);
+        let max_attempts = self.config.retries as usize + 1;
+        let key = self.hash_key(req);
+        let mut tried = Vec::new();
+        let mut last_error = ProxyError::Failed("no available upstreams".to_string());
+
+        while tried.len() < max_attempts {
+            let lease = match self.pool.select(&key, &tried) {
+                Some(lease) => lease,
+                None => break,
+            };
+            tried.push(lease.index);
+            let upstream = lease.upstream.clone();
+
+            let result = match self
+                .pool
+                .checkout(&upstream, || self.connect(&upstream.address))
+            {
+                Ok(connection) => self.exchange(connection, lease, req),
+                Err(e) => Err((e, false)),
+            };
+            match result {
+                Ok(resp) => {
+                    self.pool.report_success(&upstream);
+                    return Ok(resp);
+                }
+                Err((ProxyError::Request(e), _)) => {
+                    warn!(prefix = self.config.prefix.as_str(), path = req.path.as_str(), error = e.to_string().as_str(); "Couldn't read the request body");
+                    return Err(ProxyError::Request(e));
+                }
+                Err((e, request_sent)) => {
+                    warn!(prefix = self.config.prefix.as_str(), upstream = upstream.address.as_str(), path = req.path.as_str(), attempt = tried.len(), error = e.message().as_str(); "Upstream request failed");
+                    self.pool.report_failure(&upstream);
+                    last_error = e;
+                    // a streamed body can't be sent again
+                    if request_sent && (!is_idempotent || req.body.bytes_read() > 0) {
+                        break;
+                    }
+                }
+            }
+        }
+        if tried.is_empty() {
+            warn!(prefix = self.config.prefix.as_str(), path = req.path.as_str(); "No available upstreams");
+        }
+        return Err(last_error);
+    }
+
+    // the error flag is set once the request may have reached the upstream
+    fn exchange(
+        &self,
+        connection: PooledConnection,
+        lease: UpstreamLease,
+        req: &Request,
+    ) -> Result<Response, (ProxyError, bool)> {
+        let sent = self.send_request(&connection.stream, &lease.upstream.address, req);
+        // a pooled connection the upstream closed isn't its failure, the request goes out again
+        // on a new connection if it can be sent again
+        if connection.reused && req.body.bytes_read() == 0 {
+            let is_closed = match &sent {
+                Err(ProxyError::Failed(_)) => true,
+                Err(_) => false,
+                // the request may have arrived before the connection was closed
+                Ok(()) => {
+                    is_idempotent(&req.method)
+                        && !has_response(&connection.stream).map_err(|e| (e.into(), true))?
+                }
+            };
+            if is_closed {
+                info!(prefix = self.config.prefix.as_str(), upstream = lease.upstream.address.as_str(); "Retrying on a new connection - the upstream closed the pooled one");
+                let connection = self
+                    .pool
+                    .open(&lease.upstream, || self.connect(&lease.upstream.address))
+                    .map_err(|e| (e, false))?;
+                return self.exchange(connection, lease, req);
+            }
+        }
+        sent.map_err(|e| (e, true))?;
+        self.read_response(connection, lease, req)
+            .map_err(|e| (e, true))
+    }
+
+    fn send_request(
+        &self,
+        mut connection: &TcpStream,
+        upstream_address: &str,
+        req: &Request,
+    ) -> Result<(), ProxyError> {
+        connection.write_all(self.request_head(req, upstream_address).as_bytes())?;
+
+        // copied as it's read from the client, chunked bodies are sent chunked with their trailers
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let read_size = match (&req.body).read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_size) => read_size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ProxyError::Request(e)),
+            };
+            match req.body.is_chunked() {
+                true => write_chunk(&mut connection, &buf[..read_size])?,
+                false => connection.write_all(&buf[..read_size])?,
+            }
+        }
+        if req.body.is_chunked() {
+            let mut end = "0\r\n".to_string();
+            for (name, value) in forwarded_headers(&req.body.trailers()) {
+                end.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            end.push_str("\r\n");
+            connection.write_all(end.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    fn read_response(
+        &self,
+        connection: PooledConnection,
+        lease: UpstreamLease,
+        req: &Request,
+    ) -> Result<Response, ProxyError> {
+        let PooledConnection {
+            stream, created, ..
+        } = connection;
+        let mut reader = BufReader::new(stream);
+        let head = loop {
+            let head = read_response_head(&mut reader)?;
+            // interim responses like 100 Continue are dropped
+            if !(100..200).contains(&head.status) || head.status == 101 {
+                break head;
+            }
+        };
+        let keep_alive = self.pool.keep_alive() && head.keep_alive;
+
+        if head.status == 101 {
+            let protocol = match upgrade_protocol(req) {
+                Some(protocol) => protocol.to_string(),
+                None => {
+                    return Err(ProxyError::Failed(
+                        "upgraded a request that didn't ask for it".to_string(),
+                    ));
+                }
+            };
+            let mut resp = Response::new(101);
+            resp.headers = forwarded_headers(&head.headers);
+            resp.set_header("Connection", "Upgrade");
+            resp.set_header("Upgrade", &protocol);
+            // the lease keeps the tunnel counted as in flight on the upstream
+            resp.upgrade = Some(Box::new(move |client, received| {
+                tunnel(client, received, reader);
+                drop(lease);
+            }));
+            return Ok(resp);
+        }
+
+        let has_no_body = req.method == "HEAD" || head.status == 204 || head.status == 304;
+        let is_chunked = header_value(&head.headers, "transfer-encoding")
+            .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+        let content_length = header_value(&head.headers, "content-length")
+            .and_then(|length| length.trim().parse::<u64>().ok());
+        let (framing, length) = match (has_no_body, is_chunked, content_length) {
+            (true, _, _) => {
+                if keep_alive && reader.buffer().is_empty() {
+                    let connection = PooledConnection {
+                        stream: reader.into_inner(),
+                        created,
+                        reused: true,
+                    };
+                    self.pool.release(&lease.upstream, connection);
+                }
+                let mut resp = Response::new(head.status);
+                resp.headers = forwarded_headers(&head.headers);
+                return Ok(resp);
+            }
+            (false, true, _) => (Framing::Chunked(ChunkedReader::new(reader)), None),
+            (false, false, Some(length)) => (Framing::Length(reader.take(length)), Some(length)),
+            (false, false, None) => (Framing::Close(reader), None),
+        };
+
+        let body = UpstreamBody::new(framing, created, keep_alive, self.pool.clone(), lease);
+        let mut resp = Response::new(head.status);
+        resp.headers = forwarded_headers(&head.headers);
+        resp.body = match is_chunked {
+            true => Body::Chunked(Box::new(relay_chunks(body))),
+            false => Body::Stream {
+                reader: Box::new(body),
+                length,
+            },
+        };
+        return Ok(resp);
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, req: &Request) -> Response {
+        match self.forward(req) {
+            Ok(resp) => resp,
+            // every attempt is logged by `forward`
+            Err(ProxyError::Timeout(_)) => Response::text(504, "Gateway Timeout"),
+            Err(ProxyError::Failed(_)) => Response::text(502, "Bad Gateway"),
+            Err(ProxyError::Request(_)) if req.body.is_too_large() => {
+                Response::text(413, "Content Too Large")
+            }
+            Err(ProxyError::Request(_)) => Response::text(400, "Bad Request"),
+        }
+    }
+}
+
+// passes a chunked upstream body on chunk by chunk, so its trailers can be forwarded too
+fn relay_chunks(mut body: UpstreamBody) -> impl Iterator<Item = io::Result<Chunk>> {
+    let mut finished = false;
+    return std::iter::from_fn(move || {
+        if finished {
+            return None;
+        }
+        let mut buf = vec![0u8; 16 * 1024];
+        match body.read(&mut buf) {
+            Ok(0) => {
+                finished = true;
+                let trailers = forwarded_headers(body.trailers());
+                (!trailers.is_empty()).then_some(Ok(Chunk::Trailers(trailers)))
+            }
+            Ok(read_size) => {
+                buf.truncate(read_size);
+                Some(Ok(Chunk::Data(buf)))
+            }
+            Err(e) => {
+                finished = true;
+                Some(Err(e))
+            }
+        }
+    });
+}
+
+fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"].contains(&method)
+}
+
+// waits for the first byte of the response without reading it, false if the connection was closed instead
+fn has_response(stream: &TcpStream) -> io::Result<bool> {
+    let mut buf = [0u8; 1];
+    match stream.peek(&mut buf) {
+        Ok(0) => Ok(false),
+        Ok(_) => Ok(true),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// ex: "websocket" for a request with `Connection: Upgrade` and `Upgrade: websocket`
+fn upgrade_protocol(req: &Request) -> Option<&str> {
+    let connection = req.header("connection")?;
+    let wants_upgrade = connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    match wants_upgrade {
+        true => req.header("upgrade"),
+        false => None,
+    }
+}
+
+// copies bytes both ways until either side closes the connection or the server shuts down
+fn tunnel(mut client: Stream, received: Vec<u8>, upstream: BufReader<TcpStream>) {
+    // bytes the upstream sent right after its 101 response
+    let leftover = upstream.buffer().to_vec();
+    let mut upstream = upstream.into_inner();
+    if client.write_all(&leftover).is_err() {
+        return;
+    }
+    // and the ones the client sent right after its request
+    if upstream.write_all(&received).is_err() {
+        return;
+    }
+    let poll_timeout = PollTimeout::try_from(Duration::from_secs(1)).unwrap_or(PollTimeout::MAX);
+    let mut buf = [0u8; 16 * 1024];
+
+    loop {
+        if let Ok(flag) = SHUTDOWN_SERVER.read()
+            && *flag
+        {
+            break;
+        }
+
+        let (client_ready, upstream_ready) = if client.has_buffered_data() {
+            (true, false)
+        } else {
+            let mut poll_targets = [
+                PollFd::new(client.as_fd(), PollFlags::POLLIN),
+                PollFd::new(upstream.as_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut poll_targets, poll_timeout) {
+                Ok(_) => (),
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            }
+            // hang ups and errors are found by the read
+            let is_ready =
+                |target: &PollFd| target.revents().is_some_and(|events| !events.is_empty());
+            (is_ready(&poll_targets[0]), is_ready(&poll_targets[1]))
+        };
+
+        if client_ready {
+            match client.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_size) => {
+                    if upstream.write_all(&buf[..read_size]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(_) => break,
+            }
+        }
+        if upstream_ready {
+            match upstream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_size) => {
+                    if client.write_all(&buf[..read_size]).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(_) => break,
+            }
+        }
+    }
+    client.close();
+}
+
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+    // the upstream leaves the connection open after the response
+    keep_alive: bool,
+}
+
+fn read_response_head(reader: &mut BufReader<TcpStream>) -> Result<ResponseHead, ProxyError> {
+    let status_line = read_line(reader)?;
+    let mut head_size = status_line.len();
+    let (is_http_1_1, status) = match status_line.split(' ').collect::<Vec<&str>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => match status.parse::<u16>() {
+            Ok(status) if (100..600).contains(&status) => (version == "HTTP/1.1", status),
+            _ => {
+                return Err(ProxyError::Failed(format!(
+                    "invalid status line | {}",
+                    status_line
+                )));
+            }
+        },
+        _ => {
+            return Err(ProxyError::Failed(format!(
+                "invalid status line | {}",
+                status_line
+            )));
+        }
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        head_size += line.len();
+        if head_size > MAX_RESPONSE_HEAD_BYTES {
+            return Err(ProxyError::Failed("response head too large".to_string()));
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            _ => {
+                return Err(ProxyError::Failed(format!(
+                    "invalid header line | {}",
+                    line
+                )));
+            }
+        }
+    }
+
+    // HTTP/1.1 connections are persistent unless closed, HTTP/1.0 ones the other way around
+    let connection = header_value(&headers, "connection")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let keep_alive = match is_http_1_1 {
+        true => !connection.contains("close"),
+        false => connection.contains("keep-alive"),
+    };
+    return Ok(ResponseHead {
+        status,
+        headers,
+        keep_alive,
+    });
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// drops hop-by-hop headers, including the ones listed in the Connection header
+fn forwarded_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let connection_headers = header_value(headers, "connection")
+        .map(|value| {
+            value
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !connection_headers.contains(&name)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, os::unix::net::UnixStream, str, thread};
+
+    use super::*;
+    use crate::{
+        connection::Socket,
+        http::{RequestBody, RequestHead, find_head_end},
+    };
+
+    // answers one connection with `response` and returns the request it received
+    fn stub_upstream(response: &'static [u8]) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !is_complete_request(&received) {
+                match conn.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read_size) => received.extend_from_slice(&buf[..read_size]),
+                }
+            }
+            let _ = conn.write_all(response);
+            return String::from_utf8_lossy(&received).to_string();
+        });
+        return (address, handle);
+    }
+
+    fn is_complete_request(received: &[u8]) -> bool {
+        let head_end = match find_head_end(received) {
+            Some(head_end) => head_end,
+            None => return false,
+        };
+        let head = String::from_utf8_lossy(&received[..head_end]).to_ascii_lowercase();
+        // the test bodies don't end their chunks with a blank line
+        if head.contains("transfer-encoding: chunked") {
+            return received.ends_with(b"\r\n\r\n") && received.len() > head_end;
+        }
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        return received.len() >= head_end + length;
+    }
+
+    fn test_proxy(prefix: &str, upstream: &str) -> Proxy {
+        let mut config = ProxyConfig::new(prefix, upstream);
+        config.connection_pool.max_idle = 0;
+        config.retries = 0;
+        config.read_timeout_ms = 300;
+        return Proxy::new(config);
+    }
+
+    fn test_request(target: &str, headers: &[(&str, &str)], body: RequestBody) -> Request {
+        let head = RequestHead {
+            method: "POST".to_string(),
+            target: target.to_string(),
+            version: "1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        };
+        let mut req = head.into_request(body);
+        req.remote_address = Some("192.0.2.7:50000".parse().unwrap());
+        return req;
+    }
+
+    fn header_lines(message: &str) -> Vec<String> {
+        return message
+            .split("\r\n\r\n")
+            .next()
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .map(|line| line.to_ascii_lowercase())
+            .collect();
+    }
+
+    fn write_response(mut resp: Response) -> String {
+        let mut written = Vec::new();
+        resp.write_to(&mut written).unwrap();
+        return String::from_utf8_lossy(&written).to_string();
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_stripped_both_ways() {
+        let (address, upstream) = stub_upstream(
+            b"HTTP/1.1 200 OK\r\nConnection: close, X-Upstream-Hop\r\nX-Upstream-Hop: 1\r\nKeep-Alive: timeout=5\r\nX-Kept: yes\r\nContent-Length: 2\r\n\r\nok",
+        );
+        let proxy = test_proxy("/api", &address);
+        let req = test_request(
+            "/api/items",
+            &[
+                ("Host", "example.test"),
+                ("Connection", "keep-alive, X-Client-Hop"),
+                ("X-Client-Hop", "1"),
+                ("Keep-Alive", "timeout=5"),
+                ("TE", "trailers"),
+                ("Upgrade", "h2c"),
+                ("X-Kept", "yes"),
+            ],
+            RequestBody::from_bytes(Vec::new()),
+        );
+
+        let resp = write_response(proxy.handle(&req));
+        let sent = header_lines(&upstream.join().unwrap());
+        for removed in [
+            "x-client-hop",
+            "keep-alive",
+            "te:",
+            "upgrade",
+            "connection: keep-alive",
+        ] {
+            assert!(
+                !sent.iter().any(|line| line.starts_with(removed)),
+                "{} was forwarded",
+                removed
+            );
+        }
+        assert!(sent.contains(&"x-kept: yes".to_string()));
+        assert!(sent.contains(&format!("host: {}", address)));
+
+        let received = header_lines(&resp);
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        assert!(resp.ends_with("\r\n\r\nok"));
+        assert!(received.contains(&"x-kept: yes".to_string()));
+        assert!(
+            !received
+                .iter()
+                .any(|line| line.starts_with("x-upstream-hop"))
+        );
+        assert!(!received.iter().any(|line| line.starts_with("keep-alive")));
+    }
+
+    #[test]
+    fn forwarded_headers_describe_the_client() {
+        let (address, upstream) = stub_upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = test_proxy("/api", &address);
+        let req = test_request(
+            "/api/items",
+            &[
+                ("Host", "example.test"),
+                ("X-Forwarded-For", "198.51.100.1"),
+                ("X-Forwarded-Proto", "https"),
+                ("X-Forwarded-Host", "spoofed.test"),
+            ],
+            RequestBody::from_bytes(Vec::new()),
+        );
+
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.status, 204);
+        let sent = header_lines(&upstream.join().unwrap());
+        assert!(sent.contains(&"x-forwarded-for: 198.51.100.1, 192.0.2.7".to_string()));
+        assert!(sent.contains(&"x-forwarded-proto: http".to_string()));
+        assert!(sent.contains(&"x-forwarded-host: example.test".to_string()));
+        assert!(!sent.iter().any(|line| line.contains("spoofed.test")));
+    }
+
+    #[test]
+    fn strip_prefix_rewrites_the_target() {
+        let mut config = ProxyConfig::new("/api/", "127.0.0.1:1");
+        config.strip_prefix = true;
+        let proxy = Proxy::new(config);
+        let target = |target: &str| {
+            let req = test_request(target, &[], RequestBody::from_bytes(Vec::new()));
+            proxy.upstream_target(&req)
+        };
+        assert_eq!(target("/api/users?page=2"), "/users?page=2");
+        assert_eq!(target("/api"), "/");
+        assert_eq!(target("/api?page=2"), "/?page=2");
+    }
+
+    #[test]
+    fn request_bodies_are_streamed() {
+        let (address, upstream) = stub_upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = test_proxy("/api", &address);
+        let req = test_request(
+            "/api/upload",
+            &[("Content-Length", "5")],
+            RequestBody::from_bytes(b"hello".to_vec()),
+        );
+        assert_eq!(proxy.handle(&req).status, 204);
+        let sent = upstream.join().unwrap();
+        assert!(sent.contains("Content-Length: 5\r\n\r\nhello"));
+    }
+
+    // a chunked request whose body the client has already sent
+    fn chunked_request(chunks: &[u8], max_body_bytes: usize) -> (Request, UnixStream) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(chunks).unwrap();
+        let head = RequestHead {
+            method: "POST".to_string(),
+            target: "/api/upload".to_string(),
+            version: "1.1".to_string(),
+            headers: vec![("Transfer-Encoding".to_string(), "chunked".to_string())],
+        };
+        let stream = Stream::Plain(Socket::new(server.into(), Duration::from_secs(1)));
+        let body = RequestBody::new(stream, Vec::new(), &head, max_body_bytes);
+        return (head.into_request(body), client);
+    }
+
+    #[test]
+    fn chunked_request_bodies_are_forwarded_chunked() {
+        let chunks = b"5\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        let (req, _client) = chunked_request(chunks, 1024);
+        let (address, upstream) = stub_upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = test_proxy("/api", &address);
+        assert_eq!(proxy.handle(&req).status, 204);
+        let sent = upstream.join().unwrap();
+        assert!(sent.contains("Transfer-Encoding: chunked\r\n\r\n"));
+        assert!(sent.ends_with(str::from_utf8(chunks).unwrap()));
+        assert!(!sent.contains("Content-Length"));
+    }
+
+    #[test]
+    fn oversized_chunked_bodies_are_rejected() {
+        let (req, _client) = chunked_request(b"a\r\n0123456789\r\n0\r\n\r\n", 4);
+        let (address, _upstream) = stub_upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = test_proxy("/api", &address);
+        assert_eq!(proxy.handle(&req).status, 413);
+    }
+
+    #[test]
+    fn unreachable_upstreams_are_a_bad_gateway() {
+        // bound then closed, so connecting is refused
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let proxy = test_proxy("/api", &address);
+        let req = test_request("/api/items", &[], RequestBody::from_bytes(Vec::new()));
+        assert_eq!(proxy.handle(&req).status, 502);
+
+        let (address, upstream) = stub_upstream(b"not http\r\n\r\n");
+        let proxy = test_proxy("/api", &address);
+        assert_eq!(proxy.handle(&req).status, 502);
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn slow_upstreams_are_a_gateway_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = test_proxy("/api", &listener.local_addr().unwrap().to_string());
+        // accepted by the backlog but never answered
+        let req = test_request("/api/items", &[], RequestBody::from_bytes(Vec::new()));
+        assert_eq!(proxy.handle(&req).status, 504);
+        drop(listener);
+    }
+
+    // answers `answered[n]` requests on its nth connection, then closes it after reading one more
+    fn keep_alive_upstream(answered: Vec<usize>) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            for count in answered {
+                let (mut conn, _) = listener.accept().unwrap();
+                conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                for answer in 0..=count {
+                    let mut received = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    while !is_complete_request(&received) {
+                        match conn.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(read_size) => received.extend_from_slice(&buf[..read_size]),
+                        }
+                    }
+                    if answer < count {
+                        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .unwrap();
+                    }
+                }
+            }
+        });
+        return (address, handle);
+    }
+
+    #[test]
+    fn closed_pooled_connections_are_replaced() {
+        let (address, upstream) = keep_alive_upstream(vec![1, 2]);
+        let mut config = ProxyConfig::new("/api", &address);
+        config.retries = 0;
+        // a failure counted against the only upstream would eject it
+        config.max_failures = 1;
+        let proxy = Proxy::new(config);
+        let get = || {
+            let head = RequestHead {
+                method: "GET".to_string(),
+                target: "/api/items".to_string(),
+                version: "1.1".to_string(),
+                headers: Vec::new(),
+            };
+            let req = head.into_request(RequestBody::from_bytes(Vec::new()));
+            return write_response(proxy.handle(&req));
+        };
+
+        // the response is read to the end, so the connection goes back to the pool
+        assert!(get().ends_with("\r\n\r\nok"));
+        // the upstream closes the pooled connection instead of answering
+        assert!(get().ends_with("\r\n\r\nok"));
+        assert!(get().ends_with("\r\n\r\nok"));
+        drop(proxy);
+        upstream.join().unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use hmac::{Hmac, KeyInit, Mac};
+use log::warn;
+use serde::Deserialize;
+use sha2::Sha256;
+
+pub const REDACTION_KEY_ENV: &str = "HTTP_SERVER_REDACTION_KEY";
+
+// credentials that are never logged as is
+const DEFAULT_HEADERS: [&str; 7] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+    "x-csrf-token",
+];
+const DEFAULT_QUERY_PARAMETERS: [&str; 8] = [
+    "access_token",
+    "api_key",
+    "apikey",
+    "code",
+    "password",
+    "secret",
+    "signature",
+    "token",
+];
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionMode {
+    // values are replaced with [REDACTED]
+    Redact,
+    // values are replaced with a keyed hash, requests with the same token can still be matched up
+    // without the hashes being reversible by guessing, needs `hash_key`
+    Hash,
+}
+
+// see [redaction] in config.example.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub mode: RedactionMode,
+    // redacted on top of the defaults, names are case insensitive
+    pub headers: Vec<String>,
+    pub query_parameters: Vec<String>,
+    // false only redacts the lists above
+    pub include_defaults: bool,
+    // the HMAC key for hash mode, falls back to the HTTP_SERVER_REDACTION_KEY environment variable
+    pub hash_key: Option<String>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        return RedactionConfig {
+            mode: RedactionMode::Redact,
+            headers: Vec::new(),
+            query_parameters: Vec::new(),
+            include_defaults: true,
+            hash_key: None,
+        };
+    }
+}
+
+/*
+Applied to request data before it's logged or attached to spans, ex:
+"/login?user=ann&token=abc" -> "/login?user=ann&token=[REDACTED]"
+*/
+pub struct Redactor {
+    // None redacts values, Some hashes them with the key
+    hash: Option<Hmac<Sha256>>,
+    // lowercase names
+    headers: Vec<String>,
+    query_parameters: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(config: &RedactionConfig) -> Self {
+        let mut headers = config
+            .headers
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        let mut query_parameters = config
+            .query_parameters
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect::<Vec<String>>();
+        if config.include_defaults {
+            headers.extend(DEFAULT_HEADERS.iter().map(|name| name.to_string()));
+            query_parameters.extend(DEFAULT_QUERY_PARAMETERS.iter().map(|name| name.to_string()));
+        }
+        let hash = match (config.mode, config.hash_key.as_deref()) {
+            (RedactionMode::Redact, _) => None,
+            (RedactionMode::Hash, Some(key)) if !key.is_empty() => {
+                Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()
+            }
+            // an unkeyed hash of a short token can be reversed by trying every value
+            (RedactionMode::Hash, _) => {
+                warn!("Redaction hash mode needs a hash_key, values are redacted instead");
+                None
+            }
+        };
+        return Redactor {
+            hash,
+            headers,
+            query_parameters,
+        };
+    }
+
+    pub fn header(&self, name: &str, value: &str) -> String {
+        match self.headers.contains(&name.to_ascii_lowercase()) {
+            true => self.replacement(value),
+            false => value.to_string(),
+        }
+    }
+
+    // the query string without its leading ?, parameter order and names are kept
+    pub fn query(&self, query: &str) -> String {
+        return query
+            .split('&')
+            .map(|parameter| match parameter.split_once('=') {
+                Some((name, value)) if self.is_denied_parameter(name) => {
+                    format!("{}={}", name, self.replacement(value))
+                }
+                _ => parameter.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join("&");
+    }
+
+    // a request target or URL, ex: the Referer header
+    pub fn url(&self, url: &str) -> String {
+        match url.split_once('?') {
+            Some((path, query)) => format!("{}?{}", path, self.query(query)),
+            None => url.to_string(),
+        }
+    }
+
+    // names are compared decoded, ex: "%74oken" is "token"
+    fn is_denied_parameter(&self, name: &str) -> bool {
+        let name = percent_decode(name).to_ascii_lowercase();
+        return self.query_parameters.contains(&name);
+    }
+
+    fn replacement(&self, value: &str) -> String {
+        match &self.hash {
+            None => REDACTED.to_string(),
+            Some(hash) => {
+                let mut hash = hash.clone();
+                hash.update(value.as_bytes());
+                let digest = hash
+                    .finalize()
+                    .into_bytes()
+                    .iter()
+                    .take(16)
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                format!("hmac:{}", digest)
+            }
+        }
+    }
+}
+
+// invalid escapes are kept as they are, `+` is a space in form encoded names
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match bytes[index] {
+            b'%' if index + 2 < bytes.len() => std::str::from_utf8(&bytes[index + 1..index + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match (escaped, bytes[index]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (None, b'+') => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            (None, byte) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(mode: RedactionMode, hash_key: Option<&str>) -> Redactor {
+        return Redactor::new(&RedactionConfig {
+            mode,
+            hash_key: hash_key.map(|key| key.to_string()),
+            ..RedactionConfig::default()
+        });
+    }
+
+    #[test]
+    fn query_parameters_are_redacted_by_decoded_name() {
+        let redactor = redactor(RedactionMode::Redact, None);
+        assert_eq!(
+            redactor.url("/login?user=ann&token=abc"),
+            "/login?user=ann&token=[REDACTED]"
+        );
+        assert_eq!(
+            redactor.query("%74oken=abc&API%5fKEY=def&pass%77ord=ghi&page=2"),
+            "%74oken=[REDACTED]&API%5fKEY=[REDACTED]&pass%77ord=[REDACTED]&page=2"
+        );
+        // broken escapes don't hide the rest of the query
+        assert_eq!(
+            redactor.query("a%=1&%zz=2&secret=3"),
+            "a%=1&%zz=2&secret=[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn hash_mode_uses_the_key() {
+        let first = redactor(RedactionMode::Hash, Some("first key"));
+        let second = redactor(RedactionMode::Hash, Some("second key"));
+
+        let hashed = first.header("Authorization", "Bearer abc");
+        assert!(hashed.starts_with("hmac:"));
+        assert_eq!(hashed.len(), "hmac:".len() + 32);
+        assert_eq!(hashed, first.header("authorization", "Bearer abc"));
+        assert_ne!(hashed, first.header("authorization", "Bearer abd"));
+        assert_ne!(hashed, second.header("authorization", "Bearer abc"));
+        assert_eq!(first.header("Accept", "text/html"), "text/html");
+    }
+
+    #[test]
+    fn hash_mode_without_a_key_redacts() {
+        for key in [None, Some("")] {
+            let redactor = redactor(RedactionMode::Hash, key);
+            assert_eq!(redactor.header("Cookie", "session=abc"), REDACTED);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use serde::Deserialize;
+
+use crate::http::{Request, Response};
+
+// listeners with a redirect answer every request with a redirect to the HTTPS origin
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedirectConfig {
+    // 301 or 308, 308 keeps the request method
+    #[serde(default = "default_status")]
+    pub status: u16,
+    // ex: "https://example.com", without it the request Host header is used
+    pub origin: Option<String>,
+    // port used with the Host header, omitted from the location when 443
+    #[serde(default = "default_https_port")]
+    pub https_port: u16,
+}
+
+fn default_status() -> u16 {
+    308
+}
+
+fn default_https_port() -> u16 {
+    443
+}
+
+pub fn build_redirect_response(req: &Request, config: &RedirectConfig) -> Response {
+    let origin = match (&config.origin, req.header("host")) {
+        (Some(origin), _) => origin.trim_end_matches('/').to_string(),
+        (None, Some(host)) => {
+            let hostname = strip_port(host);
+            match config.https_port {
+                443 => format!("https://{}", hostname),
+                port => format!("https://{}:{}", hostname, port),
+            }
+        }
+        (None, None) => return Response::text(400, "Bad Request"),
+    };
+
+    // path and query are kept as sent, ex: "/assets/index.js?v=2"
+    let location = format!("{}{}", origin, req.target);
+    return Response::new(config.status).with_header("Location", &location);
+}
+
+// the Host header without its port
+pub fn strip_port(host: &str) -> &str {
+    // IPv6 hosts are bracketed, ex: "[::1]:80"
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    match host.rsplit_once(':') {
+        Some((hostname, _port)) => hostname,
+        None => host,
+    }
+}
@@ -0,0 +1,436 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::error;
+
+use crate::{
+    http::{MatchedRoute, Request, Response},
+    telemetry::force_export_telemetry,
+};
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: &Request) -> Response;
+}
+
+// closures can be registered directly, ex: router.route("GET", "/ping", |_req: &Request| ...)
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, req: &Request) -> Response {
+        self(req)
+    }
+}
+
+/*
+Pattern segments:
+- `about` matches exactly
+- `:name` matches one segment, captured as the `name` param
+- `*` matches one segment without capturing it
+- `**` or `**name` matches the rest of the path including nothing, must be the last segment,
+  `**name` captures the rest as the `name` param
+*/
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+    Rest(Option<String>),
+}
+
+pub struct Route {
+    // None matches every method
+    method: Option<String>,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+    // None names the request span `{method} {pattern}`
+    span_name: Option<String>,
+}
+
+impl Route {
+    // the request span is renamed to this once the route is matched
+    pub fn span_name(&mut self, name: &str) -> &mut Self {
+        self.span_name = Some(name.to_string());
+        return self;
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        match &self.method {
+            Some(route_method) => route_method == method,
+            None => true,
+        }
+    }
+
+    fn match_path(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let path_segments = split_path(path);
+        let mut path_index = 0;
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest(name) => {
+                    if let Some(name) = name {
+                        params.insert(name.clone(), path_segments[path_index..].join("/"));
+                    }
+                    return Some(params);
+                }
+                _ if path_index >= path_segments.len() => return None,
+                Segment::Literal(literal) if literal != path_segments[path_index] => return None,
+                Segment::Literal(_) | Segment::Wildcard => (),
+                Segment::Param(name) => {
+                    params.insert(name.clone(), path_segments[path_index].to_string());
+                }
+            }
+            path_index += 1;
+        }
+
+        if path_index != path_segments.len() {
+            return None;
+        }
+        return Some(params);
+    }
+
+    // literal segments beat params, params beat a trailing rest segment
+    fn specificity(&self) -> (usize, bool, usize) {
+        let literals = self
+            .segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count();
+        let ends_with_rest = matches!(self.segments.last(), Some(Segment::Rest(_)));
+        return (literals, !ends_with_rest, self.segments.len());
+    }
+
+    // ex: the static files fallback "/**path", registered for GET but there for every path
+    fn matches_any_path(&self) -> bool {
+        matches!(self.segments[..], [Segment::Rest(_)])
+    }
+
+    // params and wildcards match the same paths regardless of their names
+    fn shape(&self) -> Vec<&str> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::Param(_) | Segment::Wildcard => "*",
+                Segment::Rest(_) => "**",
+            })
+            .collect()
+    }
+}
+
+pub enum RouteMatch<'a> {
+    Found(&'a Route, HashMap<String, String>),
+    // the path exists but not for this method, holds the allowed methods
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        return Router { routes: Vec::new() };
+    }
+
+    // method "*" matches every method
+    pub fn route(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: impl Handler + 'static,
+    ) -> &mut Route {
+        self.route_arc(method, pattern, Arc::new(handler))
+    }
+
+    pub fn route_arc(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: Arc<dyn Handler>,
+    ) -> &mut Route {
+        let segments = match parse_pattern(pattern) {
+            Ok(segments) => segments,
+            Err(e) => {
+                error!(pattern = pattern, error = e.as_str(); "Invalid route pattern");
+                force_export_telemetry(false);
+                panic!("Invalid route pattern {} | {}", pattern, e);
+            }
+        };
+        let method = match method {
+            "*" => None,
+            method => Some(method.to_ascii_uppercase()),
+        };
+
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments,
+            handler,
+            span_name: None,
+        });
+        let last = self.routes.len() - 1;
+        return &mut self.routes[last];
+    }
+
+    // two routes conflict when they match the same paths for an overlapping method
+    pub fn check_conflicts(&self) -> Result<(), String> {
+        for (index, route) in self.routes.iter().enumerate() {
+            for other in &self.routes[index + 1..] {
+                let methods_overlap = match (&route.method, &other.method) {
+                    (Some(method), Some(other_method)) => method == other_method,
+                    _ => true,
+                };
+                if methods_overlap && route.shape() == other.shape() {
+                    return Err(format!(
+                        "{} {} conflicts with {} {}",
+                        route.method.as_deref().unwrap_or("*"),
+                        route.pattern,
+                        other.method.as_deref().unwrap_or("*"),
+                        other.pattern
+                    ));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn find(&self, method: &str, path: &str) -> RouteMatch<'_> {
+        let mut best: Option<(&Route, HashMap<String, String>)> = None;
+        let mut allowed_methods = Vec::new();
+
+        for route in &self.routes {
+            let params = match route.match_path(path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if !route.matches_method(method) {
+                // a catch-all would turn every unknown path into a 405 for the other methods
+                if let Some(route_method) = &route.method
+                    && !route.matches_any_path()
+                {
+                    allowed_methods.push(route_method.clone());
+                }
+                continue;
+            }
+            let is_better = match &best {
+                Some((best_route, _)) => route.specificity() > best_route.specificity(),
+                None => true,
+            };
+            if is_better {
+                best = Some((route, params));
+            }
+        }
+
+        return match best {
+            Some((route, params)) => RouteMatch::Found(route, params),
+            None if !allowed_methods.is_empty() => {
+                allowed_methods.sort();
+                allowed_methods.dedup();
+                RouteMatch::MethodNotAllowed(allowed_methods)
+            }
+            None => RouteMatch::NotFound,
+        };
+    }
+
+    // records the matched route on the request for spans and metrics
+    pub fn handle(&self, req: &mut Request) -> Response {
+        match self.find(&req.method, &req.path) {
+            RouteMatch::Found(route, params) => {
+                req.params = params;
+                req.route = Some(MatchedRoute {
+                    pattern: route.pattern.clone(),
+                    span_name: route.span_name.clone(),
+                });
+                route.handler.handle(req)
+            }
+            RouteMatch::MethodNotAllowed(allowed_methods) => {
+                Response::text(405, "Method Not Allowed")
+                    .with_header("Allow", &allowed_methods.join(", "))
+            }
+            RouteMatch::NotFound => Response::text(404, "Resource Not Found"),
+        }
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, String> {
+    if !pattern.starts_with('/') {
+        return Err("patterns must start with /".to_string());
+    }
+    let parts = split_path(pattern);
+    let mut segments = Vec::new();
+
+    for (index, part) in parts.iter().enumerate() {
+        let segment = if let Some(name) = part.strip_prefix("**") {
+            if index != parts.len() - 1 {
+                return Err("** must be the last segment".to_string());
+            }
+            Segment::Rest((!name.is_empty()).then(|| name.to_string()))
+        } else if *part == "*" {
+            Segment::Wildcard
+        } else if let Some(name) = part.strip_prefix(':') {
+            if name.is_empty() {
+                return Err("params need a name, ex: /users/:id".to_string());
+            }
+            Segment::Param(name.to_string())
+        } else {
+            Segment::Literal(part.to_string())
+        };
+        segments.push(segment);
+    }
+
+    return Ok(segments);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{RequestBody, RequestHead};
+
+    fn request(method: &str, target: &str) -> Request {
+        let head = RequestHead {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: "1.1".to_string(),
+            headers: Vec::new(),
+        };
+        return head.into_request(RequestBody::from_bytes(Vec::new()));
+    }
+
+    #[test]
+    fn matched_routes_carry_their_span_name() {
+        let mut router = Router::new();
+        router.route("GET", "/users/:id", |_req: &Request| Response::new(200));
+        router
+            .route("GET", "/assets/**path", |_req: &Request| Response::new(200))
+            .span_name("GET assets");
+
+        let mut req = request("GET", "/users/7");
+        assert_eq!(router.handle(&mut req).status, 200);
+        let route = req.route.unwrap();
+        assert_eq!(route.pattern, "/users/:id");
+        assert_eq!(route.span_name, None);
+        assert_eq!(req.params.get("id").map(String::as_str), Some("7"));
+
+        let mut req = request("GET", "/assets/app.js");
+        router.handle(&mut req);
+        assert_eq!(req.route.unwrap().span_name.as_deref(), Some("GET assets"));
+    }
+
+    fn routes() -> Router {
+        let mut router = Router::new();
+        for (method, pattern) in [
+            ("GET", "/users/:id"),
+            ("DELETE", "/users/:id"),
+            ("GET", "/users/me"),
+            ("GET", "/users/**rest"),
+            ("POST", "/users"),
+            ("*", "/api/**"),
+            ("GET", "/**path"),
+        ] {
+            router.route(method, pattern, |_req: &Request| Response::new(200));
+        }
+        return router;
+    }
+
+    fn matched(router: &Router, method: &str, path: &str) -> Option<String> {
+        match router.find(method, path) {
+            RouteMatch::Found(route, _) => Some(route.pattern.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn other_methods_on_known_paths_are_not_allowed() {
+        let router = routes();
+        let resp = router.handle(&mut request("PUT", "/users/7"));
+        assert_eq!(resp.status, 405);
+        assert_eq!(resp.header("Allow"), Some("DELETE, GET"));
+        assert_eq!(router.handle(&mut request("GET", "/users")).status, 200);
+        assert_eq!(
+            router
+                .handle(&mut request("DELETE", "/users"))
+                .header("Allow"),
+            Some("GET, POST")
+        );
+    }
+
+    #[test]
+    fn a_catch_all_route_leaves_unknown_paths_not_found() {
+        let router = routes();
+        assert_eq!(router.handle(&mut request("GET", "/missing")).status, 200);
+        for method in ["POST", "DELETE", "OPTIONS"] {
+            let resp = router.handle(&mut request(method, "/missing/page"));
+            assert_eq!(resp.status, 404, "{} /missing/page", method);
+            assert_eq!(resp.header("Allow"), None);
+        }
+        // every method matches a route registered for "*"
+        assert_eq!(
+            router.handle(&mut request("PATCH", "/api/items")).status,
+            200
+        );
+    }
+
+    #[test]
+    fn the_most_specific_pattern_wins() {
+        let router = routes();
+        assert_eq!(
+            matched(&router, "GET", "/users/me").as_deref(),
+            Some("/users/me")
+        );
+        assert_eq!(
+            matched(&router, "GET", "/users/7").as_deref(),
+            Some("/users/:id")
+        );
+        assert_eq!(
+            matched(&router, "GET", "/users/7/posts").as_deref(),
+            Some("/users/**rest")
+        );
+        assert_eq!(
+            matched(&router, "GET", "/users").as_deref(),
+            Some("/users/**rest")
+        );
+        assert_eq!(
+            matched(&router, "GET", "/api/items").as_deref(),
+            Some("/api/**")
+        );
+        assert_eq!(matched(&router, "GET", "/").as_deref(), Some("/**path"));
+
+        let mut req = request("GET", "/users/7/posts/3");
+        router.handle(&mut req);
+        assert_eq!(
+            req.params.get("rest").map(String::as_str),
+            Some("7/posts/3")
+        );
+    }
+
+    #[test]
+    fn routes_matching_the_same_paths_conflict() {
+        assert!(routes().check_conflicts().is_ok());
+
+        let conflict = |routes: &[(&str, &str)]| {
+            let mut router = Router::new();
+            for (method, pattern) in routes {
+                router.route(method, pattern, |_req: &Request| Response::new(200));
+            }
+            router.check_conflicts()
+        };
+        assert_eq!(
+            conflict(&[("GET", "/users/:id"), ("get", "/users/:name")]),
+            Err("GET /users/:id conflicts with GET /users/:name".to_string())
+        );
+        assert!(conflict(&[("GET", "/files/*"), ("*", "/files/:name")]).is_err());
+        assert!(conflict(&[("GET", "/files/**"), ("GET", "/files/**path")]).is_err());
+        assert!(conflict(&[("GET", "/files/:name"), ("POST", "/files/:name")]).is_ok());
+        assert!(conflict(&[("GET", "/files/:name"), ("GET", "/files/**")]).is_ok());
+    }
+}
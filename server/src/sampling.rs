@@ -0,0 +1,623 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use opentelemetry::{
+    Context, KeyValue,
+    trace::{
+        Link, SamplingDecision, SamplingResult, Span as _, SpanContext, SpanId, SpanKind, Status,
+        TraceContextExt, TraceFlags, TraceId, TraceState,
+    },
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{Sampler, ShouldSample, Span, SpanData, SpanProcessor, TracerProviderBuilder},
+};
+use serde::Deserialize;
+
+/*
+Chooses which traces are kept, ex:
+[telemetry.sampler]
+type = "parent_based"
+root = { type = "ratio", ratio = 0.25 }
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SamplerConfig {
+    AlwaysOn,
+    AlwaysOff,
+    // keeps this fraction of traces, decided by trace id so every service agrees
+    Ratio { ratio: f64 },
+    // follows the caller's sampled flag, `root` decides for traces that start here
+    ParentBased { root: Box<SamplerConfig> },
+    Rules(RulesConfig),
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        return SamplerConfig::ParentBased {
+            root: Box::new(SamplerConfig::AlwaysOn),
+        };
+    }
+}
+
+impl SamplerConfig {
+    // the rules sampler's config, also when it's the root of a parent based sampler
+    pub fn rules(&self) -> Option<&RulesConfig> {
+        return match self {
+            SamplerConfig::Rules(rules) => Some(rules),
+            SamplerConfig::ParentBased { root } => root.rules(),
+            _ => None,
+        };
+    }
+}
+
+// the rules sampler records spans it doesn't sample, they're only exported through a RuleProcessor
+pub fn with_sampled_span_processor<P: SpanProcessor + 'static>(
+    builder: TracerProviderBuilder,
+    processor: P,
+    sampler: &SamplerConfig,
+    max_pending_spans: usize,
+) -> TracerProviderBuilder {
+    return match sampler.rules() {
+        Some(rules) => {
+            builder.with_span_processor(RuleProcessor::new(processor, rules, max_pending_spans))
+        }
+        None => builder.with_span_processor(processor),
+    };
+}
+
+/*
+Samples requests by ratio, but unsampled requests are still recorded so they can be
+kept once they finish if they failed or were slow, see RuleProcessor.
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RulesConfig {
+    // fraction of requests kept when no rule applies
+    pub ratio: f64,
+    // requests answered with a 5xx are always kept
+    pub keep_errors: bool,
+    // requests taking at least this long are always kept
+    pub slow_request_ms: u64,
+    // static asset requests are sampled at `static_ratio` instead,
+    // matched by path prefix, ex: "/assets/", or by file extension, ex: "js"
+    pub static_prefixes: Vec<String>,
+    pub static_extensions: Vec<String>,
+    pub static_ratio: f64,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        return RulesConfig {
+            ratio: 0.1,
+            keep_errors: true,
+            slow_request_ms: 1000,
+            static_prefixes: vec!["/assets/".to_string()],
+            static_extensions: ["css", "js", "ico", "png", "svg", "webp", "woff2"]
+                .iter()
+                .map(|extension| extension.to_string())
+                .collect(),
+            static_ratio: 0.01,
+        };
+    }
+}
+
+impl RulesConfig {
+    fn is_static(&self, path: &str) -> bool {
+        if self
+            .static_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return true;
+        }
+        let file_name = path.rsplit('/').next().unwrap_or_default();
+        return match file_name.rsplit_once('.') {
+            Some((_, extension)) => self
+                .static_extensions
+                .iter()
+                .any(|static_extension| static_extension.eq_ignore_ascii_case(extension)),
+            None => false,
+        };
+    }
+}
+
+// the sampler the tracer provider is built with
+#[derive(Debug, Clone)]
+pub enum RequestSampler {
+    Sdk(Sampler),
+    Rules(RuleSampler),
+}
+
+impl RequestSampler {
+    pub fn new(config: &SamplerConfig) -> Self {
+        return match config {
+            SamplerConfig::AlwaysOn => RequestSampler::Sdk(Sampler::AlwaysOn),
+            SamplerConfig::AlwaysOff => RequestSampler::Sdk(Sampler::AlwaysOff),
+            SamplerConfig::Ratio { ratio } => {
+                RequestSampler::Sdk(Sampler::TraceIdRatioBased(*ratio))
+            }
+            SamplerConfig::ParentBased { root } => match RequestSampler::new(root) {
+                // the SDK's parent based sampler would drop the spans inside unsampled requests,
+                // which the rules sampler records in case the request is kept
+                RequestSampler::Rules(sampler) => RequestSampler::Rules(RuleSampler {
+                    follow_remote_parent: true,
+                    ..sampler
+                }),
+                root => RequestSampler::Sdk(Sampler::ParentBased(Box::new(root))),
+            },
+            SamplerConfig::Rules(rules) => RequestSampler::Rules(RuleSampler::new(rules)),
+        };
+    }
+}
+
+impl ShouldSample for RequestSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        match self {
+            RequestSampler::Sdk(sampler) => {
+                sampler.should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+            }
+            RequestSampler::Rules(sampler) => {
+                sampler.should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleSampler {
+    rules: RulesConfig,
+    default_sampler: Sampler,
+    static_sampler: Sampler,
+    // set under parent_based, callers that didn't sample the trace aren't overruled
+    follow_remote_parent: bool,
+}
+
+impl RuleSampler {
+    pub fn new(rules: &RulesConfig) -> Self {
+        return RuleSampler {
+            rules: rules.clone(),
+            default_sampler: Sampler::TraceIdRatioBased(rules.ratio),
+            static_sampler: Sampler::TraceIdRatioBased(rules.static_ratio),
+            follow_remote_parent: false,
+        };
+    }
+}
+
+impl ShouldSample for RuleSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context
+            .map(|cx| cx.span().span_context().clone())
+            .filter(|parent| parent.is_valid());
+        let trace_state = match &parent {
+            Some(parent) => parent.trace_state().clone(),
+            None => TraceState::default(),
+        };
+
+        let decision = match &parent {
+            // spans inside a request follow it, unsampled ones wait for the request to end
+            Some(parent) if !parent.is_remote() => {
+                let parent_recording = parent_context.is_some_and(|cx| cx.span().is_recording());
+                match (parent.is_sampled(), parent_recording) {
+                    (true, _) => SamplingDecision::RecordAndSample,
+                    (false, true) => SamplingDecision::RecordOnly,
+                    // dropped requests, ex: health checks
+                    (false, false) => SamplingDecision::Drop,
+                }
+            }
+            Some(parent) if parent.is_sampled() => SamplingDecision::RecordAndSample,
+            Some(_) if self.follow_remote_parent => SamplingDecision::Drop,
+            _ => {
+                let path = attributes
+                    .iter()
+                    .find(|attribute| attribute.key.as_str() == "url.path")
+                    .map(|attribute| attribute.value.as_str());
+                let sampler = match path {
+                    Some(path) if self.rules.is_static(&path) => &self.static_sampler,
+                    _ => &self.default_sampler,
+                };
+                match sampler
+                    .should_sample(None, trace_id, name, span_kind, attributes, links)
+                    .decision
+                {
+                    SamplingDecision::Drop => SamplingDecision::RecordOnly,
+                    decision => decision,
+                }
+            }
+        };
+        return SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state,
+        };
+    }
+}
+
+// requests remembered after their span ended, for spans of theirs that end later
+const FINISHED_TRACES: usize = 1024;
+// spans waiting longer belong to a request span that never ended, they're dropped once the limit is hit
+const MAX_PENDING_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct PendingTrace {
+    spans: Vec<SpanData>,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct PendingSpans {
+    traces: HashMap<TraceId, PendingTrace>,
+    count: usize,
+    // whether each recently finished request was kept, `finished_order` has the oldest first
+    finished: HashMap<TraceId, bool>,
+    finished_order: VecDeque<TraceId>,
+    // set when the limit is hit, so it's logged once until spans are released again
+    full: bool,
+}
+
+impl PendingSpans {
+    fn take(&mut self, trace_id: TraceId) -> Vec<SpanData> {
+        let spans = match self.traces.remove(&trace_id) {
+            Some(trace) => trace.spans,
+            None => Vec::new(),
+        };
+        self.count -= spans.len();
+        return spans;
+    }
+
+    fn finish(&mut self, trace_id: TraceId, kept: bool) {
+        if self.finished.insert(trace_id, kept).is_none() {
+            self.finished_order.push_back(trace_id);
+        }
+        if self.finished_order.len() > FINISHED_TRACES
+            && let Some(oldest) = self.finished_order.pop_front()
+        {
+            self.finished.remove(&oldest);
+        }
+    }
+
+    fn drop_older_than(&mut self, age: Duration) {
+        let mut dropped = 0;
+        self.traces.retain(|_, trace| {
+            let keep = trace.since.elapsed() < age;
+            if !keep {
+                dropped += trace.spans.len();
+            }
+            keep
+        });
+        self.count -= dropped;
+    }
+}
+
+/*
+Wraps the exporting processor for the rules sampler. Recorded but unsampled spans are
+held until the request span they belong to ends, then the whole request is exported
+if it failed or was slow and dropped otherwise.
+*/
+#[derive(Debug)]
+pub struct RuleProcessor<P: SpanProcessor> {
+    inner: P,
+    keep_errors: bool,
+    slow_request: Duration,
+    // spans waiting on their request, more than this are dropped
+    max_pending_spans: usize,
+    pending: Mutex<PendingSpans>,
+}
+
+impl<P: SpanProcessor> RuleProcessor<P> {
+    pub fn new(inner: P, rules: &RulesConfig, max_pending_spans: usize) -> Self {
+        return RuleProcessor {
+            inner,
+            keep_errors: rules.keep_errors,
+            slow_request: Duration::from_millis(rules.slow_request_ms),
+            max_pending_spans,
+            pending: Mutex::new(PendingSpans {
+                traces: HashMap::new(),
+                count: 0,
+                finished: HashMap::new(),
+                finished_order: VecDeque::new(),
+                full: false,
+            }),
+        };
+    }
+
+    fn should_keep(&self, span: &SpanData) -> bool {
+        if self.keep_errors && matches!(span.status, Status::Error { .. }) {
+            return true;
+        }
+        let duration = span
+            .end_time
+            .duration_since(span.start_time)
+            .unwrap_or_default();
+        return duration >= self.slow_request;
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for RuleProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        // a caller can send several requests in one trace, each one is decided again
+        let parent = cx.span().span_context().clone();
+        if !span.span_context().is_sampled() && (!parent.is_valid() || parent.is_remote()) {
+            let mut pending = match self.pending.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            pending.finished.remove(&span.span_context().trace_id());
+        }
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if span.span_context.is_sampled() {
+            self.inner.on_end(span);
+            return;
+        }
+
+        let mut pending = match self.pending.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let trace_id = span.span_context.trace_id();
+        // the request span, or a span started outside of any request
+        let is_local_root =
+            span.span_kind == SpanKind::Server || span.parent_span_id == SpanId::INVALID;
+        if !is_local_root {
+            // ended after its request, ex: work the handler left running
+            if let Some(kept) = pending.finished.get(&trace_id).copied() {
+                drop(pending);
+                if kept {
+                    self.inner.on_end(mark_sampled(span));
+                }
+                return;
+            }
+            if pending.count >= self.max_pending_spans {
+                pending.drop_older_than(MAX_PENDING_AGE);
+            }
+            if pending.count >= self.max_pending_spans {
+                if !pending.full {
+                    pending.full = true;
+                    warn!(max_pending_spans = self.max_pending_spans; "Dropping spans - too many are waiting on their request to end");
+                }
+                return;
+            }
+            pending
+                .traces
+                .entry(trace_id)
+                .or_insert_with(|| PendingTrace {
+                    spans: Vec::new(),
+                    since: Instant::now(),
+                })
+                .spans
+                .push(span);
+            pending.count += 1;
+            return;
+        }
+
+        let children = pending.take(trace_id);
+        if pending.count < self.max_pending_spans {
+            pending.full = false;
+        }
+        let keep = self.should_keep(&span);
+        pending.finish(trace_id, keep);
+        drop(pending);
+        if keep {
+            for child in children {
+                self.inner.on_end(mark_sampled(child));
+            }
+            self.inner.on_end(mark_sampled(span));
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        return self.inner.force_flush();
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        return self.inner.shutdown();
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+// kept spans are exported as sampled, like the ones sampled when they started
+fn mark_sampled(mut span: SpanData) -> SpanData {
+    let context = &span.span_context;
+    span.span_context = SpanContext::new(
+        context.trace_id(),
+        context.span_id(),
+        context.trace_flags() | TraceFlags::SAMPLED,
+        context.is_remote(),
+        context.trace_state().clone(),
+    );
+    return span;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use opentelemetry::trace::{Span as _, Tracer, TracerProvider};
+    use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct Exported(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanProcessor for Exported {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        // keeps every span it's given, like the batch processor it stands in for
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            return Ok(());
+        }
+
+        fn shutdown(&self) -> OTelSdkResult {
+            return Ok(());
+        }
+    }
+
+    impl Exported {
+        fn take(&self) -> Vec<SpanData> {
+            return std::mem::take(&mut *self.0.lock().unwrap());
+        }
+    }
+
+    fn provider(
+        sampler: &SamplerConfig,
+        max_pending_spans: usize,
+    ) -> (SdkTracerProvider, Exported) {
+        let exported = Exported::default();
+        let builder = SdkTracerProvider::builder().with_sampler(RequestSampler::new(sampler));
+        let provider =
+            with_sampled_span_processor(builder, exported.clone(), sampler, max_pending_spans)
+                .build();
+        return (provider, exported);
+    }
+
+    // keeps only failed requests
+    fn rules() -> RulesConfig {
+        return RulesConfig {
+            ratio: 0.0,
+            keep_errors: true,
+            slow_request_ms: 60_000,
+            static_ratio: 0.0,
+            ..RulesConfig::default()
+        };
+    }
+
+    // a request span with a child, like handle_request
+    fn request(tracer: &SdkTracer, parent: &Context, failed: bool) {
+        let request_span = tracer
+            .span_builder("GET /items")
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![KeyValue::new("url.path", "/items")])
+            .start_with_context(tracer, parent);
+        let cx = parent.with_span(request_span);
+        tracer.start_with_context("handle", &cx).end();
+        if failed {
+            cx.span().set_status(Status::error("upstream failed"));
+        }
+        cx.span().end();
+    }
+
+    fn remote_parent(sampled: bool) -> Context {
+        let flags = match sampled {
+            true => TraceFlags::SAMPLED,
+            false => TraceFlags::default(),
+        };
+        return Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(7),
+            SpanId::from(7),
+            flags,
+            true,
+            TraceState::default(),
+        ));
+    }
+
+    #[test]
+    fn rules_nested_in_parent_based_keep_only_failed_requests() {
+        let sampler = SamplerConfig::ParentBased {
+            root: Box::new(SamplerConfig::Rules(rules())),
+        };
+        assert!(sampler.rules().is_some());
+        let (provider, exported) = provider(&sampler, 100);
+        let tracer = provider.tracer("test");
+
+        request(&tracer, &Context::new(), false);
+        assert!(exported.take().is_empty());
+
+        request(&tracer, &Context::new(), true);
+        let spans = exported.take();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.span_context.is_sampled()));
+    }
+
+    #[test]
+    fn rules_nested_in_parent_based_follow_remote_callers() {
+        let sampler = SamplerConfig::ParentBased {
+            root: Box::new(SamplerConfig::Rules(rules())),
+        };
+        let (provider, exported) = provider(&sampler, 100);
+        let tracer = provider.tracer("test");
+
+        request(&tracer, &remote_parent(false), true);
+        assert!(exported.take().is_empty());
+        request(&tracer, &remote_parent(true), false);
+        assert_eq!(exported.take().len(), 2);
+    }
+
+    #[test]
+    fn top_level_rules_keep_failed_requests_of_unsampled_callers() {
+        let sampler = SamplerConfig::Rules(rules());
+        let (provider, exported) = provider(&sampler, 100);
+        let tracer = provider.tracer("test");
+
+        request(&tracer, &remote_parent(false), false);
+        assert!(exported.take().is_empty());
+        request(&tracer, &remote_parent(false), true);
+        let spans = exported.take();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.span_context.is_sampled()));
+    }
+
+    #[test]
+    fn spans_ending_after_their_request_follow_its_decision() {
+        let sampler = SamplerConfig::Rules(rules());
+        // one span can wait, a late span that was held would keep the next request's child out
+        let (provider, exported) = provider(&sampler, 1);
+        let tracer = provider.tracer("test");
+
+        for failed in [false, true] {
+            let request_span = tracer
+                .span_builder("GET /items")
+                .with_kind(SpanKind::Server)
+                .start(&tracer);
+            let cx = Context::new().with_span(request_span);
+            let mut background = tracer.start_with_context("background", &cx);
+            if failed {
+                cx.span().set_status(Status::error("upstream failed"));
+            }
+            cx.span().end();
+            background.end();
+        }
+        let spans = exported.take();
+        let names = spans
+            .iter()
+            .map(|span| span.name.as_ref())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, ["GET /items", "background"]);
+        assert!(spans.iter().all(|span| span.span_context.is_sampled()));
+
+        request(&tracer, &Context::new(), true);
+        assert_eq!(exported.take().len(), 2);
+    }
+}
@@ -1,10 +1,10 @@
+use std::sync::atomic::Ordering;
 use std::{
-    collections::HashSet,
-    fs,
-    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
-    path::PathBuf,
-    thread::{JoinHandle, available_parallelism},
-    time::Duration,
+    io::{self, Read},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    thread::{JoinHandle, available_parallelism, sleep},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
@@ -12,60 +12,130 @@ use log::{error, info, warn};
 use nix::{
     errno::Errno,
     poll::{PollFd, PollFlags, PollTimeout, poll},
-    sys::socket::{Backlog, MsgFlags, SockaddrIn, accept, getpeername, recv, send},
+    sys::socket::accept,
 };
 use opentelemetry::{
-    KeyValue, global,
-    metrics::Counter,
-    trace::{Span, SpanKind, Tracer},
+    Context, KeyValue, global,
+    metrics::{Counter, Histogram, UpDownCounter},
+    trace::{
+        SamplingDecision, SamplingResult, Span, SpanKind, Status, TraceContextExt, TraceState,
+        Tracer,
+    },
 };
+use serde::Deserialize;
 use std::str;
 
 use crate::{
-    init::{get_static_file_paths, setup_listening_socket},
-    statics::SHUTDOWN_SERVER,
-    telemetry::{force_export_telemetry, get_tracer},
+    access_log::{AccessLog, AccessLogEntry},
+    admin::{ADMIN_TOKEN_ENV, ServerStats, WorkerState, route_admin_api},
+    config::ServerConfig,
+    connection::{Socket, Stream},
+    health::{HealthConfig, route_health_checks},
+    http::{Request, RequestBody, RequestHead, Response, find_head_end, parse_request_head},
+    listener::{AcceptedConnection, ListenAddress, Listener, ListenerConfig, Peer},
+    middleware::{MiddlewareChain, REQUEST_ID_HEADER, build_middleware},
+    prometheus::PrometheusMetrics,
+    proxy::{Proxy, ProxyConfig},
+    redaction::Redactor,
+    redirect::{build_redirect_response, strip_port},
+    router::Router,
+    sse::{ADMIN_EVENTS, EventStream, SERVER_EVENTS},
+    static_files::StaticFiles,
+    statics::{DRAINING, SHUTDOWN_SERVER},
+    telemetry::{extract_context, force_export_telemetry, get_tracer, trace_response},
+    websocket::{Echo, WebSocketUpgrade},
 };
+#[cfg(feature = "tls")]
+use crate::{
+    statics::RELOAD_CERTIFICATES,
+    tls::{TlsAcceptor, client_identity},
+};
+#[cfg(feature = "tls")]
+use opentelemetry::{StringValue, Value};
 
-enum SysCallError {
+enum ReadError {
     Timeout,
-    Error(MessagedErrno),
+    Error(MessagedError),
+    // the request can't be parsed, answered with a 400
+    Invalid(String),
+    TooLarge,
+    // a transfer coding other than chunked, answered with a 501
+    UnsupportedEncoding,
+    // the request line and headers don't fit in MAX_REQUEST_HEAD_BYTES, answered with a 431
+    HeadTooLarge,
 }
 
-struct MessagedErrno {
-    errno: Errno,
+const MAX_REQUEST_HEAD_BYTES: usize = 16 * 1024;
+
+struct MessagedError {
+    error: io::Error,
     message: String,
 }
 
 #[derive(Clone)]
 struct ConnectionChannel {
-    sender: Option<Sender<OwnedFd>>,
-    receiver: Receiver<OwnedFd>,
+    sender: Option<Sender<AcceptedConnection>>,
+    receiver: Receiver<AcceptedConnection>,
 }
 
-pub struct Server {
-    static_files: HashSet<PathBuf>,
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AcceptMode {
+    // the main thread accepts connections and sends them to the handlers over a channel
+    Channel,
+    // every handler thread owns SO_REUSEPORT listening sockets and accepts directly,
+    // the kernel load balances connections between them
+    ReusePort,
+}
+
+// seconds, the semantic conventions' recommended boundaries
+const REQUEST_DURATION_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+// the methods the semantic conventions name, anything else is recorded as _OTHER
+const KNOWN_METHODS: [&str; 9] = [
+    "CONNECT", "DELETE", "GET", "HEAD", "OPTIONS", "PATCH", "POST", "PUT", "TRACE",
+];
+
+// state shared by every request handler thread
+struct RequestContext {
+    router: Router,
+    access_log: AccessLog,
+    redactor: Arc<Redactor>,
+    health: HealthConfig,
+    stats: Arc<ServerStats>,
+    // served instead of `router` on admin listeners
+    admin_router: Router,
+    middleware: MiddlewareChain,
+    max_request_body_bytes: usize,
+    max_upgraded_connections: usize,
     total_reqs: Counter<u64>,
     finished_reqs: Counter<u64>,
-    listening_sock: OwnedFd,
+    // HTTP server metrics from the OpenTelemetry semantic conventions
+    request_duration: Histogram<f64>,
+    request_body_size: Histogram<u64>,
+    response_body_size: Histogram<u64>,
+    active_requests: UpDownCounter<i64>,
+    trace_response: bool,
     timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+pub struct Server {
+    ctx: Arc<RequestContext>,
+    accept_mode: AcceptMode,
+    listener_configs: Vec<ListenerConfig>,
+    listeners: Vec<Listener>,
+    drain: Duration,
+    thread_count: usize,
     cxns: ConnectionChannel,
     join_handlers: Option<Vec<JoinHandle<()>>>,
 }
 
 impl Server {
-    pub fn init_server(
-        timeout: Duration,
-        static_files_location: PathBuf,
-        sock_addr: SockaddrIn,
-    ) -> Self {
-        let static_files = get_static_file_paths(static_files_location);
-        if static_files.is_empty() {
-            error!("No static files found");
-            force_export_telemetry(false);
-            panic!("No static files found")
-        }
-
+    pub fn init_server(config: &ServerConfig) -> Self {
         let reqs_started = global::meter("requests")
             .u64_counter("total_started")
             .with_description("Total number of requests started")
@@ -74,8 +144,121 @@ impl Server {
             .u64_counter("total_finished")
             .with_description("Total number of requests finished")
             .build();
+        let request_duration = global::meter("requests")
+            .f64_histogram("http.server.request.duration")
+            .with_unit("s")
+            .with_description("Duration of HTTP server requests")
+            .with_boundaries(REQUEST_DURATION_BUCKETS.to_vec())
+            .build();
+        let request_body_size = global::meter("requests")
+            .u64_histogram("http.server.request.body.size")
+            .with_unit("By")
+            .with_description("Size of HTTP server request bodies")
+            .build();
+        let response_body_size = global::meter("requests")
+            .u64_histogram("http.server.response.body.size")
+            .with_unit("By")
+            .with_description("Size of HTTP server response bodies")
+            .build();
+        let active_requests = global::meter("requests")
+            .i64_up_down_counter("http.server.active_requests")
+            .with_unit("{request}")
+            .with_description("Number of active HTTP server requests")
+            .build();
+
+        if config.listeners.is_empty() {
+            error!("No listeners configured");
+            force_export_telemetry(false);
+            panic!("No listeners configured")
+        }
+        if config.accept_mode == AcceptMode::ReusePort
+            && config
+                .listeners
+                .iter()
+                .any(|listener| matches!(listener.address, ListenAddress::Unix(_)))
+        {
+            error!("Unix socket listeners can't be used in reuse port accept mode");
+            force_export_telemetry(false);
+            panic!("Unix socket listeners can't be used in reuse port accept mode")
+        }
+        #[cfg(feature = "tls")]
+        let tls = config.tls.as_ref().map(TlsAcceptor::new);
+        let tls_listener = config.listeners.iter().find(|listener| listener.tls);
+        #[cfg(not(feature = "tls"))]
+        if let Some(listener) = tls_listener {
+            error!(listener = listener.name.as_str(); "TLS listeners require the tls feature");
+            force_export_telemetry(false);
+            panic!("TLS listeners require the tls feature | {}", listener.name);
+        }
+        #[cfg(feature = "tls")]
+        if tls.is_none()
+            && let Some(listener) = tls_listener
+        {
+            error!(listener = listener.name.as_str(); "TLS listener configured without a [tls] section");
+            force_export_telemetry(false);
+            panic!(
+                "TLS listener configured without a [tls] section | {}",
+                listener.name
+            );
+        }
+
+        if let Some(listener) = config.listeners.iter().find(|listener| {
+            listener
+                .redirect
+                .as_ref()
+                .is_some_and(|redirect| ![301, 308].contains(&redirect.status))
+        }) {
+            error!(listener = listener.name.as_str(); "Redirect status must be 301 or 308");
+            force_export_telemetry(false);
+            panic!("Redirect status must be 301 or 308 | {}", listener.name);
+        }
 
-        let listening_sock = setup_listening_socket(sock_addr, Backlog::MAXCONN);
+        if let Some(listener) = config
+            .listeners
+            .iter()
+            .find(|listener| listener.admin && listener.redirect.is_some())
+        {
+            error!(listener = listener.name.as_str(); "Admin listeners can't redirect");
+            force_export_telemetry(false);
+            panic!("Admin listeners can't redirect | {}", listener.name);
+        }
+        if config.admin.enabled
+            && config
+                .admin
+                .token
+                .as_ref()
+                .is_none_or(|token| token.is_empty())
+        {
+            error!("The admin API is enabled without a token");
+            force_export_telemetry(false);
+            panic!(
+                "The admin API is enabled without a token, set admin.token or {}",
+                ADMIN_TOKEN_ENV
+            );
+        }
+        if config.telemetry.prometheus.admin_only
+            && !config.listeners.iter().any(|listener| listener.admin)
+        {
+            error!("Prometheus metrics are admin only but no admin listener is configured");
+            force_export_telemetry(false);
+            panic!("Prometheus metrics are admin only but no admin listener is configured");
+        }
+
+        let listeners = match config.accept_mode {
+            AcceptMode::Channel => config
+                .listeners
+                .iter()
+                .cloned()
+                .filter_map(Listener::bind)
+                .collect::<Vec<Listener>>(),
+            // handler threads bind their own sockets
+            AcceptMode::ReusePort => Vec::new(),
+        };
+        if config.accept_mode == AcceptMode::Channel && listeners.is_empty() {
+            error!("None of the configured listeners could be bound");
+            force_export_telemetry(false);
+            panic!("None of the configured listeners could be bound");
+        }
 
         let conns_chanel = {
             let (sender, receiver) = unbounded();
@@ -85,18 +268,6 @@ impl Server {
             }
         };
 
-        return Server {
-            static_files,
-            total_reqs: reqs_started,
-            finished_reqs: reqs_finished,
-            listening_sock,
-            timeout,
-            cxns: conns_chanel,
-            join_handlers: None,
-        };
-    }
-
-    pub fn begin_connection_handlers(&mut self) {
         let thread_count = match available_parallelism() {
             Ok(threads) => threads.get(),
             Err(e) => {
@@ -107,40 +278,118 @@ impl Server {
                 1
             }
         };
+        let queue = match config.accept_mode {
+            AcceptMode::Channel => Some(conns_chanel.receiver.clone()),
+            AcceptMode::ReusePort => None,
+        };
+        let stats = Arc::new(ServerStats::new(thread_count, queue));
+        // shared with the admin API, which can reload the index
+        let static_files = match config.dev_mode {
+            true => None,
+            false => Some(Arc::new(StaticFiles::new(config.static_files.clone()))),
+        };
+        let router = build_router(config, static_files.clone(), stats.clone());
+        let admin_router = build_admin_router(config, stats.clone(), static_files);
 
+        let redactor = Arc::new(Redactor::new(&config.redaction));
+        let ctx = RequestContext {
+            router,
+            access_log: AccessLog::new(&config.access_log, redactor.clone()),
+            redactor,
+            health: config.health.clone(),
+            stats,
+            admin_router,
+            middleware: build_middleware(&config.middleware),
+            max_request_body_bytes: config.max_request_body_bytes,
+            max_upgraded_connections: config.max_upgraded_connections,
+            total_reqs: reqs_started,
+            finished_reqs: reqs_finished,
+            request_duration,
+            request_body_size,
+            response_body_size,
+            active_requests,
+            trace_response: config.telemetry.trace_response,
+            timeout: config.timeout(),
+            #[cfg(feature = "tls")]
+            tls,
+        };
+
+        return Server {
+            ctx: Arc::new(ctx),
+            accept_mode: config.accept_mode,
+            listener_configs: config.listeners.clone(),
+            listeners,
+            drain: config.health.drain(),
+            thread_count,
+            cxns: conns_chanel,
+            join_handlers: None,
+        };
+    }
+
+    pub fn begin_connection_handlers(&mut self) {
         let mut join_handlers: Vec<JoinHandle<_>> = Vec::new();
 
-        for thread_id in 0..thread_count {
-            let total_reqs = self.total_reqs.clone();
-            let finished_reqs = self.finished_reqs.clone();
-            let static_files = self.static_files.clone();
-            let timeout = self.timeout.clone();
-            let receiver = self.cxns.receiver.clone();
-
-            let join_handler = std::thread::spawn(move || {
-                loop {
-                    if let Ok(flag) = SHUTDOWN_SERVER.read() {
-                        if *flag {
-                            break;
+        for thread_id in 0..self.thread_count {
+            let ctx = self.ctx.clone();
+
+            let join_handler = match self.accept_mode {
+                AcceptMode::Channel => {
+                    let receiver = self.cxns.receiver.clone();
+                    std::thread::spawn(move || {
+                        let _worker = ctx.stats.worker(thread_id);
+                        loop {
+                            if let Ok(flag) = SHUTDOWN_SERVER.read()
+                                && *flag
+                            {
+                                break;
+                            }
+
+                            let conn = match receiver.recv_timeout(ctx.timeout) {
+                                Ok(conn) => conn,
+                                Err(RecvTimeoutError::Timeout) => continue,
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            };
+
+                            ctx.stats.set_worker_state(thread_id, WorkerState::Busy);
+                            handle_request(&ctx, thread_id, conn);
+                            ctx.stats.set_worker_state(thread_id, WorkerState::Idle);
                         }
+                    })
+                }
+                AcceptMode::ReusePort => {
+                    // bound before spawning so bind failures surface on the main thread
+                    let listeners = self
+                        .listener_configs
+                        .iter()
+                        .cloned()
+                        .filter_map(|mut config| {
+                            config.reuse_port = true;
+                            Listener::bind(config)
+                        })
+                        .collect::<Vec<Listener>>();
+                    if listeners.is_empty() {
+                        error!("None of the configured listeners could be bound");
+                        force_export_telemetry(false);
+                        panic!("None of the configured listeners could be bound");
                     }
+                    std::thread::spawn(move || {
+                        let _worker = ctx.stats.worker(thread_id);
+                        loop {
+                            if let Ok(flag) = SHUTDOWN_SERVER.read()
+                                && *flag
+                            {
+                                break;
+                            }
 
-                    let conn_fd = match receiver.recv_timeout(timeout) {
-                        Ok(fd) => fd,
-                        Err(RecvTimeoutError::Timeout) => continue,
-                        Err(RecvTimeoutError::Disconnected) => break,
-                    };
-
-                    handle_request(
-                        &total_reqs,
-                        &finished_reqs,
-                        thread_id,
-                        conn_fd,
-                        &static_files,
-                        &timeout,
-                    );
+                            for conn in accept_ready_connections(&listeners, ctx.timeout) {
+                                ctx.stats.set_worker_state(thread_id, WorkerState::Busy);
+                                handle_request(&ctx, thread_id, conn);
+                                ctx.stats.set_worker_state(thread_id, WorkerState::Idle);
+                            }
+                        }
+                    })
                 }
-            });
+            };
 
             join_handlers.push(join_handler);
         }
@@ -163,48 +412,50 @@ impl Server {
             }
         };
 
+        let mut drain_deadline: Option<Instant> = None;
         loop {
-            if let Ok(flag) = SHUTDOWN_SERVER.read() {
-                if *flag {
-                    break;
+            if let Ok(flag) = SHUTDOWN_SERVER.read()
+                && *flag
+            {
+                SERVER_EVENTS.close();
+                ADMIN_EVENTS.close();
+                break;
+            }
+            if drain_deadline.is_none() && DRAINING.load(Ordering::SeqCst) {
+                info!(drain_ms = self.drain.as_millis() as u64; "Draining - readiness checks fail until the server shuts down");
+                drain_deadline = Some(Instant::now() + self.drain);
+            }
+            if let Some(deadline) = drain_deadline
+                && Instant::now() >= deadline
+            {
+                match SHUTDOWN_SERVER.write() {
+                    Ok(mut flag) => *flag = true,
+                    Err(poisoned) => *poisoned.into_inner() = true,
                 }
+                SERVER_EVENTS.close();
+                ADMIN_EVENTS.close();
+                break;
             }
 
-            let conn_fd = {
-                let mut poll_targets =
-                    [PollFd::new(self.listening_sock.as_fd(), PollFlags::POLLIN)];
-                let timeout = match PollTimeout::try_from(self.timeout) {
-                    Ok(timeout) => timeout,
-                    Err(e) => {
-                        warn!(error = format!("{}", e).as_str(); "Defaulting to non-blocking timeout - couldn't set polling timeout");
-                        PollTimeout::ZERO
-                    }
-                };
+            #[cfg(feature = "tls")]
+            if RELOAD_CERTIFICATES.swap(false, Ordering::SeqCst)
+                && let Some(tls) = &self.ctx.tls
+            {
+                tls.reload_certificates();
+            }
 
-                if let Err(e) = poll(&mut poll_targets, timeout) {
-                    error!(error = format!("{}", e).as_str(); "Skipping request - poll failed");
-                    continue;
-                }
-                match poll_targets[0].revents() {
-                    // continue if connection is available
-                    Some(PollFlags::POLLIN) => (),
-                    // skip if poll timedout
-                    Some(_) | None => continue,
-                }
+            if self.accept_mode == AcceptMode::ReusePort {
+                // handler threads accept for themselves, the main thread only waits for shutdown
+                sleep(self.ctx.timeout);
+                continue;
+            }
 
-                match accept(self.listening_sock.as_raw_fd()) {
-                    Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
-                    Err(e) => {
-                        error!(error = format!("{}", e).as_str(); "Skipping request - accept failed");
-                        continue;
-                    }
+            for conn in accept_ready_connections(&self.listeners, self.ctx.timeout) {
+                if let Err(e) = sender.send(conn) {
+                    // might cause issues with blocking
+                    error!(error = format!("{}", e).as_str(); "Skipping request - could not send connection to handlers");
+                    continue;
                 }
-            };
-
-            if let Err(e) = sender.send(conn_fd) {
-                // might cause issues with blocking
-                error!(error = format!("{}", e).as_str(); "Skipping request - could not send connection to handlers");
-                continue;
             }
         }
     }
@@ -213,10 +464,11 @@ impl Server {
         match self.join_handlers.take() {
             Some(handlers) => {
                 for handler in handlers {
-                    if let Err(_) = handler.join() {
+                    if handler.join().is_err() {
                         error!("Thread Join Failed");
                     }
                 }
+                self.ctx.access_log.flush();
             }
             None => {
                 error!("No handlers to join");
@@ -227,221 +479,685 @@ impl Server {
     }
 }
 
-fn read_request(fd: BorrowedFd, poll_timeout_duration: Duration) -> Result<Vec<u8>, SysCallError> {
-    /*
-    Assumption:
-    - Client allways sends a request, before server sends a response.
-    ! What basis do we have for this? (Check HTTP protocol specification)
-    */
-    let mut read_buf = [0u8; 1000];
-    let mut req: Vec<u8> = Vec::new();
-
+// polls every listener once and accepts a connection from each one that's ready
+fn accept_ready_connections(
+    listeners: &[Listener],
+    poll_timeout_duration: Duration,
+) -> Vec<AcceptedConnection> {
+    let mut poll_targets = listeners
+        .iter()
+        .map(|listener| PollFd::new(listener.as_fd(), PollFlags::POLLIN))
+        .collect::<Vec<PollFd>>();
     let timeout = match PollTimeout::try_from(poll_timeout_duration) {
         Ok(timeout) => timeout,
         Err(e) => {
-            warn!(error = format!("{}", e).as_str(); "Defaulting to polling timeout max - couldn't set polling timeout");
-            PollTimeout::MAX
+            warn!(error = format!("{}", e).as_str(); "Defaulting to non-blocking timeout - couldn't set polling timeout");
+            PollTimeout::ZERO
         }
     };
-    let mut poll_targets = [PollFd::new(fd, PollFlags::POLLIN)];
 
-    if let Err(e) = poll(&mut poll_targets, timeout) {
-        return Err(SysCallError::Error(MessagedErrno {
-            errno: e,
-            message: "Skipping request - poll failed".to_string(),
-        }));
-    };
-    match poll_targets[0].revents() {
-        Some(PollFlags::POLLIN) => (),
-        Some(_) | None => return Err(SysCallError::Timeout),
-    }
-    loop {
-        match recv(fd.as_raw_fd(), &mut read_buf[..], MsgFlags::MSG_DONTWAIT) {
-            Ok(req_size) => {
-                req.extend_from_slice(&read_buf[..req_size]);
-                if req_size < read_buf.len() {
-                    break;
-                }
-            }
+    match poll(&mut poll_targets, timeout) {
+        Ok(_) => (),
+        // signals like SIGHUP interrupt the poll, try again on the next iteration
+        Err(Errno::EINTR) => return Vec::new(),
+        Err(e) => {
+            error!(error = format!("{}", e).as_str(); "Skipping request - poll failed");
+            return Vec::new();
+        }
+    }
+    let ready_listeners = poll_targets
+        .iter()
+        .zip(listeners.iter())
+        // only listeners with a connection available, the rest timed out
+        .filter(|(target, _)| target.revents() == Some(PollFlags::POLLIN))
+        .map(|(_, listener)| listener);
+
+    let mut conns = Vec::new();
+    for listener in ready_listeners {
+        let conn_fd = match accept(listener.as_fd().as_raw_fd()) {
+            Ok(fd) => unsafe { OwnedFd::from_raw_fd(fd) },
             Err(e) => {
-                if e == Errno::EAGAIN || e == Errno::EWOULDBLOCK {
-                    break;
-                } else {
-                    return Err(SysCallError::Error(MessagedErrno {
-                        errno: e,
-                        message: "Skipping request - recv had unexpected error".to_string(),
-                    }));
-                }
+                error!(error = format!("{}", e).as_str(), listener = listener.config.name.as_str(); "Skipping request - accept failed");
+                continue;
             }
-        }
+        };
+        conns.push(AcceptedConnection {
+            fd: conn_fd,
+            listener: listener.config.clone(),
+        });
     }
 
-    Ok(req)
+    return conns;
 }
 
-fn build_response(req: Vec<u8>, static_files: &HashSet<PathBuf>) -> String {
+fn read_request(
+    stream: &mut Stream,
+    max_body_bytes: usize,
+    head_timeout: Duration,
+) -> Result<(RequestHead, Vec<u8>), ReadError> {
     /*
     Assumption:
-    - We'll always get a request.
-    - The request is a valid HTTP request.
+    - Client allways sends a request, before server sends a response.
+    ! What basis do we have for this? (Check HTTP protocol specification)
     */
-    let status_line_parts = {
-        // This variable is the status line of an HTTP request split by spaces.
-        let end_of_line_position = match req.iter().position(|&b| b == b'\r') {
-            Some(pos) => pos,
-            None => {
-                error!("Invalid HTTP Request - Could Not Find End Of Line");
-                let message = "Bad Request";
-                return format!(
-                    "HTTP/1.1 400 Bad Request\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-                    message.len(),
-                    message
-                );
+    let mut read_buf = [0u8; 1000];
+    let mut req: Vec<u8> = Vec::new();
+    let head_started = Instant::now();
+
+    /*
+    Reads until the end of the request head. Every socket read times out if the client stalls,
+    the deadline stops clients trickling in a byte at a time from holding the thread.
+    */
+    let head_end = loop {
+        if let Some(head_end) = find_head_end(&req) {
+            break head_end;
+        }
+        if req.len() > MAX_REQUEST_HEAD_BYTES {
+            return Err(ReadError::HeadTooLarge);
+        }
+        if !req.is_empty() && head_started.elapsed() > head_timeout {
+            return Err(ReadError::Invalid(
+                "timed out reading request head".to_string(),
+            ));
+        }
+        match stream.read(&mut read_buf[..]) {
+            Ok(0) if req.is_empty() => return Err(ReadError::Timeout),
+            Ok(0) => {
+                return Err(ReadError::Invalid(
+                    "connection closed mid request".to_string(),
+                ));
             }
-        };
-        let status_line = String::from_utf8_lossy(&req[..end_of_line_position]);
-        status_line
-            .split(" ")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>()
-    };
-    let requested_path = {
-        let path_string = String::from("../client/dist") + status_line_parts[1].as_str();
-        PathBuf::from(path_string)
+            Ok(req_size) => req.extend_from_slice(&read_buf[..req_size]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && req.is_empty() => {
+                return Err(ReadError::Timeout);
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(ReadError::Invalid(
+                    "timed out reading request head".to_string(),
+                ));
+            }
+            Err(e) => {
+                return Err(ReadError::Error(MessagedError {
+                    error: e,
+                    message: "Skipping request - recv had unexpected error".to_string(),
+                }));
+            }
+        }
     };
 
-    match (status_line_parts[0].as_str(), status_line_parts[1].as_str()) {
-        ("GET", _path) if static_files.contains(&requested_path) => {
-            let content_type = {
-                let file_ext = requested_path.extension();
-
-                match file_ext.and_then(|ex| ex.to_str()) {
-                    Some("html") => "text/html",
-                    Some("css") => "text/css",
-                    Some("js") => "application/javascript",
-                    Some("svg") => "image/svg+xml",
-                    None => "application/json",
-                    _ => "application/octet-stream",
-                }
-            };
-            let content = match fs::read_to_string(requested_path.display().to_string()) {
-                Ok(content) => content,
-                Err(e) => {
-                    error!(error = format!("{}", e).as_str(); "Could Not Read File");
-                    let message = "Internal Server Error | Could Not Read File";
-                    return format!(
-                        "HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}",
-                        message.len(),
-                        message
-                    );
-                }
-            };
+    if head_end > MAX_REQUEST_HEAD_BYTES {
+        return Err(ReadError::HeadTooLarge);
+    }
+    let head = parse_request_head(&req[..head_end]).map_err(ReadError::Invalid)?;
+    match head.transfer_encoding().as_deref() {
+        None | Some("chunked") => (),
+        // only chunked bodies can be decoded
+        Some(_) => return Err(ReadError::UnsupportedEncoding),
+    }
+    // a request with both can be read differently by the upstream, ex: request smuggling
+    if head.transfer_encoding().is_some() && head.content_length().is_some() {
+        return Err(ReadError::Invalid(
+            "both Content-Length and Transfer-Encoding are set".to_string(),
+        ));
+    }
+    if head.content_length().unwrap_or(0) > max_body_bytes {
+        return Err(ReadError::TooLarge);
+    }
+    // the body is read by the handler, the bytes read past the head are its start
+    let buffered = req.split_off(head_end);
 
-            return format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
-                content_type,
-                content.len(),
-                content
-            );
-        }
-        ("GET", "/") => {
-            let content = match fs::read_to_string("../client/dist/index.html") {
-                Ok(content) => content,
-                Err(e) => {
-                    error!(error = format!("{}", e).as_str(); "Could Not Read File");
-                    let message = "Internal Server Error | Could Not Read File";
-                    return format!(
-                        "HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}",
-                        message.len(),
-                        message
-                    );
-                }
-            };
-            return format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}",
-                content.len(),
-                content
-            );
-        }
-        (_method, _path) => {
-            let message = "Resource Not Found";
-            return format!(
-                "HTTP/1.1 404 Not Found\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}",
-                message.len(),
-                message
-            );
+    Ok((head, buffered))
+}
+
+fn build_router(
+    config: &ServerConfig,
+    static_files: Option<Arc<StaticFiles>>,
+    stats: Arc<ServerStats>,
+) -> Router {
+    let mut router = Router::new();
+    if config.dev_mode {
+        info!(vite_address = config.dev.vite_address.as_str(); "Development mode - unmatched requests are proxied to the Vite dev server");
+        let mut dev_server = ProxyConfig::new("/", &config.dev.vite_address);
+        // Vite builds URLs from the Host header
+        dev_server.preserve_host = true;
+        // the dev server restarting shouldn't lock it out
+        dev_server.ejection_ms = 0;
+        router.route("*", "/**", Proxy::new(dev_server));
+    } else if let Some(static_files) = static_files {
+        router
+            .route_arc("GET", "/**path", static_files)
+            .span_name("GET static");
+    }
+    for proxy_config in &config.proxies {
+        let proxy = Proxy::new(proxy_config.clone());
+        router.route("*", &proxy.route_pattern(), proxy);
+    }
+    if let Some(echo_path) = &config.websocket.echo_path {
+        router.route(
+            "GET",
+            echo_path,
+            WebSocketUpgrade::new(Echo, &config.websocket),
+        );
+    }
+    if let Some(metrics_path) = &config.telemetry.prometheus.path
+        && !config.telemetry.prometheus.admin_only
+    {
+        router.route("GET", metrics_path, PrometheusMetrics);
+    }
+    if let Some(events_path) = &config.events.path {
+        router.route(
+            "GET",
+            events_path,
+            EventStream::new(SERVER_EVENTS.clone(), &config.events),
+        );
+    }
+    route_health_checks(&mut router, &config.health, stats);
+
+    if let Err(e) = router.check_conflicts() {
+        error!(error = e.as_str(); "Conflicting routes");
+        force_export_telemetry(false);
+        panic!("Conflicting routes | {}", e);
+    }
+    return router;
+}
+
+fn method_attribute(method: &str) -> String {
+    match KNOWN_METHODS.contains(&method) {
+        true => method.to_string(),
+        false => "_OTHER".to_string(),
+    }
+}
+
+fn record_request_metrics(
+    ctx: &RequestContext,
+    req: &Request,
+    resp: &Response,
+    started: Instant,
+    write_result: &io::Result<u64>,
+) {
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method_attribute(&req.method)),
+        KeyValue::new("url.scheme", if req.secure { "https" } else { "http" }),
+        KeyValue::new("http.response.status_code", resp.status as i64),
+        KeyValue::new("network.protocol.name", "http"),
+        KeyValue::new("network.protocol.version", req.version.clone()),
+    ];
+    // unmatched requests leave it out, their paths would be unbounded
+    if let Some(route) = &req.route {
+        attributes.push(KeyValue::new("http.route", route.pattern.clone()));
+    }
+    match write_result {
+        Err(e) => attributes.push(KeyValue::new("error.type", format!("{:?}", e.kind()))),
+        Ok(_) if resp.status >= 500 => {
+            attributes.push(KeyValue::new("error.type", resp.status.to_string()))
         }
+        Ok(_) => (),
+    }
+
+    ctx.request_duration
+        .record(started.elapsed().as_secs_f64(), &attributes);
+    ctx.request_body_size.record(
+        req.body.length().unwrap_or_else(|| req.body.bytes_read()),
+        &attributes,
+    );
+    if let Ok(body_bytes) = write_result {
+        ctx.response_body_size.record(*body_bytes, &attributes);
+    }
+}
+
+// `{method} {route}`, just the method without a route, "HTTP" for unknown methods
+fn span_name(method: &str, route: Option<&str>) -> String {
+    let method = match KNOWN_METHODS.contains(&method) {
+        true => method,
+        false => "HTTP",
+    };
+    return match route {
+        Some(route) => format!("{} {}", method, route),
+        None => method.to_string(),
+    };
+}
+
+// semantic convention attributes known once the request is read
+fn request_span_attributes(req: &Request, redactor: &Redactor) -> Vec<KeyValue> {
+    let method = method_attribute(&req.method);
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.path", req.path.clone()),
+        KeyValue::new("url.scheme", if req.secure { "https" } else { "http" }),
+        KeyValue::new("network.protocol.version", req.version.clone()),
+    ];
+    if method == "_OTHER" {
+        attributes.push(KeyValue::new(
+            "http.request.method_original",
+            req.method.clone(),
+        ));
+    }
+    if let Some((_, query)) = req.target.split_once('?') {
+        attributes.push(KeyValue::new("url.query", redactor.query(query)));
+    }
+    if let Some(host) = req.header("host") {
+        attributes.push(KeyValue::new(
+            "server.address",
+            strip_port(host).to_string(),
+        ));
+    }
+    if let Some(user_agent) = req.header("user-agent") {
+        attributes.push(KeyValue::new("user_agent.original", user_agent.to_string()));
     }
+    return attributes;
 }
 
-pub fn handle_request(
-    total_counter: &Counter<u64>,
-    success_counter: &Counter<u64>,
-    thread_id: usize,
-    conn_fd: OwnedFd,
-    serve_files: &HashSet<PathBuf>,
-    poll_timeout: &Duration,
+// answers requests that couldn't be read, their span only has what's known about the connection
+fn reject_request(
+    mut stream: Stream,
+    mut resp: Response,
+    span_attributes: Vec<KeyValue>,
+    read_started: SystemTime,
 ) {
-    total_counter.add(1, &[]);
     let tracer = get_tracer();
     let mut span = tracer
-        .span_builder("request")
+        .span_builder("HTTP")
         .with_kind(SpanKind::Server)
-        .start(tracer);
+        .with_start_time(read_started)
+        .with_attributes(span_attributes)
+        .start_with_context(tracer, &Context::new());
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        resp.status as i64,
+    ));
+    let _ = resp.write_to(&mut stream);
+    stream.close();
+    span.end();
+}
+
+// endpoints for operators, served on listeners with `admin = true`
+fn build_admin_router(
+    config: &ServerConfig,
+    stats: Arc<ServerStats>,
+    static_files: Option<Arc<StaticFiles>>,
+) -> Router {
+    let mut router = Router::new();
+    if let Some(metrics_path) = &config.telemetry.prometheus.path {
+        router.route("GET", metrics_path, PrometheusMetrics);
+    }
+    route_health_checks(&mut router, &config.health, stats.clone());
+    route_admin_api(&mut router, config, stats, static_files);
+
+    if let Err(e) = router.check_conflicts() {
+        error!(error = e.as_str(); "Conflicting admin routes");
+        force_export_telemetry(false);
+        panic!("Conflicting admin routes | {}", e);
+    }
+    return router;
+}
 
-    span.set_attribute(KeyValue::new("thread_id", thread_id as i64));
+fn handle_request(ctx: &RequestContext, thread_id: usize, conn: AcceptedConnection) {
+    let mut open_connection = ctx.stats.open_connection();
+    let peer = conn.peer();
+    let remote_address = peer.as_ref().and_then(Peer::socket_address);
+    let AcceptedConnection {
+        fd: conn_fd,
+        listener,
+    } = conn;
+    let listener_attributes = [KeyValue::new("listener", listener.name.clone())];
+    ctx.total_reqs.add(1, &listener_attributes);
+    // the span starts once the request is read, so these are collected until then
+    let mut span_attributes = vec![
+        KeyValue::new("thread_id", thread_id as i64),
+        KeyValue::new("listener", listener.name.clone()),
+        KeyValue::new("listener_address", listener.address.to_string()),
+    ];
     let mut is_warning = false;
 
-    let caller_addr = match getpeername::<SockaddrIn>(conn_fd.as_raw_fd()) {
-        Ok(sock_addr) => {
-            span.set_attribute(KeyValue::new("caller_address", sock_addr.to_string()));
+    let caller_addr = match peer {
+        Some(Peer::Inet(sock_addr)) => {
+            if let Some(address) = remote_address {
+                span_attributes.push(KeyValue::new("client.address", address.ip().to_string()));
+                span_attributes.push(KeyValue::new("client.port", address.port() as i64));
+            }
             sock_addr.to_string()
         }
-        Err(_) => {
+        Some(Peer::Unix(creds)) => {
+            span_attributes.push(KeyValue::new("caller_pid", creds.pid() as i64));
+            span_attributes.push(KeyValue::new("caller_uid", creds.uid() as i64));
+            span_attributes.push(KeyValue::new("caller_gid", creds.gid() as i64));
+            Peer::Unix(creds).to_string()
+        }
+        None => {
             is_warning = true;
             "don't know".to_string()
         }
     };
 
-    let req: Vec<u8> = match read_request(conn_fd.as_fd(), *poll_timeout) {
-        Ok(req) => req,
-        Err(SysCallError::Timeout) => return,
-        Err(SysCallError::Error(e)) => {
-            error!(thread_id = thread_id, errno = format!("{}", e.errno).as_str(); "{}", e.message);
-            return;
+    let socket = Socket::new(conn_fd, ctx.timeout);
+    let mut stream = match (listener.tls, socket) {
+        #[cfg(feature = "tls")]
+        (true, socket) => {
+            let tls = match &ctx.tls {
+                Some(tls) => tls,
+                // checked when the server is initialized
+                None => return,
+            };
+            match tls.accept(socket, &listener.name, ctx.timeout) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(thread_id = thread_id, listener = listener.name.as_str(), caller_address = caller_addr.as_str(), error = format!("{}", e).as_str(); "Skipping request - TLS handshake failed");
+                    return;
+                }
+            }
         }
+        (_, socket) => Stream::Plain(socket),
     };
-    let request_string = match str::from_utf8(req.as_slice()) {
-        Ok(s) => s,
-        Err(_) => {
-            is_warning = true;
-            "Couldn't convert"
+
+    #[cfg(feature = "tls")]
+    let client_subject = match client_identity(&stream) {
+        Some(identity) => {
+            span_attributes.push(KeyValue::new(
+                "tls.client.subject",
+                identity.subject.clone(),
+            ));
+            span_attributes.push(KeyValue::new(
+                "tls.client.subject_alt_names",
+                Value::Array(
+                    identity
+                        .subject_alt_names
+                        .into_iter()
+                        .map(StringValue::from)
+                        .collect::<Vec<StringValue>>()
+                        .into(),
+                ),
+            ));
+            Some(identity.subject)
         }
+        None => None,
     };
+    #[cfg(not(feature = "tls"))]
+    let client_subject: Option<String> = None;
 
-    let resp = build_response(req.clone(), serve_files);
-    if let Err(e) = send(conn_fd.as_raw_fd(), resp.as_bytes(), MsgFlags::empty()) {
-        error!(thread_id = thread_id, errno = format!("{}", e).as_str(); "Skipping request - could not send data to socket");
+    let started = Instant::now();
+    let read_started = SystemTime::now();
+    let (head, buffered) = match read_request(&mut stream, ctx.max_request_body_bytes, ctx.timeout)
+    {
+        Ok(parts) => parts,
+        Err(ReadError::Timeout) => return,
+        Err(ReadError::Error(e)) => {
+            error!(thread_id = thread_id, error = format!("{}", e.error).as_str(); "{}", e.message);
+            return;
+        }
+        Err(ReadError::Invalid(reason)) => {
+            warn!(thread_id = thread_id, caller_address = caller_addr.as_str(), error = reason.as_str(); "Invalid HTTP Request");
+            reject_request(
+                stream,
+                Response::text(400, "Bad Request"),
+                span_attributes,
+                read_started,
+            );
+            return;
+        }
+        Err(ReadError::TooLarge) => {
+            warn!(thread_id = thread_id, caller_address = caller_addr.as_str(); "Request body too large");
+            reject_request(
+                stream,
+                Response::text(413, "Content Too Large"),
+                span_attributes,
+                read_started,
+            );
+            return;
+        }
+        Err(ReadError::UnsupportedEncoding) => {
+            warn!(thread_id = thread_id, caller_address = caller_addr.as_str(); "Unsupported request transfer encoding");
+            reject_request(
+                stream,
+                Response::text(501, "Not Implemented"),
+                span_attributes,
+                read_started,
+            );
+            return;
+        }
+        Err(ReadError::HeadTooLarge) => {
+            warn!(thread_id = thread_id, caller_address = caller_addr.as_str(); "Request head too large");
+            reject_request(
+                stream,
+                Response::text(431, "Request Header Fields Too Large"),
+                span_attributes,
+                read_started,
+            );
+            return;
+        }
+    };
+    let body = RequestBody::new(stream, buffered, &head, ctx.max_request_body_bytes);
+    let mut req = head.into_request(body.clone());
+    req.remote_address = remote_address;
+    req.secure = listener.tls;
+    let active_attributes = [
+        KeyValue::new("http.request.method", method_attribute(&req.method)),
+        KeyValue::new("url.scheme", if req.secure { "https" } else { "http" }),
+    ];
+    ctx.active_requests.add(1, &active_attributes);
+
+    let tracer = get_tracer();
+    let is_probe = ctx.health.is_probe(&req.path);
+    span_attributes.extend(request_span_attributes(&req, &ctx.redactor));
+    // continues the trace of a browser or gateway that sent a traceparent
+    let parent_cx = extract_context(&req.headers);
+    let mut span_builder = tracer
+        .span_builder(span_name(&req.method, None))
+        .with_kind(SpanKind::Server)
+        .with_start_time(read_started)
+        .with_attributes(span_attributes);
+    if is_probe && !ctx.health.sample {
+        span_builder = span_builder.with_sampling_result(SamplingResult {
+            decision: SamplingDecision::Drop,
+            attributes: Vec::new(),
+            trace_state: TraceState::default(),
+        });
+    }
+    let cx = parent_cx.with_span(span_builder.start_with_context(tracer, &parent_cx));
+    let span = cx.span();
+    tracer
+        .span_builder("read")
+        .with_start_time(read_started)
+        .start_with_context(tracer, &cx)
+        .end();
+
+    // current while handling, so spans the handlers start are children of it
+    let handle_cx = cx.with_span(tracer.start_with_context("handle", &cx));
+    let mut resp = {
+        let _guard = handle_cx.clone().attach();
+        ctx.middleware
+            .run(&mut req, &|req: &mut Request| match &listener.redirect {
+                Some(redirect) => build_redirect_response(req, redirect),
+                None if listener.admin => ctx.admin_router.handle(req),
+                None => ctx.router.handle(req),
+            })
+    };
+    handle_cx.span().end();
+    // every upgraded connection holds a thread until it closes, past the limit clients retry later
+    if resp.upgrade.is_some() && !open_connection.upgrade(ctx.max_upgraded_connections) {
+        warn!(thread_id = thread_id, caller_address = caller_addr.as_str(), max_upgraded_connections = ctx.max_upgraded_connections; "Rejecting upgrade - too many upgraded connections");
+        let mut rejected =
+            Response::text(503, "Service Unavailable").with_header("Retry-After", "1");
+        if let Some(request_id) = resp.header(REQUEST_ID_HEADER) {
+            rejected.set_header(REQUEST_ID_HEADER, request_id);
+        }
+        resp = rejected;
+    }
+    // the connection is only shared with handlers through the body
+    let (mut stream, unread) = match body.take_connection() {
+        Some(connection) => connection,
+        None => {
+            ctx.active_requests.add(-1, &active_attributes);
+            error!(thread_id = thread_id; "Skipping request - the connection was taken by the handler");
+            return;
+        }
+    };
+    if let Some(route) = &req.route {
+        span.update_name(
+            route
+                .span_name
+                .clone()
+                .unwrap_or_else(|| span_name(&req.method, Some(&route.pattern))),
+        );
+        span.set_attribute(KeyValue::new("http.route", route.pattern.clone()));
+    }
+    let request_id = req.header(REQUEST_ID_HEADER).map(|id| id.to_string());
+    if let Some(request_id) = &request_id {
+        span.set_attribute(KeyValue::new("request_id", request_id.clone()));
+    }
+    #[cfg(feature = "tls")]
+    if listener.tls
+        && let Some(hsts) = ctx.tls.as_ref().and_then(|tls| tls.hsts_header())
+    {
+        resp.set_header("Strict-Transport-Security", hsts);
+    }
+    if ctx.trace_response {
+        resp.set_header("traceresponse", &trace_response(&cx));
+    }
+    let mut write_span = tracer.start_with_context("write", &cx);
+    let write_result = resp.write_to(&mut stream);
+    write_span.end();
+    ctx.active_requests.add(-1, &active_attributes);
+    record_request_metrics(ctx, &req, &resp, started, &write_result);
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        resp.status as i64,
+    ));
+    match &write_result {
+        Err(e) => {
+            span.set_attribute(KeyValue::new("error.type", format!("{:?}", e.kind())));
+            span.set_status(Status::error(e.to_string()));
+        }
+        // 4xx responses are the client's error, so they leave the server span unset
+        Ok(_) if resp.status >= 500 => {
+            span.set_attribute(KeyValue::new("error.type", resp.status.to_string()));
+            span.set_status(Status::error(""));
+        }
+        Ok(_) => (),
+    }
+    // upgraded connections can stay open for hours, the span covers the request and response head
+    span.end();
+    if !is_probe || ctx.health.access_log {
+        ctx.access_log.log(&AccessLogEntry {
+            time: read_started,
+            remote_address: remote_address
+                .map(|address| address.ip().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            listener: &listener.name,
+            request: &req,
+            status: resp.status,
+            body_bytes: *write_result.as_ref().unwrap_or(&0),
+            duration: started.elapsed(),
+            request_id: request_id.as_deref(),
+        });
+    }
+    if let Err(e) = write_result {
+        error!(thread_id = thread_id, error = format!("{}", e).as_str(); "Skipping request - could not send data to socket");
         return;
     };
+    match resp.upgrade.take() {
+        // upgraded connections and event streams can stay open for hours, so they get their own thread
+        Some(upgrade) => {
+            std::thread::spawn(move || {
+                upgrade(stream, unread);
+                drop(open_connection);
+            });
+        }
+        None => stream.close(),
+    }
 
-    success_counter.add(1, &[]);
+    ctx.finished_reqs.add(1, &listener_attributes);
+    // the access log has the request itself
     if is_warning {
         warn!(
             thread_id = thread_id,
+            listener = listener.name.as_str(),
             caller_address = caller_addr.as_str(),
-            request = request_string;
-            /*response = *resp.as_str();*/
+            client_subject = client_subject.as_deref(),
+            request_id = request_id.as_deref();
             "Request handled with warnings"
         );
-    } else {
-        info!(
-            thread_id = thread_id,
-            caller_address = caller_addr.as_str(),
-            request = request_string;
-            /*response = *resp.as_str();*/
-            "Request successfully handled"
-        );
     }
     return;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream, thread};
+
+    use super::*;
+
+    fn test_stream() -> (Stream, UnixStream) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let socket = Socket::new(OwnedFd::from(server), Duration::from_millis(200));
+        return (Stream::Plain(socket), client);
+    }
+
+    #[test]
+    fn reads_the_head_and_body() {
+        let (mut stream, mut client) = test_stream();
+        client
+            .write_all(b"POST /items HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+        let (head, buffered) = match read_request(&mut stream, 1024, Duration::from_secs(1)) {
+            Ok(parts) => parts,
+            Err(_) => panic!("the request should be read"),
+        };
+        let body = RequestBody::new(stream, buffered, &head, 1024);
+        assert_eq!(head.into_request(body.clone()).path, "/items");
+        assert_eq!(body.to_bytes().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn bytes_after_the_body_are_handed_back_with_the_connection() {
+        let (mut stream, mut client) = test_stream();
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n\x81\x80frame")
+            .unwrap();
+        let (head, buffered) = match read_request(&mut stream, 1024, Duration::from_secs(1)) {
+            Ok(parts) => parts,
+            Err(_) => panic!("the request should be read"),
+        };
+        let body = RequestBody::new(stream, buffered, &head, 1024);
+        assert!(body.to_bytes().unwrap().is_empty());
+        let (_, unread) = body.take_connection().unwrap();
+        assert_eq!(unread, b"\x81\x80frame");
+    }
+
+    #[test]
+    fn oversized_heads_are_rejected() {
+        let (mut stream, mut client) = test_stream();
+        let writer = thread::spawn(move || {
+            let header = format!("X-Filler: {}\r\n", "a".repeat(1000));
+            let _ = client.write_all(b"GET / HTTP/1.1\r\n");
+            for _ in 0..(MAX_REQUEST_HEAD_BYTES / header.len() + 2) {
+                if client.write_all(header.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+        let result = read_request(&mut stream, 1024, Duration::from_secs(5));
+        assert!(matches!(result, Err(ReadError::HeadTooLarge)));
+        drop(stream);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn heads_sent_a_byte_at_a_time_hit_the_deadline() {
+        let (mut stream, mut client) = test_stream();
+        let writer = thread::spawn(move || {
+            // every byte arrives well within the socket timeout
+            for byte in b"GET / HTTP/1.1\r\nHost: slow\r\nX-Trickle: 1\r\n\r\n" {
+                if client.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                sleep(Duration::from_millis(50));
+            }
+        });
+        let started = Instant::now();
+        let result = read_request(&mut stream, 1024, Duration::from_millis(300));
+        assert!(matches!(result, Err(ReadError::Invalid(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(stream);
+        writer.join().unwrap();
+    }
+}
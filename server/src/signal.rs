@@ -6,13 +6,20 @@ use nix::{
     sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction},
 };
 
-use crate::statics::SHUTDOWN_SERVER;
+#[cfg(feature = "tls")]
+use crate::statics::RELOAD_CERTIFICATES;
+use crate::statics::{DRAINING, REOPEN_ACCESS_LOG, SHUTDOWN_SERVER};
 use crate::telemetry::force_export_telemetry;
+use std::sync::atomic::Ordering;
 
 // NOTE Start:
 // not sure how to handle logging with signals
 // don't know if telemetry export function is signal safe
 extern "C" fn sig_handler(_signal: c_int) {
+    // the first signal starts draining, the accept loop shuts down once it's done
+    if !DRAINING.swap(true, Ordering::SeqCst) {
+        return;
+    }
     match SHUTDOWN_SERVER.write() {
         Ok(mut guard) => {
             if *guard {
@@ -27,6 +34,15 @@ extern "C" fn sig_handler(_signal: c_int) {
     }
 }
 
+#[cfg(feature = "tls")]
+extern "C" fn reload_sig_handler(_signal: c_int) {
+    RELOAD_CERTIFICATES.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn reopen_sig_handler(_signal: c_int) {
+    REOPEN_ACCESS_LOG.store(true, Ordering::SeqCst);
+}
+
 pub fn setup_sig_handler() {
     let sig_act = SigAction::new(
         SigHandler::Handler(sig_handler),
@@ -34,10 +50,38 @@ pub fn setup_sig_handler() {
         SigSet::empty(),
     );
 
-    if let Err(e) = unsafe { sigaction(Signal::SIGINT, &sig_act) } {
-        error!(errno = format!("{}", e).as_str(); "Could not set up signal handler");
+    // SIGTERM is what process managers and container runtimes send, ex: systemd and Kubernetes
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        if let Err(e) = unsafe { sigaction(signal, &sig_act) } {
+            error!(errno = format!("{}", e).as_str(), signal = signal.as_str(); "Could not set up signal handler");
+            force_export_telemetry(false);
+            panic!("Could not set up {} handler | {}", signal, e);
+        };
+    }
+
+    let reopen_act = SigAction::new(
+        SigHandler::Handler(reopen_sig_handler),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    if let Err(e) = unsafe { sigaction(Signal::SIGUSR1, &reopen_act) } {
+        error!(errno = format!("{}", e).as_str(); "Could not set up SIGUSR1 handler");
         force_export_telemetry(false);
-        panic!("Could not set up signal handler | {}", e);
+        panic!("Could not set up SIGUSR1 handler | {}", e);
     };
+
+    #[cfg(feature = "tls")]
+    {
+        let reload_act = SigAction::new(
+            SigHandler::Handler(reload_sig_handler),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        if let Err(e) = unsafe { sigaction(Signal::SIGHUP, &reload_act) } {
+            error!(errno = format!("{}", e).as_str(); "Could not set up SIGHUP handler");
+            force_export_telemetry(false);
+            panic!("Could not set up SIGHUP handler | {}", e);
+        };
+    }
 }
 // NOTE End:
@@ -0,0 +1,283 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TrySendError, bounded};
+use log::{info, warn};
+use opentelemetry::{KeyValue, global, metrics::UpDownCounter};
+use serde::Deserialize;
+
+use crate::{
+    connection::Stream,
+    http::{Request, Response},
+    router::Handler,
+};
+
+// events kept for clients resuming with Last-Event-ID
+const HISTORY_SIZE: usize = 256;
+// subscribers that fall this many events behind are dropped, they resume once they reconnect
+const SUBSCRIBER_BUFFER: usize = 64;
+
+// events the application publishes to every client, served when `events.path` is set
+pub static SERVER_EVENTS: LazyLock<Arc<EventHub>> =
+    LazyLock::new(|| Arc::new(EventHub::new(HISTORY_SIZE)));
+// operator events naming internal addresses, ex: upstreams going down, only the admin API serves them
+pub static ADMIN_EVENTS: LazyLock<Arc<EventHub>> =
+    LazyLock::new(|| Arc::new(EventHub::new(HISTORY_SIZE)));
+
+static OPEN_STREAMS: LazyLock<UpDownCounter<i64>> = LazyLock::new(|| {
+    global::meter("events")
+        .i64_up_down_counter("open_streams")
+        .with_description("Server-sent event streams currently open")
+        .build()
+});
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    // serves SERVER_EVENTS at this path, ex: "/events"
+    pub path: Option<String>,
+    // comment lines sent when no events were, keeps proxies from closing idle streams
+    pub heartbeat_interval_ms: u64,
+    // how long clients wait before reconnecting
+    pub retry_ms: u64,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        return EventsConfig {
+            path: None,
+            heartbeat_interval_ms: 15_000,
+            retry_ms: 3000,
+        };
+    }
+}
+
+pub struct Event {
+    pub id: u64,
+    pub event: String,
+    pub data: String,
+}
+
+impl Event {
+    // every line of data gets its own field, the client joins them back with newlines
+    fn encode(&self) -> String {
+        let mut encoded = format!("id: {}\nevent: {}\n", self.id, self.event);
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        encoded.push('\n');
+        return encoded;
+    }
+}
+
+struct HubState {
+    next_id: u64,
+    // set at shutdown, streams end once they sent what was published before
+    closed: bool,
+    history: VecDeque<Arc<Event>>,
+    subscribers: Vec<Sender<Arc<Event>>>,
+}
+
+// broadcasts published events to every open stream, ids are assigned in publish order
+pub struct EventHub {
+    history_size: usize,
+    state: Mutex<HubState>,
+}
+
+impl EventHub {
+    pub fn new(history_size: usize) -> Self {
+        return EventHub {
+            history_size,
+            state: Mutex::new(HubState {
+                next_id: 1,
+                closed: false,
+                history: VecDeque::new(),
+                subscribers: Vec::new(),
+            }),
+        };
+    }
+
+    // `event` is the event type, it must be a single line
+    pub fn publish(&self, event: &str, data: &str) -> u64 {
+        let mut state = match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let event = Arc::new(Event {
+            id: state.next_id,
+            event: event.replace(['\r', '\n'], " "),
+            data: data.to_string(),
+        });
+        state.next_id += 1;
+
+        state.history.push_back(event.clone());
+        if state.history.len() > self.history_size {
+            state.history.pop_front();
+        }
+        state
+            .subscribers
+            .retain(|subscriber| match subscriber.try_send(event.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(event_id = event.id; "Dropping event stream that fell behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+        return event.id;
+    }
+
+    // the events after `last_event_id` still in the history, then a receiver for new ones
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> (Vec<Arc<Event>>, Receiver<Arc<Event>>) {
+        let mut state = match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let missed = match last_event_id {
+            Some(last_event_id) => state
+                .history
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let (sender, receiver) = bounded(SUBSCRIBER_BUFFER);
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+        return (missed, receiver);
+    }
+
+    // tells every client the server is going away, then ends their streams
+    pub fn close(&self) {
+        self.publish("shutdown", "");
+        let mut state = match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.closed = true;
+        state.subscribers.clear();
+    }
+}
+
+/*
+Registered on a GET route, answers with a text/event-stream that stays open and
+forwards everything published to the hub, ex:
+router.route("GET", "/events", EventStream::new(SERVER_EVENTS.clone(), &config.events))
+*/
+pub struct EventStream {
+    hub: Arc<EventHub>,
+    heartbeat_interval: Duration,
+    retry_ms: u64,
+}
+
+impl EventStream {
+    pub fn new(hub: Arc<EventHub>, config: &EventsConfig) -> Self {
+        return EventStream {
+            hub,
+            heartbeat_interval: Duration::from_millis(config.heartbeat_interval_ms),
+            retry_ms: config.retry_ms,
+        };
+    }
+}
+
+impl Handler for EventStream {
+    fn handle(&self, req: &Request) -> Response {
+        // sent by browsers when they reconnect
+        let last_event_id = req
+            .header("last-event-id")
+            .and_then(|id| id.trim().parse::<u64>().ok());
+
+        let mut resp = Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            // keeps nginx style proxies from buffering the stream
+            .with_header("X-Accel-Buffering", "no");
+
+        let hub = self.hub.clone();
+        let heartbeat_interval = self.heartbeat_interval;
+        let retry_ms = self.retry_ms;
+        let path = req.path.clone();
+        // clients don't send anything once the stream started
+        resp.upgrade = Some(Box::new(move |mut stream, _received| {
+            let attributes = [KeyValue::new("path", path.clone())];
+            OPEN_STREAMS.add(1, &attributes);
+            let (missed, events) = hub.subscribe(last_event_id);
+            let result = stream_events(&mut stream, missed, events, heartbeat_interval, retry_ms);
+            stream.close();
+            OPEN_STREAMS.add(-1, &attributes);
+            match result {
+                Ok(_) => info!(path = path.as_str(); "Event stream closed"),
+                Err(e) => {
+                    info!(path = path.as_str(), reason = e.to_string().as_str(); "Event stream disconnected")
+                }
+            }
+        }));
+        return resp;
+    }
+}
+
+// runs until the client goes away or the hub is closed at shutdown
+fn stream_events(
+    stream: &mut Stream,
+    missed: Vec<Arc<Event>>,
+    events: Receiver<Arc<Event>>,
+    heartbeat_interval: Duration,
+    retry_ms: u64,
+) -> io::Result<()> {
+    let mut preamble = format!("retry: {}\n\n", retry_ms);
+    for event in missed {
+        preamble.push_str(&event.encode());
+    }
+    stream.write_all(preamble.as_bytes())?;
+    stream.flush()?;
+
+    let mut last_write = Instant::now();
+    loop {
+        let wait = heartbeat_interval
+            .saturating_sub(last_write.elapsed())
+            .min(Duration::from_secs(1));
+        let message = match events.recv_timeout(wait) {
+            Ok(event) => event.encode(),
+            Err(RecvTimeoutError::Timeout) if last_write.elapsed() >= heartbeat_interval => {
+                ": heartbeat\n\n".to_string()
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            // the hub was closed or dropped this stream for falling behind,
+            // events queued before that were already received
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+        last_write = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_the_hub_ends_streams_after_a_shutdown_event() {
+        let hub = EventHub::new(HISTORY_SIZE);
+        let (_, events) = hub.subscribe(None);
+        hub.publish("status", "up");
+        hub.close();
+
+        let received = events
+            .iter()
+            .map(|event| event.event.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(received, ["status", "shutdown"]);
+
+        // clients connecting during shutdown still get the history, then the stream ends
+        let (missed, events) = hub.subscribe(Some(0));
+        assert_eq!(missed.len(), 2);
+        assert!(events.recv().is_err());
+    }
+}
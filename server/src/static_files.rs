@@ -0,0 +1,200 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use log::{error, info};
+
+use crate::{
+    http::{Body, Request, Response},
+    init::{get_static_file_paths, read_static_file_paths},
+    router::Handler,
+    telemetry::force_export_telemetry,
+};
+
+// serves the files found under `root` at startup, registered on `GET /**path`
+pub struct StaticFiles {
+    root: PathBuf,
+    // replaced by reloads from the admin API
+    files: RwLock<HashSet<PathBuf>>,
+}
+
+impl StaticFiles {
+    pub fn new(root: PathBuf) -> Self {
+        let files = get_static_file_paths(root.clone());
+        if files.is_empty() {
+            error!("No static files found");
+            force_export_telemetry(false);
+            panic!("No static files found")
+        }
+        info!(
+            static_files_location = root.display().to_string().as_str(),
+            static_file_count = files.len();
+            "Static files loaded"
+        );
+
+        return StaticFiles {
+            root,
+            files: RwLock::new(files),
+        };
+    }
+
+    // walks the root again, the old index is kept if that fails or finds nothing
+    pub fn reload(&self) -> Result<usize, String> {
+        let files = read_static_file_paths(self.root.clone()).map_err(|e| e.to_string())?;
+        if files.is_empty() {
+            return Err("No static files found".to_string());
+        }
+        let file_count = files.len();
+        match self.files.write() {
+            Ok(mut guard) => *guard = files,
+            Err(poisoned) => *poisoned.into_inner() = files,
+        }
+        info!(
+            static_files_location = self.root.display().to_string().as_str(),
+            static_file_count = file_count;
+            "Static files reloaded"
+        );
+        return Ok(file_count);
+    }
+
+    // served paths relative to the root, sorted
+    pub fn paths(&self) -> Vec<String> {
+        let files = match self.files.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut paths = files
+            .iter()
+            .filter_map(|path| path.strip_prefix(&self.root).ok())
+            .map(|path| path.display().to_string())
+            .collect::<Vec<String>>();
+        paths.sort();
+        return paths;
+    }
+
+    fn contains(&self, path: &PathBuf) -> bool {
+        return match self.files.read() {
+            Ok(guard) => guard.contains(path),
+            Err(poisoned) => poisoned.into_inner().contains(path),
+        };
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, req: &Request) -> Response {
+        let requested_path = match req.param("path") {
+            Some("") | None => self.root.join("index.html"),
+            Some(path) => self.root.join(path),
+        };
+        // only files found at startup are served, this also keeps `..` out of the root
+        if !self.contains(&requested_path) {
+            return Response::text(404, "Resource Not Found");
+        }
+
+        let (file, file_length) = match fs::File::open(&requested_path)
+            .and_then(|file| file.metadata().map(|metadata| (file, metadata.len())))
+        {
+            Ok(opened) => opened,
+            Err(e) => {
+                error!(error = format!("{}", e).as_str(); "Could Not Read File");
+                return Response::text(500, "Internal Server Error | Could Not Read File");
+            }
+        };
+
+        let range = match req.header("range") {
+            Some(range) => parse_range(range, file_length),
+            None => ByteRange::Whole,
+        };
+        let (status, offset, length) = match range {
+            ByteRange::Whole => (200, 0, file_length),
+            ByteRange::Partial { offset, length } => (206, offset, length),
+            ByteRange::Unsatisfiable => {
+                return Response::text(416, "Range Not Satisfiable")
+                    .with_header("Content-Range", &format!("bytes */{}", file_length));
+            }
+        };
+        let mut resp = Response::new(status)
+            .with_header("Content-Type", content_type(&requested_path))
+            .with_header("Accept-Ranges", "bytes");
+        if status == 206 {
+            let content_range = format!("bytes {}-{}/{}", offset, offset + length - 1, file_length);
+            resp.set_header("Content-Range", &content_range);
+        }
+        resp.body = Body::File {
+            file,
+            offset,
+            length,
+        };
+        return resp;
+    }
+}
+
+enum ByteRange {
+    Whole,
+    Partial { offset: u64, length: u64 },
+    Unsatisfiable,
+}
+
+// only single ranges are served, the whole file is sent for multiple or malformed ones
+fn parse_range(header: &str, file_length: u64) -> ByteRange {
+    let range = match header.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range,
+        _ => return ByteRange::Whole,
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Whole,
+    };
+
+    let (offset, last) = if start.is_empty() {
+        // suffix range, ex: "bytes=-500" is the last 500 bytes
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (
+                file_length.saturating_sub(suffix),
+                file_length.saturating_sub(1),
+            ),
+            Err(_) => return ByteRange::Whole,
+        }
+    } else {
+        let offset = match start.parse::<u64>() {
+            Ok(offset) => offset,
+            Err(_) => return ByteRange::Whole,
+        };
+        let last = match end {
+            "" => u64::MAX,
+            end => match end.parse::<u64>() {
+                Ok(last) if last >= offset => last,
+                _ => return ByteRange::Whole,
+            },
+        };
+        (offset, last.min(file_length.saturating_sub(1)))
+    };
+    if offset >= file_length {
+        return ByteRange::Unsatisfiable;
+    }
+    return ByteRange::Partial {
+        offset,
+        length: last - offset + 1,
+    };
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ex| ex.to_str()) {
+        Some("html") => "text/html; charset=UTF-8",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=UTF-8",
+        None => "application/json",
+        _ => "application/octet-stream",
+    }
+}
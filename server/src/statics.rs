@@ -1,14 +1,28 @@
-use std::sync::{LazyLock, OnceLock, RwLock};
+use std::sync::{LazyLock, OnceLock, RwLock, atomic::AtomicBool};
 
 use opentelemetry::global::BoxedTracer;
 use opentelemetry_sdk::{
     Resource, logs::SdkLoggerProvider, metrics::SdkMeterProvider, trace::SdkTracerProvider,
 };
 
+use tokio::runtime::Runtime;
+
+use crate::prometheus::PrometheusReader;
+
 pub static SHUTDOWN_SERVER: RwLock<bool> = RwLock::new(false);
+// set by the first SIGINT or SIGTERM, requests are served until `health.drain_ms` runs out
+pub static DRAINING: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "tls")]
+pub static RELOAD_CERTIFICATES: AtomicBool = AtomicBool::new(false);
+// set by SIGUSR1, the access log file is reopened after logrotate moves it
+pub static REOPEN_ACCESS_LOG: AtomicBool = AtomicBool::new(false);
 pub static METER_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
 pub static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
 pub static LOGGER_PROVIDER: OnceLock<SdkLoggerProvider> = OnceLock::new();
+// set when Prometheus scrapes are enabled
+pub static PROMETHEUS_READER: OnceLock<PrometheusReader> = OnceLock::new();
+// drives the OTLP exporters' connections, started when one of them is configured
+pub static OTLP_RUNTIME: OnceLock<Runtime> = OnceLock::new();
 pub static TELEMETRY_CONFIG: LazyLock<Resource> =
     LazyLock::new(|| Resource::builder().with_service_name("http_server").build());
 
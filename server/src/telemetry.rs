@@ -1,20 +1,93 @@
-use log::{LevelFilter, error, warn};
-use opentelemetry::global::{self, BoxedTracer};
+use log::{LevelFilter, Log, Metadata, Record, error, warn};
+use opentelemetry::{
+    Context,
+    global::{self, BoxedTracer},
+    propagation::{Extractor, Injector, TextMapCompositePropagator},
+    trace::TraceContextExt,
+};
 use opentelemetry_appender_log::OpenTelemetryLogBridge;
 use opentelemetry_sdk::{
     error::OTelSdkError::{self, AlreadyShutdown, InternalFailure, Timeout},
-    logs::{BatchLogProcessor, SdkLoggerProvider},
-    metrics::SdkMeterProvider,
-    trace::SdkTracerProvider,
+    logs::{self, BatchLogProcessor, SdkLoggerProvider},
+    metrics::{PeriodicReader, SdkMeterProvider},
+    propagation::{BaggagePropagator, TraceContextPropagator},
+    trace::{self, BatchSpanProcessor, SdkTracerProvider, TracerProviderBuilder},
 };
 use opentelemetry_stdout::{LogExporter, MetricExporter, SpanExporter};
 
-use crate::statics::{LOGGER_PROVIDER, METER_PROVIDER, TELEMETRY_CONFIG, TRACER, TRACER_PROVIDER};
+use crate::{
+    otlp::{
+        ExporterConfig, ExporterKind, TelemetryConfig, is_export_thread, log_exporter,
+        metric_exporter, span_exporter,
+    },
+    prometheus::{PrometheusConfig, PrometheusReader},
+    sampling::{RequestSampler, SamplerConfig, with_sampled_span_processor},
+    statics::{
+        LOGGER_PROVIDER, METER_PROVIDER, PROMETHEUS_READER, TELEMETRY_CONFIG, TRACER,
+        TRACER_PROVIDER,
+    },
+};
 
 pub fn get_tracer() -> &'static BoxedTracer {
     TRACER.get_or_init(|| global::tracer("http_server"))
 }
 
+// reads propagated context from request headers, names are matched case insensitively
+struct HeaderExtractor<'a>(&'a [(String, String)]);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut Vec<(String, String)>);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_string(), value));
+    }
+}
+
+// the caller's trace and baggage, an empty context when the request has none
+pub fn extract_context(headers: &[(String, String)]) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+// headers that continue the context's trace in an outgoing request
+pub fn inject_context(cx: &Context) -> Vec<(String, String)> {
+    let mut headers = Vec::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(cx, &mut HeaderInjector(&mut headers))
+    });
+    return headers;
+}
+
+// header names the propagators read and write, ex: "traceparent"
+pub fn propagation_fields() -> Vec<String> {
+    global::get_text_map_propagator(|propagator| {
+        propagator.fields().map(|field| field.to_string()).collect()
+    })
+}
+
+// the context's span as a W3C traceresponse header value
+pub fn trace_response(cx: &Context) -> String {
+    let span_context = cx.span().span_context().clone();
+    return format!(
+        "00-{}-{}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    );
+}
+
 // this is a global flush function for all contexts
 pub fn force_export_telemetry(is_retry: bool) {
     let mut should_retry = false;
@@ -26,7 +99,7 @@ pub fn force_export_telemetry(is_retry: bool) {
                 warn!(timeout = d.as_millis() ;"Force metric export timed out")
             }
             Err(InternalFailure(e)) => {
-                error!(error = format!("{}", e).as_str(); "Internal failure occured force exporting metrics")
+                error!(error = e.as_str(); "Internal failure occured force exporting metrics")
             }
         },
         None => {
@@ -42,7 +115,7 @@ pub fn force_export_telemetry(is_retry: bool) {
                 warn!(timeout = d.as_millis() ;"Force trace export timed out")
             }
             Err(InternalFailure(e)) => {
-                error!(error = format!("{}", e).as_str(); "Internal failure occured force exporting traces")
+                error!(error = e.as_str(); "Internal failure occured force exporting traces")
             }
         },
         None => {
@@ -58,7 +131,7 @@ pub fn force_export_telemetry(is_retry: bool) {
                 warn!(timeout = d.as_millis() ;"Force log export timed out")
             }
             Err(InternalFailure(e)) => {
-                error!(error = format!("{}", e).as_str(); "Internal failure occured force exporting logs")
+                error!(error = e.as_str(); "Internal failure occured force exporting logs")
             }
         },
         None => {
@@ -119,56 +192,148 @@ pub fn shutdown_telemetry(
     };
 }
 
-pub fn init_telemetry() -> (SdkLoggerProvider, SdkMeterProvider, SdkTracerProvider) {
-    return (init_logger(), init_meter(), init_tracer());
+pub fn init_telemetry(
+    config: &TelemetryConfig,
+) -> (SdkLoggerProvider, SdkMeterProvider, SdkTracerProvider) {
+    return (
+        init_logger(&config.logs),
+        init_meter(&config.metrics, &config.prometheus),
+        init_tracer(&config.traces, &config.sampler),
+    );
 }
 
-fn init_logger() -> SdkLoggerProvider {
-    let log_exporter = LogExporter::default();
-    let log_processor = BatchLogProcessor::builder(log_exporter).build();
-    let logger_provider = SdkLoggerProvider::builder()
-        .with_log_processor(log_processor)
-        .with_resource(TELEMETRY_CONFIG.clone())
+fn init_logger(config: &ExporterConfig) -> SdkLoggerProvider {
+    let batch_config = logs::BatchConfigBuilder::default()
+        .with_max_queue_size(config.batch.max_queue_size)
+        .with_max_export_batch_size(config.batch.max_export_batch_size)
+        .with_scheduled_delay(config.batch.scheduled_delay())
         .build();
-    let log_bridge = OpenTelemetryLogBridge::new(&logger_provider);
+    let builder = SdkLoggerProvider::builder().with_resource(TELEMETRY_CONFIG.clone());
+    let builder = match config.exporter {
+        ExporterKind::Stdout => builder.with_log_processor(
+            BatchLogProcessor::builder(LogExporter::default())
+                .with_batch_config(batch_config)
+                .build(),
+        ),
+        ExporterKind::OtlpGrpc | ExporterKind::OtlpHttp => builder.with_log_processor(
+            BatchLogProcessor::builder(log_exporter(config))
+                .with_batch_config(batch_config)
+                .build(),
+        ),
+        ExporterKind::None => builder,
+    };
+    let logger_provider = builder.build();
+    let log_bridge = ExportLogFilter {
+        inner: OpenTelemetryLogBridge::new(&logger_provider),
+    };
 
     if let Err(e) = log::set_boxed_logger(Box::new(log_bridge)) {
         panic!("Couldn't set up logger | {}", e)
     }
     log::set_max_level(LevelFilter::max());
 
-    if let Err(_) = LOGGER_PROVIDER.set(logger_provider.clone()) {
+    if LOGGER_PROVIDER.set(logger_provider.clone()).is_err() {
         panic!("Logger provider was already set");
     }
 
     return logger_provider;
 }
 
-fn init_meter() -> SdkMeterProvider {
-    let metric_exporter = MetricExporter::default();
-    let meter_provider = SdkMeterProvider::builder()
-        .with_periodic_exporter(metric_exporter)
-        .with_resource(TELEMETRY_CONFIG.clone())
+// exporting on every span end would hold up the request workers, so spans are queued
+fn with_batch_exporter<E: trace::SpanExporter + 'static>(
+    builder: TracerProviderBuilder,
+    exporter: E,
+    config: &ExporterConfig,
+    sampler: &SamplerConfig,
+) -> TracerProviderBuilder {
+    let batch_config = trace::BatchConfigBuilder::default()
+        .with_max_queue_size(config.batch.max_queue_size)
+        .with_max_export_batch_size(config.batch.max_export_batch_size)
+        .with_scheduled_delay(config.batch.scheduled_delay())
+        .build();
+    let processor = BatchSpanProcessor::builder(exporter)
+        .with_batch_config(batch_config)
         .build();
+    return with_sampled_span_processor(builder, processor, sampler, config.batch.max_queue_size);
+}
+
+// leaves out the exporters' own records, see `is_export_thread`
+struct ExportLogFilter<L: Log> {
+    inner: L,
+}
+
+impl<L: Log> Log for ExportLogFilter<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        !is_export_thread() && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn init_meter(config: &ExporterConfig, prometheus: &PrometheusConfig) -> SdkMeterProvider {
+    let mut builder = SdkMeterProvider::builder().with_resource(TELEMETRY_CONFIG.clone());
+    // scrapes collect on their own, alongside whichever exporter pushes metrics
+    if prometheus.path.is_some() {
+        let reader = PrometheusReader::default();
+        builder = builder.with_reader(reader.clone());
+        if PROMETHEUS_READER.set(reader).is_err() {
+            panic!("Prometheus reader was already set");
+        }
+    }
+    let builder = match config.exporter {
+        ExporterKind::Stdout => builder.with_reader(
+            PeriodicReader::builder(MetricExporter::default())
+                .with_interval(config.batch.scheduled_delay())
+                .build(),
+        ),
+        ExporterKind::OtlpGrpc | ExporterKind::OtlpHttp => builder.with_reader(
+            PeriodicReader::builder(metric_exporter(config))
+                .with_interval(config.batch.scheduled_delay())
+                .build(),
+        ),
+        ExporterKind::None => builder,
+    };
+    let meter_provider = builder.build();
 
     global::set_meter_provider(meter_provider.clone());
 
-    if let Err(_) = METER_PROVIDER.set(meter_provider.clone()) {
+    if METER_PROVIDER.set(meter_provider.clone()).is_err() {
         panic!("Metrics provider was already set");
     }
 
     return meter_provider;
 }
 
-fn init_tracer() -> SdkTracerProvider {
-    let span_exporter = SpanExporter::default();
-    let tracer_provider = SdkTracerProvider::builder()
-        .with_simple_exporter(span_exporter)
+fn init_tracer(config: &ExporterConfig, sampler: &SamplerConfig) -> SdkTracerProvider {
+    let builder = SdkTracerProvider::builder()
         .with_resource(TELEMETRY_CONFIG.clone())
-        .build();
+        .with_sampler(RequestSampler::new(sampler));
+    let builder = match config.exporter {
+        ExporterKind::Stdout => {
+            with_batch_exporter(builder, SpanExporter::default(), config, sampler)
+        }
+        ExporterKind::OtlpGrpc | ExporterKind::OtlpHttp => {
+            with_batch_exporter(builder, span_exporter(config), config, sampler)
+        }
+        ExporterKind::None => builder,
+    };
+    let tracer_provider = builder.build();
     global::set_tracer_provider(tracer_provider.clone());
+    // W3C traceparent, tracestate and baggage headers
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ]));
 
-    if let Err(_) = TRACER_PROVIDER.set(tracer_provider.clone()) {
+    if TRACER_PROVIDER.set(tracer_provider.clone()).is_err() {
         panic!("Trace provider was already set");
     }
 
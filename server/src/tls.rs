@@ -0,0 +1,851 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use opentelemetry::{KeyValue, global, metrics::Counter};
+use rustls::{
+    RootCertStore, ServerConfig as RustlsServerConfig, ServerConnection, StreamOwned,
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier, danger::ClientCertVerifier},
+    sign::CertifiedKey,
+};
+use serde::Deserialize;
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::{
+    connection::{Socket, Stream},
+    telemetry::force_export_telemetry,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub certificates: Vec<CertificateConfig>,
+    // offered in preference order during ALPN
+    #[serde(default = "default_alpn_protocols")]
+    pub alpn_protocols: Vec<String>,
+    // verify client certificates, omit to accept any client
+    pub client_auth: Option<ClientAuthConfig>,
+    // adds Strict-Transport-Security to responses sent over TLS
+    pub hsts: Option<HstsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HstsConfig {
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl HstsConfig {
+    fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        return value;
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientAuthConfig {
+    // PEM bundle of the CAs client certificates must chain to
+    pub ca_bundle: PathBuf,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    // handshakes without a valid client certificate fail
+    #[default]
+    Required,
+    // clients without a certificate are allowed, invalid certificates still fail
+    Optional,
+}
+
+// the verified client certificate of a mutual TLS connection
+pub struct ClientIdentity {
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateConfig {
+    // PEM files, the chain starts with the leaf certificate
+    pub cert_chain: PathBuf,
+    pub private_key: PathBuf,
+    // SNI names served by this certificate, `*.example.com` matches one label,
+    // a certificate without names is used when no other certificate matches
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+fn default_alpn_protocols() -> Vec<String> {
+    vec!["http/1.1".to_string()]
+}
+
+// one year, the minimum for the preload list
+fn default_hsts_max_age() -> u64 {
+    31536000
+}
+
+#[derive(Debug, Default)]
+struct LoadedCertificates {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+// resolves the certificate for a handshake from the SNI name, swapped out on reload
+#[derive(Debug)]
+struct CertificateStore {
+    configs: Vec<CertificateConfig>,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Arc<LoadedCertificates>>,
+}
+
+impl CertificateStore {
+    fn load(&self) -> Result<LoadedCertificates, String> {
+        let mut loaded = LoadedCertificates::default();
+        let mut unnamed = None;
+
+        for config in &self.configs {
+            let cert_chain = CertificateDer::pem_file_iter(&config.cert_chain)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("{} | {}", config.cert_chain.display(), e))?;
+            if cert_chain.is_empty() {
+                return Err(format!(
+                    "{} | no certificates found",
+                    config.cert_chain.display()
+                ));
+            }
+            let private_key = PrivateKeyDer::from_pem_file(&config.private_key)
+                .map_err(|e| format!("{} | {}", config.private_key.display(), e))?;
+            let key = CertifiedKey::from_der(cert_chain, private_key, &self.provider)
+                .map_err(|e| format!("{} | {}", config.cert_chain.display(), e))?;
+            let key = Arc::new(key);
+
+            for name in &config.server_names {
+                loaded
+                    .by_name
+                    .insert(name.to_ascii_lowercase(), key.clone());
+            }
+            if config.server_names.is_empty() {
+                unnamed.get_or_insert(key.clone());
+            }
+            loaded.default.get_or_insert(key);
+        }
+        // an unnamed certificate takes priority over the first certificate as the default
+        if unnamed.is_some() {
+            loaded.default = unnamed;
+        }
+
+        return Ok(loaded);
+    }
+
+    fn replace(&self, loaded: LoadedCertificates) {
+        match self.loaded.write() {
+            Ok(mut guard) => *guard = Arc::new(loaded),
+            Err(poisoned_guard) => *poisoned_guard.into_inner() = Arc::new(loaded),
+        }
+    }
+
+    fn reload(&self) {
+        match self.load() {
+            Ok(loaded) => {
+                self.replace(loaded);
+                info!(certificates = self.configs.len(); "Reloaded TLS certificates");
+            }
+            Err(e) => {
+                error!(error = e.as_str(); "Couldn't reload TLS certificates - keeping the current certificates");
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = match self.loaded.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned_guard) => poisoned_guard.into_inner().clone(),
+        };
+
+        let server_name = match client_hello.server_name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return loaded.default.clone(),
+        };
+        if let Some(key) = loaded.by_name.get(&server_name) {
+            return Some(key.clone());
+        }
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        if let Some(key) = wildcard.and_then(|name| loaded.by_name.get(&name)) {
+            return Some(key.clone());
+        }
+        return loaded.default.clone();
+    }
+}
+
+pub struct TlsAcceptor {
+    tls_config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    // rebuilt on reload so a changed CA bundle is picked up
+    config: RwLock<Arc<RustlsServerConfig>>,
+    certificates: Arc<CertificateStore>,
+    handshake_failures: Counter<u64>,
+    hsts_header: Option<String>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: &TlsConfig) -> Self {
+        if config.certificates.is_empty() {
+            error!("TLS is enabled but no certificates are configured");
+            force_export_telemetry(false);
+            panic!("TLS is enabled but no certificates are configured");
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let certificates = Arc::new(CertificateStore {
+            configs: config.certificates.clone(),
+            provider: provider.clone(),
+            loaded: RwLock::new(Arc::new(LoadedCertificates::default())),
+        });
+        match certificates.load() {
+            Ok(loaded) => certificates.replace(loaded),
+            Err(e) => {
+                error!(error = e.as_str(); "Couldn't load TLS certificates");
+                force_export_telemetry(false);
+                panic!("Couldn't load TLS certificates | {}", e);
+            }
+        }
+
+        let server_config = match build_server_config(config, &provider, &certificates) {
+            Ok(server_config) => server_config,
+            Err(e) => {
+                error!(error = e.as_str(); "Couldn't configure TLS");
+                force_export_telemetry(false);
+                panic!("Couldn't configure TLS | {}", e);
+            }
+        };
+
+        let handshake_failures = global::meter("tls")
+            .u64_counter("handshake_failures")
+            .with_description("Total number of failed TLS handshakes")
+            .build();
+
+        return TlsAcceptor {
+            tls_config: config.clone(),
+            provider,
+            config: RwLock::new(Arc::new(server_config)),
+            certificates,
+            handshake_failures,
+            hsts_header: config.hsts.as_ref().map(HstsConfig::header_value),
+        };
+    }
+
+    pub fn hsts_header(&self) -> Option<&str> {
+        self.hsts_header.as_deref()
+    }
+
+    pub fn reload_certificates(&self) {
+        self.certificates.reload();
+        if self.tls_config.client_auth.is_none() {
+            return;
+        }
+
+        match build_server_config(&self.tls_config, &self.provider, &self.certificates) {
+            Ok(server_config) => {
+                match self.config.write() {
+                    Ok(mut guard) => *guard = Arc::new(server_config),
+                    Err(poisoned_guard) => *poisoned_guard.into_inner() = Arc::new(server_config),
+                }
+                info!("Reloaded TLS client CA bundle");
+            }
+            Err(e) => {
+                error!(error = e.as_str(); "Couldn't reload TLS client CA bundle - keeping the current bundle");
+            }
+        }
+    }
+
+    // runs the handshake to completion, so the returned stream is ready for the request
+    pub fn accept(&self, socket: Socket, listener: &str, timeout: Duration) -> io::Result<Stream> {
+        let deadline = Instant::now() + timeout;
+        let config = match self.config.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned_guard) => poisoned_guard.into_inner().clone(),
+        };
+        let conn = match ServerConnection::new(config) {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.record_failure(listener, "config");
+                return Err(io::Error::other(e));
+            }
+        };
+        let mut stream = StreamOwned::new(conn, socket);
+
+        while stream.conn.is_handshaking() {
+            // the socket timeout applies per read, a client trickling bytes would never hit it
+            if Instant::now() >= deadline {
+                self.record_failure(listener, "timeout");
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ));
+            }
+            if let Err(e) = handshake_step(&mut stream.conn, &mut stream.sock) {
+                self.record_failure(listener, failure_reason(&e));
+                return Err(e);
+            }
+        }
+
+        return Ok(Stream::Tls(Box::new(stream)));
+    }
+
+    fn record_failure(&self, listener: &str, reason: &'static str) {
+        self.handshake_failures.add(
+            1,
+            &[
+                KeyValue::new("listener", listener.to_string()),
+                KeyValue::new("reason", reason),
+            ],
+        );
+    }
+}
+
+// one write or read of the handshake, unlike `complete_io` which loops until it's done
+fn handshake_step(conn: &mut ServerConnection, sock: &mut Socket) -> io::Result<()> {
+    if conn.wants_write() {
+        conn.write_tls(sock)?;
+        return Ok(());
+    }
+    if conn.read_tls(sock)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    if let Err(e) = conn.process_new_packets() {
+        // sends the alert describing the error
+        let _ = conn.write_tls(sock);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    return Ok(());
+}
+
+// a fixed set of metric label values, rustls errors carry client supplied details
+fn failure_reason(e: &io::Error) -> &'static str {
+    if let Some(tls_error) = e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        return match tls_error {
+            rustls::Error::NoCertificatesPresented => "no_client_certificate",
+            rustls::Error::InvalidCertificate(_) => "invalid_certificate",
+            rustls::Error::NoApplicationProtocol => "no_application_protocol",
+            rustls::Error::PeerIncompatible(_) => "peer_incompatible",
+            // usually the client rejecting the server certificate
+            rustls::Error::AlertReceived(_) => "alert_received",
+            rustls::Error::InvalidMessage(_)
+            | rustls::Error::InappropriateMessage { .. }
+            | rustls::Error::InappropriateHandshakeMessage { .. }
+            | rustls::Error::PeerMisbehaved(_)
+            | rustls::Error::PeerSentOversizedRecord => "protocol_error",
+            rustls::Error::DecryptError => "decrypt_error",
+            _ => "other",
+        };
+    }
+    return match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "timeout",
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => "connection_closed",
+        _ => "io_error",
+    };
+}
+
+fn build_server_config(
+    config: &TlsConfig,
+    provider: &Arc<CryptoProvider>,
+    certificates: &Arc<CertificateStore>,
+) -> Result<RustlsServerConfig, String> {
+    let builder = RustlsServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("protocol versions | {}", e))?;
+    let builder = match &config.client_auth {
+        None => builder.with_no_client_auth(),
+        Some(client_auth) => {
+            builder.with_client_cert_verifier(build_client_verifier(client_auth, provider)?)
+        }
+    };
+
+    let mut server_config = builder.with_cert_resolver(certificates.clone());
+    server_config.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    return Ok(server_config);
+}
+
+fn build_client_verifier(
+    client_auth: &ClientAuthConfig,
+    provider: &Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    let ca_certs = CertificateDer::pem_file_iter(&client_auth.ca_bundle)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{} | {}", client_auth.ca_bundle.display(), e))?;
+    for ca_cert in ca_certs {
+        roots
+            .add(ca_cert)
+            .map_err(|e| format!("{} | {}", client_auth.ca_bundle.display(), e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!(
+            "{} | no CA certificates found",
+            client_auth.ca_bundle.display()
+        ));
+    }
+
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+    let builder = match client_auth.mode {
+        ClientAuthMode::Required => builder,
+        ClientAuthMode::Optional => builder.allow_unauthenticated(),
+    };
+    return builder
+        .build()
+        .map_err(|e| format!("{} | {}", client_auth.ca_bundle.display(), e));
+}
+
+// reads the subject and SANs from the leaf client certificate, None without mutual TLS
+pub fn client_identity(stream: &Stream) -> Option<ClientIdentity> {
+    let tls_stream = match stream {
+        Stream::Tls(tls_stream) => tls_stream,
+        Stream::Plain(_) => return None,
+    };
+    let leaf = tls_stream.conn.peer_certificates()?.first()?;
+    let (_, cert) = match X509Certificate::from_der(leaf.as_ref()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!(error = format!("{}", e).as_str(); "Couldn't parse verified client certificate");
+            return None;
+        }
+    };
+
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        Ok(None) | Err(_) => Vec::new(),
+    };
+
+    return Some(ClientIdentity {
+        subject: cert.subject().to_string(),
+        subject_alt_names,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        net::{TcpListener, TcpStream},
+        os::fd::OwnedFd,
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+    use rustls::{ClientConfig, ClientConnection, pki_types::ServerName};
+
+    use super::*;
+
+    struct TestCertificate {
+        cert_pem: String,
+        key_pem: String,
+        der: Vec<u8>,
+    }
+
+    fn test_ca(name: &str) -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        return CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+    }
+
+    fn issue(
+        ca: &CertifiedIssuer<'static, KeyPair>,
+        common_name: &str,
+        names: &[&str],
+    ) -> TestCertificate {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        let mut params = CertificateParams::new(names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca).unwrap();
+        return TestCertificate {
+            cert_pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+            der: cert.der().to_vec(),
+        };
+    }
+
+    // removed with its files when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "http-server-tls-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            return TempDir(dir);
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_certificate(
+        dir: &Path,
+        file_name: &str,
+        certificate: &TestCertificate,
+        server_names: &[&str],
+    ) -> CertificateConfig {
+        let cert_chain = dir.join(format!("{}.crt", file_name));
+        let private_key = dir.join(format!("{}.key", file_name));
+        fs::write(&cert_chain, &certificate.cert_pem).unwrap();
+        fs::write(&private_key, &certificate.key_pem).unwrap();
+        return CertificateConfig {
+            cert_chain,
+            private_key,
+            server_names: server_names.iter().map(|name| name.to_string()).collect(),
+        };
+    }
+
+    fn tls_config(certificates: Vec<CertificateConfig>) -> TlsConfig {
+        return TlsConfig {
+            certificates,
+            alpn_protocols: default_alpn_protocols(),
+            client_auth: None,
+            hsts: None,
+        };
+    }
+
+    fn client_config(
+        ca: &CertifiedIssuer<'static, KeyPair>,
+        alpn_protocols: &[&str],
+        client_certificate: Option<&TestCertificate>,
+    ) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let mut config = match client_certificate {
+            Some(certificate) => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from(certificate.der.clone())],
+                    PrivateKeyDer::from_pem_slice(certificate.key_pem.as_bytes()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        return config;
+    }
+
+    struct ClientResult {
+        server_certificate: Vec<u8>,
+        alpn_protocol: Option<Vec<u8>>,
+    }
+
+    // runs a handshake between `acceptor` and a rustls client on a loopback connection
+    fn handshake(
+        acceptor: &TlsAcceptor,
+        client_config: ClientConfig,
+        server_name: &str,
+    ) -> (io::Result<Stream>, io::Result<ClientResult>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let client = thread::spawn(move || -> io::Result<ClientResult> {
+            let mut sock = TcpStream::connect(address)?;
+            sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let mut conn = ClientConnection::new(Arc::new(client_config), server_name)
+                .map_err(io::Error::other)?;
+            while conn.is_handshaking() {
+                conn.complete_io(&mut sock)?;
+            }
+            let server_certificate = conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.to_vec())
+                .unwrap_or_default();
+            return Ok(ClientResult {
+                server_certificate,
+                alpn_protocol: conn.alpn_protocol().map(|protocol| protocol.to_vec()),
+            });
+        });
+
+        let (server_sock, _) = listener.accept().unwrap();
+        let socket = Socket::new(OwnedFd::from(server_sock), Duration::from_secs(5));
+        let server = acceptor.accept(socket, "test", Duration::from_secs(5));
+        return (server, client.join().unwrap());
+    }
+
+    #[test]
+    fn sni_selects_the_named_certificate() {
+        let ca = test_ca("test ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let a = issue(&ca, "a", &["a.test"]);
+        let b = issue(&ca, "b", &["b.test"]);
+        let acceptor = TlsAcceptor::new(&tls_config(vec![
+            write_certificate(dir, "a", &a, &["a.test"]),
+            write_certificate(dir, "b", &b, &["b.test"]),
+        ]));
+
+        let (server, client) = handshake(&acceptor, client_config(&ca, &[], None), "b.test");
+        assert!(server.is_ok());
+        assert_eq!(client.unwrap().server_certificate, b.der);
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "a.test");
+        assert_eq!(client.unwrap().server_certificate, a.der);
+    }
+
+    #[test]
+    fn wildcard_and_default_certificates_are_fallbacks() {
+        let ca = test_ca("test ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let wildcard = issue(&ca, "wildcard", &["*.example.test"]);
+        let default = issue(&ca, "default", &["other.test"]);
+        let acceptor = TlsAcceptor::new(&tls_config(vec![
+            write_certificate(dir, "wildcard", &wildcard, &["*.example.test"]),
+            write_certificate(dir, "default", &default, &[]),
+        ]));
+
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "api.example.test");
+        assert_eq!(client.unwrap().server_certificate, wildcard.der);
+        // one label only, so this falls through to the unnamed certificate
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "other.test");
+        assert_eq!(client.unwrap().server_certificate, default.der);
+    }
+
+    #[test]
+    fn alpn_negotiates_a_configured_protocol() {
+        let ca = test_ca("test ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let certificate = issue(&ca, "site", &["site.test"]);
+        let mut config = tls_config(vec![write_certificate(dir, "site", &certificate, &[])]);
+        config.alpn_protocols = vec!["h2".to_string(), "http/1.1".to_string()];
+        let acceptor = TlsAcceptor::new(&config);
+
+        let (server, client) = handshake(
+            &acceptor,
+            client_config(&ca, &["http/1.1"], None),
+            "site.test",
+        );
+        assert!(server.is_ok());
+        assert_eq!(client.unwrap().alpn_protocol, Some(b"http/1.1".to_vec()));
+
+        let (server, _) = handshake(
+            &acceptor,
+            client_config(&ca, &["spdy/3"], None),
+            "site.test",
+        );
+        let e = server.err().unwrap();
+        assert_eq!(failure_reason(&e), "no_application_protocol");
+    }
+
+    #[test]
+    fn reload_picks_up_replaced_certificate_files() {
+        let ca = test_ca("test ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let old = issue(&ca, "old", &["site.test"]);
+        let new = issue(&ca, "new", &["site.test"]);
+        let acceptor = TlsAcceptor::new(&tls_config(vec![write_certificate(
+            dir,
+            "site",
+            &old,
+            &["site.test"],
+        )]));
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "site.test");
+        assert_eq!(client.unwrap().server_certificate, old.der);
+
+        write_certificate(dir, "site", &new, &["site.test"]);
+        acceptor.reload_certificates();
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "site.test");
+        assert_eq!(client.unwrap().server_certificate, new.der);
+
+        // a broken file keeps the certificates that were loaded
+        fs::write(dir.join("site.crt"), "not a certificate").unwrap();
+        acceptor.reload_certificates();
+        let (_, client) = handshake(&acceptor, client_config(&ca, &[], None), "site.test");
+        assert_eq!(client.unwrap().server_certificate, new.der);
+    }
+
+    fn mutual_tls_acceptor(
+        dir: &Path,
+        server_ca: &CertifiedIssuer<'static, KeyPair>,
+        client_ca: &CertifiedIssuer<'static, KeyPair>,
+        mode: ClientAuthMode,
+    ) -> TlsAcceptor {
+        let certificate = issue(server_ca, "site", &["site.test"]);
+        let ca_bundle = dir.join("clients.pem");
+        fs::write(&ca_bundle, client_ca.pem()).unwrap();
+        let mut config = tls_config(vec![write_certificate(dir, "site", &certificate, &[])]);
+        config.client_auth = Some(ClientAuthConfig { ca_bundle, mode });
+        return TlsAcceptor::new(&config);
+    }
+
+    #[test]
+    fn required_client_auth_verifies_and_exposes_the_client_identity() {
+        let server_ca = test_ca("server ca");
+        let client_ca = test_ca("client ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let acceptor = mutual_tls_acceptor(dir, &server_ca, &client_ca, ClientAuthMode::Required);
+
+        let client_certificate = issue(&client_ca, "worker-1", &["worker-1.internal"]);
+        let (server, _) = handshake(
+            &acceptor,
+            client_config(&server_ca, &[], Some(&client_certificate)),
+            "site.test",
+        );
+        let identity = client_identity(&server.unwrap()).unwrap();
+        assert_eq!(identity.subject, "CN=worker-1");
+        assert_eq!(
+            identity.subject_alt_names,
+            vec!["DNSName(worker-1.internal)"]
+        );
+
+        let (server, _) = handshake(&acceptor, client_config(&server_ca, &[], None), "site.test");
+        assert_eq!(
+            failure_reason(&server.err().unwrap()),
+            "no_client_certificate"
+        );
+
+        // signed by a CA that isn't in the bundle
+        let untrusted = issue(&server_ca, "intruder", &["intruder.internal"]);
+        let (server, _) = handshake(
+            &acceptor,
+            client_config(&server_ca, &[], Some(&untrusted)),
+            "site.test",
+        );
+        assert_eq!(
+            failure_reason(&server.err().unwrap()),
+            "invalid_certificate"
+        );
+    }
+
+    #[test]
+    fn optional_client_auth_allows_anonymous_clients() {
+        let server_ca = test_ca("server ca");
+        let client_ca = test_ca("client ca");
+        let temp = TempDir::new();
+        let dir = temp.0.as_path();
+        let acceptor = mutual_tls_acceptor(dir, &server_ca, &client_ca, ClientAuthMode::Optional);
+
+        let (server, _) = handshake(&acceptor, client_config(&server_ca, &[], None), "site.test");
+        assert!(client_identity(&server.unwrap()).is_none());
+    }
+
+    #[test]
+    fn slow_handshakes_time_out() {
+        let ca = test_ca("test ca");
+        let temp = TempDir::new();
+        let certificate = issue(&ca, "a", &["a.test"]);
+        let acceptor = TlsAcceptor::new(&tls_config(vec![write_certificate(
+            temp.0.as_path(),
+            "a",
+            &certificate,
+            &[],
+        )]));
+        let mut client_hello = Vec::new();
+        let server_name = ServerName::try_from("a.test").unwrap();
+        ClientConnection::new(Arc::new(client_config(&ca, &[], None)), server_name)
+            .unwrap()
+            .write_tls(&mut client_hello)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // every byte arrives well within the socket timeout
+        let client = thread::spawn(move || {
+            let mut sock = TcpStream::connect(address).unwrap();
+            for byte in client_hello {
+                if sock.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let (server_sock, _) = listener.accept().unwrap();
+        let socket = Socket::new(OwnedFd::from(server_sock), Duration::from_secs(5));
+
+        let started = Instant::now();
+        let result = acceptor.accept(socket, "test", Duration::from_millis(200));
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn failure_reasons_are_a_fixed_set() {
+        let tls_error = |e: rustls::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+        assert_eq!(
+            failure_reason(&tls_error(rustls::Error::General(
+                "client said hi".to_string()
+            ))),
+            "other"
+        );
+        assert_eq!(
+            failure_reason(&tls_error(rustls::Error::DecryptError)),
+            "decrypt_error"
+        );
+        assert_eq!(
+            failure_reason(&io::Error::from(io::ErrorKind::TimedOut)),
+            "timeout"
+        );
+        assert_eq!(
+            failure_reason(&io::Error::from(io::ErrorKind::UnexpectedEof)),
+            "connection_closed"
+        );
+    }
+}
@@ -0,0 +1,741 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Read, Take, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, Histogram},
+};
+use serde::Deserialize;
+
+use crate::{admin::publish_upstream_state, http::ChunkedReader, statics::SHUTDOWN_SERVER};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    RoundRobin,
+    // the upstream with the fewest requests in flight
+    LeastConnections,
+    // the same key keeps going to the same upstream while it's available
+    ConsistentHash,
+}
+
+// probes every upstream with a GET on an interval, 2xx and 3xx responses are healthy
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    // consecutive results needed to flip the upstream state
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_interval_ms() -> u64 {
+    5000
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_threshold() -> u32 {
+    2
+}
+
+// keep-alive connections kept per upstream between requests
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConnectionPoolConfig {
+    // 0 disables pooling, every request opens a new connection
+    pub max_idle: usize,
+    pub idle_timeout_ms: u64,
+    // connections older than this are closed instead of reused
+    pub max_lifetime_ms: u64,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        return ConnectionPoolConfig {
+            max_idle: 16,
+            idle_timeout_ms: 30_000,
+            max_lifetime_ms: 300_000,
+        };
+    }
+}
+
+// virtual nodes per upstream on the consistent hash ring
+const HASH_RING_REPLICAS: usize = 64;
+
+pub struct Upstream {
+    pub address: String,
+    // requests in flight, including responses still streaming to the client
+    active: AtomicUsize,
+    // result of the active health checks, upstreams start healthy
+    healthy: AtomicBool,
+    health_check_streak: AtomicU32,
+    // passive ejection after consecutive request failures
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    // most recently used last
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+impl Upstream {
+    fn new(address: String) -> Self {
+        return Upstream {
+            address,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            health_check_streak: AtomicU32::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            idle: Mutex::new(Vec::new()),
+        };
+    }
+
+    fn idle_connections(&self) -> std::sync::MutexGuard<'_, Vec<IdleConnection>> {
+        match self.idle.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut ejected_until = match self.ejected_until.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *ejected_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+// counts a request as in flight on the upstream until dropped
+pub struct UpstreamLease {
+    pub upstream: Arc<Upstream>,
+    pub index: usize,
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct PooledConnection {
+    pub stream: TcpStream,
+    pub created: Instant,
+    // served a response before, the upstream may have closed it while it was idle
+    pub reused: bool,
+}
+
+struct IdleConnection {
+    connection: PooledConnection,
+    idle_since: Instant,
+}
+
+// how the end of an upstream response body is found
+pub enum Framing {
+    Length(Take<BufReader<TcpStream>>),
+    Chunked(ChunkedReader<BufReader<TcpStream>>),
+    // ends when the upstream closes the connection, so it's never reused
+    Close(BufReader<TcpStream>),
+}
+
+/*
+A response body streaming from an upstream. It holds the lease so the request counts as
+in flight until the body is sent, and returns the connection to the pool once the body
+was read to the end.
+*/
+pub struct UpstreamBody {
+    framing: Option<Framing>,
+    created: Instant,
+    keep_alive: bool,
+    pool: Arc<UpstreamPool>,
+    lease: UpstreamLease,
+}
+
+impl UpstreamBody {
+    pub fn new(
+        framing: Framing,
+        created: Instant,
+        keep_alive: bool,
+        pool: Arc<UpstreamPool>,
+        lease: UpstreamLease,
+    ) -> Self {
+        return UpstreamBody {
+            framing: Some(framing),
+            created,
+            keep_alive,
+            pool,
+            lease,
+        };
+    }
+
+    // trailers of a chunked body, available once it was read to the end
+    pub fn trailers(&self) -> &[(String, String)] {
+        match &self.framing {
+            Some(Framing::Chunked(reader)) => reader.trailers(),
+            _ => &[],
+        }
+    }
+}
+
+impl Read for UpstreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.framing {
+            Some(Framing::Length(reader)) => reader.read(buf),
+            Some(Framing::Chunked(reader)) => reader.read(buf),
+            Some(Framing::Close(reader)) => reader.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for UpstreamBody {
+    fn drop(&mut self) {
+        let reader = match self.framing.take() {
+            Some(Framing::Length(reader)) if reader.limit() == 0 => reader.into_inner(),
+            Some(Framing::Chunked(reader)) if reader.is_done() => reader.into_inner(),
+            _ => return,
+        };
+        // leftover bytes would be read as the start of the next response
+        if !self.keep_alive || !reader.buffer().is_empty() {
+            return;
+        }
+        let connection = PooledConnection {
+            stream: reader.into_inner(),
+            created: self.created,
+            reused: true,
+        };
+        self.pool.release(&self.lease.upstream, connection);
+    }
+}
+
+pub struct UpstreamPool {
+    // the proxy prefix, used in logs and metrics
+    name: String,
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+    // sorted (hash, upstream index) points
+    ring: Vec<(u64, usize)>,
+    max_failures: u32,
+    ejection: Duration,
+    connection_pool: ConnectionPoolConfig,
+    connections_opened: Counter<u64>,
+    connections_reused: Counter<u64>,
+    connection_wait_time: Histogram<f64>,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        name: &str,
+        addresses: &[String],
+        strategy: BalanceStrategy,
+        max_failures: u32,
+        ejection: Duration,
+        connection_pool: ConnectionPoolConfig,
+    ) -> Self {
+        let upstreams = addresses
+            .iter()
+            .map(|address| Arc::new(Upstream::new(address.clone())))
+            .collect::<Vec<Arc<Upstream>>>();
+
+        let mut ring = Vec::new();
+        if strategy == BalanceStrategy::ConsistentHash {
+            for (index, upstream) in upstreams.iter().enumerate() {
+                for replica in 0..HASH_RING_REPLICAS {
+                    ring.push((
+                        hash_key(&format!("{}#{}", upstream.address, replica)),
+                        index,
+                    ));
+                }
+            }
+            ring.sort();
+        }
+
+        let meter = global::meter("upstreams");
+        let pool = UpstreamPool {
+            name: name.to_string(),
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            ring,
+            max_failures,
+            ejection,
+            connection_pool,
+            connections_opened: meter
+                .u64_counter("connections_opened")
+                .with_description("New connections opened to the upstream")
+                .build(),
+            connections_reused: meter
+                .u64_counter("connections_reused")
+                .with_description("Requests sent over a pooled keep-alive connection")
+                .build(),
+            connection_wait_time: meter
+                .f64_histogram("connection_wait_time")
+                .with_description("Time to get a connection from the pool or open a new one")
+                .with_unit("ms")
+                .build(),
+        };
+        pool.register_gauges();
+        return pool;
+    }
+
+    fn register_gauges(&self) {
+        let meter = global::meter("upstreams");
+        let upstreams = self.upstreams.clone();
+        let pool_name = self.name.clone();
+        meter
+            .u64_observable_gauge("available")
+            .with_description(
+                "1 when the upstream can receive requests, 0 when unhealthy or ejected",
+            )
+            .with_callback(move |observer| {
+                for upstream in &upstreams {
+                    observer.observe(
+                        upstream.is_available() as u64,
+                        &[
+                            KeyValue::new("pool", pool_name.clone()),
+                            KeyValue::new("upstream", upstream.address.clone()),
+                        ],
+                    );
+                }
+            })
+            .build();
+
+        let upstreams = self.upstreams.clone();
+        let pool_name = self.name.clone();
+        meter
+            .u64_observable_gauge("active_requests")
+            .with_description("Requests in flight on the upstream")
+            .with_callback(move |observer| {
+                for upstream in &upstreams {
+                    observer.observe(
+                        upstream.active.load(Ordering::Relaxed) as u64,
+                        &[
+                            KeyValue::new("pool", pool_name.clone()),
+                            KeyValue::new("upstream", upstream.address.clone()),
+                        ],
+                    );
+                }
+            })
+            .build();
+
+        let upstreams = self.upstreams.clone();
+        let pool_name = self.name.clone();
+        meter
+            .u64_observable_gauge("available_upstreams")
+            .with_description("Upstreams in the pool that can receive requests")
+            .with_callback(move |observer| {
+                let available = upstreams
+                    .iter()
+                    .filter(|upstream| upstream.is_available())
+                    .count();
+                observer.observe(
+                    available as u64,
+                    &[KeyValue::new("pool", pool_name.clone())],
+                );
+            })
+            .build();
+
+        let upstreams = self.upstreams.clone();
+        let pool_name = self.name.clone();
+        meter
+            .u64_observable_gauge("idle_connections")
+            .with_description("Keep-alive connections waiting in the pool")
+            .with_callback(move |observer| {
+                for upstream in &upstreams {
+                    observer.observe(
+                        upstream.idle_connections().len() as u64,
+                        &[
+                            KeyValue::new("pool", pool_name.clone()),
+                            KeyValue::new("upstream", upstream.address.clone()),
+                        ],
+                    );
+                }
+            })
+            .build();
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        self.connection_pool.max_idle > 0
+    }
+
+    // reuses an idle connection when one is still usable, otherwise opens one with `connect`
+    pub fn checkout<E>(
+        &self,
+        upstream: &Upstream,
+        connect: impl FnOnce() -> Result<TcpStream, E>,
+    ) -> Result<PooledConnection, E> {
+        let start = Instant::now();
+        let attributes = [
+            KeyValue::new("pool", self.name.clone()),
+            KeyValue::new("upstream", upstream.address.clone()),
+        ];
+
+        let connection = match self.take_idle(upstream) {
+            Some(connection) => {
+                self.connections_reused.add(1, &attributes);
+                connection
+            }
+            None => self.open(upstream, connect)?,
+        };
+        self.connection_wait_time
+            .record(start.elapsed().as_secs_f64() * 1000.0, &attributes);
+        return Ok(connection);
+    }
+
+    // a new connection, skipping the idle ones
+    pub fn open<E>(
+        &self,
+        upstream: &Upstream,
+        connect: impl FnOnce() -> Result<TcpStream, E>,
+    ) -> Result<PooledConnection, E> {
+        let stream = connect()?;
+        self.connections_opened.add(
+            1,
+            &[
+                KeyValue::new("pool", self.name.clone()),
+                KeyValue::new("upstream", upstream.address.clone()),
+            ],
+        );
+        return Ok(PooledConnection {
+            stream,
+            created: Instant::now(),
+            reused: false,
+        });
+    }
+
+    fn take_idle(&self, upstream: &Upstream) -> Option<PooledConnection> {
+        let idle_timeout = Duration::from_millis(self.connection_pool.idle_timeout_ms);
+        let max_lifetime = Duration::from_millis(self.connection_pool.max_lifetime_ms);
+        let mut idle = upstream.idle_connections();
+        while let Some(entry) = idle.pop() {
+            if entry.idle_since.elapsed() < idle_timeout
+                && entry.connection.created.elapsed() < max_lifetime
+                && is_reusable(&entry.connection.stream)
+            {
+                return Some(entry.connection);
+            }
+        }
+        return None;
+    }
+
+    pub fn release(&self, upstream: &Upstream, connection: PooledConnection) {
+        let idle_timeout = Duration::from_millis(self.connection_pool.idle_timeout_ms);
+        let max_lifetime = Duration::from_millis(self.connection_pool.max_lifetime_ms);
+        if connection.created.elapsed() >= max_lifetime {
+            return;
+        }
+        let mut idle = upstream.idle_connections();
+        idle.retain(|entry| entry.idle_since.elapsed() < idle_timeout);
+        if idle.len() < self.connection_pool.max_idle {
+            idle.push(IdleConnection {
+                connection,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    // picks an available upstream that hasn't been tried yet, `key` is used by consistent hashing
+    pub fn select(&self, key: &str, tried: &[usize]) -> Option<UpstreamLease> {
+        let candidate =
+            |index: &usize| !tried.contains(index) && self.upstreams[*index].is_available();
+
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.upstreams.len())
+                    .map(|offset| (start + offset) % self.upstreams.len())
+                    .find(candidate)
+            }
+            BalanceStrategy::LeastConnections => (0..self.upstreams.len())
+                .filter(candidate)
+                .min_by_key(|index| self.upstreams[*index].active.load(Ordering::Relaxed)),
+            BalanceStrategy::ConsistentHash => {
+                let start = self
+                    .ring
+                    .partition_point(|(point, _)| *point < hash_key(key));
+                (0..self.ring.len())
+                    .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                    .find(candidate)
+            }
+        }?;
+
+        let upstream = self.upstreams[index].clone();
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        return Some(UpstreamLease { upstream, index });
+    }
+
+    pub fn report_success(&self, upstream: &Upstream) {
+        upstream.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    // ejects the upstream for a while once it fails `max_failures` requests in a row
+    pub fn report_failure(&self, upstream: &Upstream) {
+        let failures = upstream
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures < self.max_failures {
+            return;
+        }
+        upstream.consecutive_failures.store(0, Ordering::Relaxed);
+        let mut ejected_until = match upstream.ejected_until.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *ejected_until = Some(Instant::now() + self.ejection);
+        warn!(
+            pool = self.name.as_str(),
+            upstream = upstream.address.as_str(),
+            failures = failures,
+            ejection_ms = self.ejection.as_millis() as u64;
+            "Upstream ejected after consecutive failures"
+        );
+        publish_upstream_state(&self.name, &upstream.address, "ejected");
+    }
+
+    // runs until the server shuts down
+    pub fn spawn_health_checks(self: &Arc<Self>, config: HealthCheckConfig) {
+        let pool = self.clone();
+        std::thread::spawn(move || {
+            let interval = Duration::from_millis(config.interval_ms);
+            let mut next_check = Instant::now();
+            loop {
+                if let Ok(flag) = SHUTDOWN_SERVER.read()
+                    && *flag
+                {
+                    break;
+                }
+                if Instant::now() < next_check {
+                    sleep(Duration::from_millis(100).min(interval));
+                    continue;
+                }
+                next_check = Instant::now() + interval;
+
+                for upstream in &pool.upstreams {
+                    let passed = check_health(&upstream.address, &config);
+                    pool.record_health_check(upstream, passed, &config);
+                }
+            }
+        });
+    }
+
+    fn record_health_check(&self, upstream: &Upstream, passed: bool, config: &HealthCheckConfig) {
+        let healthy = upstream.healthy.load(Ordering::Relaxed);
+        if passed == healthy {
+            upstream.health_check_streak.store(0, Ordering::Relaxed);
+            return;
+        }
+        let streak = upstream.health_check_streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = match passed {
+            true => config.healthy_threshold,
+            false => config.unhealthy_threshold,
+        };
+        if streak < threshold {
+            return;
+        }
+
+        upstream.health_check_streak.store(0, Ordering::Relaxed);
+        upstream.healthy.store(passed, Ordering::Relaxed);
+        if passed {
+            info!(pool = self.name.as_str(), upstream = upstream.address.as_str(); "Upstream passed health checks");
+            publish_upstream_state(&self.name, &upstream.address, "healthy");
+        } else {
+            warn!(pool = self.name.as_str(), upstream = upstream.address.as_str(); "Upstream failed health checks");
+            publish_upstream_state(&self.name, &upstream.address, "unhealthy");
+        }
+    }
+}
+
+// an idle connection that's readable was closed by the upstream or has unexpected data
+fn is_reusable(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 1];
+    let is_idle = matches!(stream.peek(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    return stream.set_nonblocking(false).is_ok() && is_idle;
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn check_health(address: &str, config: &HealthCheckConfig) -> bool {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut stream = match address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .and_then(|address| TcpStream::connect_timeout(&address, timeout).ok())
+    {
+        Some(stream) => stream,
+        None => return false,
+    };
+    if stream.set_read_timeout(Some(timeout)).is_err()
+        || stream.set_write_timeout(Some(timeout)).is_err()
+    {
+        return false;
+    }
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        config.path, address
+    );
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+    let mut status_line = String::new();
+    if BufReader::new(stream).read_line(&mut status_line).is_err() {
+        return false;
+    }
+    // ex: "HTTP/1.1 200 OK"
+    return match status_line.split(' ').nth(1).map(str::parse::<u16>) {
+        Some(Ok(status)) => (200..400).contains(&status),
+        _ => false,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: BalanceStrategy, max_failures: u32, ejection: Duration) -> UpstreamPool {
+        let addresses = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]
+            .map(str::to_string)
+            .to_vec();
+        return UpstreamPool::new(
+            "/api",
+            &addresses,
+            strategy,
+            max_failures,
+            ejection,
+            ConnectionPoolConfig::default(),
+        );
+    }
+
+    // requests per upstream, each one finishes before the next is sent
+    fn distribution(pool: &UpstreamPool, keys: impl Iterator<Item = String>) -> Vec<usize> {
+        let mut counts = vec![0; pool.upstreams.len()];
+        for key in keys {
+            counts[pool.select(&key, &[]).unwrap().index] += 1;
+        }
+        return counts;
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let pool = pool(BalanceStrategy::RoundRobin, 3, Duration::from_secs(10));
+        let counts = distribution(&pool, (0..300).map(|_| String::new()));
+        assert_eq!(counts, [100, 100, 100]);
+
+        // a retry skips the upstreams that were already tried
+        let lease = pool.select("", &[0, 1]).unwrap();
+        assert_eq!(lease.index, 2);
+        assert!(pool.select("", &[0, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn least_connections_picks_the_least_busy_upstream() {
+        let pool = pool(
+            BalanceStrategy::LeastConnections,
+            3,
+            Duration::from_secs(10),
+        );
+        let mut leases = (0..6)
+            .map(|_| pool.select("", &[]).unwrap())
+            .collect::<Vec<UpstreamLease>>();
+        let mut counts = vec![0; 3];
+        for lease in &leases {
+            counts[lease.index] += 1;
+        }
+        assert_eq!(counts, [2, 2, 2]);
+
+        // finished requests, including streamed responses, free their upstream
+        let freed = leases.remove(3).index;
+        assert_eq!(pool.select("", &[]).unwrap().index, freed);
+    }
+
+    #[test]
+    fn consistent_hash_spreads_keys_and_keeps_them_in_place() {
+        let pool = pool(BalanceStrategy::ConsistentHash, 1, Duration::from_secs(10));
+        let keys = (0..3000).map(|key| format!("client-{}", key));
+        for count in distribution(&pool, keys.clone()) {
+            assert!(count > 500, "uneven distribution: {}", count);
+        }
+        let before = keys
+            .clone()
+            .map(|key| pool.select(&key, &[]).unwrap().index)
+            .collect::<Vec<usize>>();
+
+        pool.report_failure(&pool.upstreams[1]);
+        for (key, index) in keys.zip(before) {
+            let moved_to = pool.select(&key, &[]).unwrap().index;
+            match index {
+                // only keys of the ejected upstream move
+                1 => assert_ne!(moved_to, 1),
+                index => assert_eq!(moved_to, index, "{} moved", key),
+            }
+        }
+    }
+
+    #[test]
+    fn upstreams_are_ejected_after_consecutive_failures() {
+        let pool = pool(BalanceStrategy::RoundRobin, 3, Duration::from_millis(100));
+        let upstream = &pool.upstreams[0];
+        let others = [1, 2];
+
+        pool.report_failure(upstream);
+        pool.report_failure(upstream);
+        pool.report_success(upstream);
+        pool.report_failure(upstream);
+        pool.report_failure(upstream);
+        assert!(upstream.is_available());
+        assert_eq!(pool.select("", &others).unwrap().index, 0);
+
+        pool.report_failure(upstream);
+        assert!(!upstream.is_available());
+        assert!(pool.select("", &others).is_none());
+        let counts = distribution(&pool, (0..10).map(|_| String::new()));
+        assert_eq!(counts[0], 0);
+
+        sleep(Duration::from_millis(150));
+        assert!(upstream.is_available());
+        assert_eq!(pool.select("", &others).unwrap().index, 0);
+    }
+}
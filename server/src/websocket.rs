@@ -0,0 +1,733 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use log::{info, warn};
+use opentelemetry::{KeyValue, global, metrics::UpDownCounter};
+use serde::Deserialize;
+use sha1_smol::Sha1;
+
+use crate::{
+    connection::Stream,
+    http::{Request, Response},
+    router::Handler,
+    statics::SHUTDOWN_SERVER,
+};
+
+// appended to the client key before hashing, RFC 6455 section 1.3
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+// outgoing messages larger than this are sent as fragments
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+static OPEN_CONNECTIONS: LazyLock<UpDownCounter<i64>> = LazyLock::new(|| {
+    global::meter("websockets")
+        .i64_up_down_counter("open_connections")
+        .with_description("WebSocket connections currently open")
+        .build()
+});
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    // larger messages close the connection with 1009
+    pub max_message_bytes: usize,
+    // idle connections are pinged, and closed when the pong doesn't arrive in time
+    pub ping_interval_ms: u64,
+    // serves an echo endpoint at this path, ex: "/ws/echo"
+    pub echo_path: Option<String>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        return WebSocketConfig {
+            max_message_bytes: 1024 * 1024,
+            ping_interval_ms: 30_000,
+            echo_path: None,
+        };
+    }
+}
+
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+pub trait WebSocketHandler: Send + Sync {
+    // subprotocols the handler speaks, the first one the client also offers is selected
+    fn protocols(&self) -> &[&'static str] {
+        &[]
+    }
+
+    // runs on the connection's own thread, the socket is closed once it returns
+    fn handle(&self, req: &Request, socket: &mut WebSocket);
+}
+
+// echoes every message back, useful for checking WebSocket connectivity
+pub struct Echo;
+
+impl WebSocketHandler for Echo {
+    fn handle(&self, _req: &Request, socket: &mut WebSocket) {
+        while let Some(message) = socket.recv() {
+            if socket.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/*
+Registered on a GET route, answers the opening handshake with a 101 and hands the
+connection to the WebSocketHandler, ex:
+router.route("GET", "/ws/chat", WebSocketUpgrade::new(Chat, &config.websocket))
+*/
+pub struct WebSocketUpgrade {
+    handler: Arc<dyn WebSocketHandler>,
+    config: WebSocketConfig,
+}
+
+impl WebSocketUpgrade {
+    pub fn new(handler: impl WebSocketHandler + 'static, config: &WebSocketConfig) -> Self {
+        return WebSocketUpgrade {
+            handler: Arc::new(handler),
+            config: config.clone(),
+        };
+    }
+}
+
+impl Handler for WebSocketUpgrade {
+    fn handle(&self, req: &Request) -> Response {
+        let has_token = |name: &str, token: &str| {
+            req.header(name).is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+            return Response::text(426, "Upgrade Required")
+                .with_header("Connection", "Upgrade")
+                .with_header("Upgrade", "websocket");
+        }
+        if req.header("sec-websocket-version") != Some("13") {
+            return Response::text(426, "Unsupported WebSocket Version")
+                .with_header("Sec-WebSocket-Version", "13");
+        }
+        let key = match req.header("sec-websocket-key") {
+            Some(key) if STANDARD.decode(key).is_ok_and(|key| key.len() == 16) => key,
+            _ => return Response::text(400, "Invalid Sec-WebSocket-Key"),
+        };
+
+        let mut resp = Response::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", &accept_key(key));
+        let offered_protocols = req
+            .header("sec-websocket-protocol")
+            .map(|value| value.split(',').map(str::trim).collect::<Vec<&str>>())
+            .unwrap_or_default();
+        if let Some(protocol) = offered_protocols
+            .iter()
+            .find(|protocol| self.handler.protocols().contains(protocol))
+        {
+            resp.set_header("Sec-WebSocket-Protocol", protocol);
+        }
+
+        let handler = self.handler.clone();
+        let config = self.config.clone();
+        let req = req.clone();
+        resp.upgrade = Some(Box::new(move |stream, received| {
+            let attributes = [KeyValue::new("path", req.path.clone())];
+            OPEN_CONNECTIONS.add(1, &attributes);
+            let mut socket = WebSocket::new(stream, received, &config);
+            handler.handle(&req, &mut socket);
+            socket.close(CLOSE_NORMAL, "");
+            OPEN_CONNECTIONS.add(-1, &attributes);
+            info!(path = req.path.as_str(), close_code = socket.close_code; "WebSocket closed");
+        }));
+        return resp;
+    }
+}
+
+// base64(sha1(key + GUID))
+fn accept_key(key: &str) -> String {
+    let digest = Sha1::from(format!("{}{}", key, HANDSHAKE_GUID)).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// a close code and reason sent to the client when a frame breaks the protocol
+struct ProtocolError(u16, &'static str);
+
+// parses one client frame from the start of `buf`, None until the whole frame arrived
+fn parse_frame(buf: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, ProtocolError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[0] & 0x70 != 0 {
+        return Err(ProtocolError(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if buf[1] & 0x80 == 0 {
+        return Err(ProtocolError(
+            CLOSE_PROTOCOL_ERROR,
+            "client frames must be masked",
+        ));
+    }
+    if ![
+        OPCODE_CONTINUATION,
+        OPCODE_TEXT,
+        OPCODE_BINARY,
+        OPCODE_CLOSE,
+        OPCODE_PING,
+        OPCODE_PONG,
+    ]
+    .contains(&opcode)
+    {
+        return Err(ProtocolError(CLOSE_PROTOCOL_ERROR, "unknown opcode"));
+    }
+
+    let (payload_length, mut offset) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(length), 10)
+        }
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    // control frames can be sent in the middle of a fragmented message, so they can't be fragmented
+    if opcode & 0x8 != 0 && (!fin || payload_length > 125) {
+        return Err(ProtocolError(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if payload_length > max_payload as u64 {
+        return Err(ProtocolError(CLOSE_MESSAGE_TOO_BIG, "message too big"));
+    }
+    let payload_length = payload_length as usize;
+    if buf.len() < offset + 4 + payload_length {
+        return Ok(None);
+    }
+
+    let mask = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    offset += 4;
+    let payload = buf[offset..offset + payload_length]
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| byte ^ mask[index % 4])
+        .collect();
+
+    let frame = Frame {
+        fin,
+        opcode,
+        payload,
+    };
+    return Ok(Some((frame, offset + payload_length)));
+}
+
+// server frames are never masked
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    return frame;
+}
+
+// close codes a peer may send, RFC 6455 section 7.4
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+/*
+A server side WebSocket connection. Pings are answered, fragmented messages are
+reassembled and the close handshake is handled, so `recv` only returns data messages.
+*/
+pub struct WebSocket {
+    stream: Stream,
+    max_message_bytes: usize,
+    ping_interval: Duration,
+    // received bytes that don't make up a whole frame yet
+    read_buf: Vec<u8>,
+    // opcode and payload of a message that is still arriving in fragments
+    fragments: Option<(u8, Vec<u8>)>,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+    closed: bool,
+    // the code the connection was closed with, once closed
+    pub close_code: Option<u16>,
+}
+
+impl WebSocket {
+    // `received` holds bytes read with the handshake request
+    fn new(stream: Stream, received: Vec<u8>, config: &WebSocketConfig) -> Self {
+        return WebSocket {
+            stream,
+            max_message_bytes: config.max_message_bytes,
+            ping_interval: Duration::from_millis(config.ping_interval_ms),
+            read_buf: received,
+            fragments: None,
+            last_received: Instant::now(),
+            ping_sent: None,
+            closed: false,
+            close_code: None,
+        };
+    }
+
+    // the next message, None once the connection is closed
+    pub fn recv(&mut self) -> Option<Message> {
+        loop {
+            let frame = self.next_frame()?;
+            match frame.opcode {
+                OPCODE_PING => {
+                    let _ = self.write_frame(true, OPCODE_PONG, &frame.payload);
+                }
+                OPCODE_PONG => self.ping_sent = None,
+                OPCODE_CLOSE => {
+                    let code = match frame.payload.len() {
+                        0 => CLOSE_NORMAL,
+                        1 => return self.fail(CLOSE_PROTOCOL_ERROR, "invalid close frame"),
+                        _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                    };
+                    if !is_valid_close_code(code) {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, "invalid close code");
+                    }
+                    if std::str::from_utf8(frame.payload.get(2..).unwrap_or_default()).is_err() {
+                        return self.fail(CLOSE_INVALID_PAYLOAD, "close reason is not UTF-8");
+                    }
+                    // the close is echoed to complete the closing handshake
+                    self.close(code, "");
+                    return None;
+                }
+                OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, "expected a continuation frame");
+                }
+                OPCODE_TEXT | OPCODE_BINARY if frame.fin => {
+                    return self.finish_message(frame.opcode, frame.payload);
+                }
+                OPCODE_TEXT | OPCODE_BINARY => self.fragments = Some((frame.opcode, frame.payload)),
+                _ => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return self
+                                .fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation frame");
+                        }
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_bytes {
+                        return self.fail(CLOSE_MESSAGE_TOO_BIG, "message too big");
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    match frame.fin {
+                        true => return self.finish_message(opcode, payload),
+                        false => self.fragments = Some((opcode, payload)),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        let (opcode, payload) = match &message {
+            Message::Text(text) => (OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(bytes) => (OPCODE_BINARY, bytes.as_slice()),
+        };
+        if payload.len() <= MAX_FRAME_PAYLOAD {
+            return self.write_frame(true, opcode, payload);
+        }
+
+        let chunk_count = payload.len().div_ceil(MAX_FRAME_PAYLOAD);
+        for (index, chunk) in payload.chunks(MAX_FRAME_PAYLOAD).enumerate() {
+            let frame_opcode = if index == 0 {
+                opcode
+            } else {
+                OPCODE_CONTINUATION
+            };
+            self.write_frame(index == chunk_count - 1, frame_opcode, chunk)?;
+        }
+        return Ok(());
+    }
+
+    // sends a close frame if one wasn't sent yet, no messages can be sent afterwards
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.closed {
+            return;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let _ = self.write_frame(true, OPCODE_CLOSE, &payload);
+        self.closed = true;
+        self.close_code = Some(code);
+        self.stream.close();
+    }
+
+    fn fail(&mut self, code: u16, reason: &'static str) -> Option<Message> {
+        warn!(close_code = code, reason = reason; "Closing WebSocket");
+        self.close(code, reason);
+        return None;
+    }
+
+    fn finish_message(&mut self, opcode: u8, payload: Vec<u8>) -> Option<Message> {
+        if opcode == OPCODE_BINARY {
+            return Some(Message::Binary(payload));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => Some(Message::Text(text)),
+            Err(_) => self.fail(CLOSE_INVALID_PAYLOAD, "text message is not UTF-8"),
+        }
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        self.stream.write_all(&encode_frame(fin, opcode, payload))?;
+        return self.stream.flush();
+    }
+
+    fn next_frame(&mut self) -> Option<Frame> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if self.closed {
+                return None;
+            }
+            match parse_frame(&self.read_buf, self.max_message_bytes) {
+                Ok(Some((frame, frame_length))) => {
+                    self.read_buf.drain(..frame_length);
+                    return Some(frame);
+                }
+                Ok(None) => (),
+                Err(ProtocolError(code, reason)) => {
+                    self.fail(code, reason);
+                    return None;
+                }
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    return None;
+                }
+                Ok(read_size) => {
+                    self.read_buf.extend_from_slice(&chunk[..read_size]);
+                    self.last_received = Instant::now();
+                }
+                // the socket read timeout is used to check on shutdown and idle connections
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    if let Ok(flag) = SHUTDOWN_SERVER.read()
+                        && *flag
+                    {
+                        self.close(CLOSE_GOING_AWAY, "server shutting down");
+                        return None;
+                    }
+                    self.check_idle();
+                }
+                Err(_) => {
+                    self.closed = true;
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn check_idle(&mut self) {
+        if let Some(sent) = self.ping_sent {
+            if sent.elapsed() > self.ping_interval {
+                self.fail(CLOSE_GOING_AWAY, "ping timed out");
+            }
+            return;
+        }
+        if self.last_received.elapsed() > self.ping_interval
+            && self.write_frame(true, OPCODE_PING, &[]).is_ok()
+        {
+            self.ping_sent = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixStream, thread};
+
+    use super::*;
+    use crate::{
+        connection::Socket,
+        http::{RequestBody, RequestHead},
+    };
+
+    // a client frame, those are always masked
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(0x80 | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+        return frame;
+    }
+
+    fn masked_text_frame(text: &str) -> Vec<u8> {
+        return masked_frame(true, OPCODE_TEXT, text.as_bytes());
+    }
+
+    fn parse_error(buf: &[u8], max_payload: usize) -> u16 {
+        match parse_frame(buf, max_payload) {
+            Err(ProtocolError(code, _)) => code,
+            Ok(_) => panic!("the frame was accepted"),
+        }
+    }
+
+    // a server side socket that already received `frames`, and the client end of its connection
+    fn connect(frames: Vec<u8>, config: &WebSocketConfig) -> (WebSocket, UnixStream) {
+        let (client, server) = UnixStream::pair().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let stream = Stream::Plain(Socket::new(server.into(), Duration::from_millis(100)));
+        return (WebSocket::new(stream, frames, config), client);
+    }
+
+    fn read_frame(client: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).unwrap();
+        let mut payload = vec![0u8; (head[1] & 0x7F) as usize];
+        client.read_exact(&mut payload).unwrap();
+        return (head[0], payload);
+    }
+
+    #[test]
+    fn unmasked_client_frames_are_rejected() {
+        assert_eq!(
+            parse_error(&encode_frame(true, OPCODE_TEXT, b"hi"), 1024),
+            CLOSE_PROTOCOL_ERROR
+        );
+        let mut reserved = masked_text_frame("hi");
+        reserved[0] |= 0x40;
+        assert_eq!(parse_error(&reserved, 1024), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn extended_payload_lengths_are_parsed() {
+        for length in [125, 126, 65535, 65536, 70000] {
+            let payload = vec![b'a'; length];
+            let frame = masked_frame(true, OPCODE_BINARY, &payload);
+            let (parsed, consumed) = match parse_frame(&frame, 1 << 20) {
+                Ok(Some(parsed)) => parsed,
+                _ => panic!("a {} byte frame was not parsed", length),
+            };
+            assert_eq!(consumed, frame.len());
+            assert_eq!(parsed.payload, payload);
+            // nothing is returned until the whole frame arrived
+            assert!(matches!(
+                parse_frame(&frame[..frame.len() - 1], 1 << 20),
+                Ok(None)
+            ));
+        }
+        // the 16-bit and 64-bit lengths themselves can arrive in pieces
+        assert!(matches!(
+            parse_frame(&[0x82, 0x80 | 126, 0], 1024),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_frame(&[0x82, 0x80 | 127, 0, 0, 0], 1024),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_rejected() {
+        let frame = masked_frame(true, OPCODE_BINARY, &[0u8; 200]);
+        assert_eq!(parse_error(&frame, 199), CLOSE_MESSAGE_TOO_BIG);
+        // only the header is needed to reject it
+        assert_eq!(parse_error(&frame[..4], 199), CLOSE_MESSAGE_TOO_BIG);
+        let ping = masked_frame(true, OPCODE_PING, &[0u8; 126]);
+        assert_eq!(parse_error(&ping, 1024), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn encoded_frames_use_the_shortest_length() {
+        assert_eq!(
+            encode_frame(true, OPCODE_TEXT, &[0u8; 125])[..2],
+            [0x81, 125]
+        );
+        assert_eq!(
+            encode_frame(false, OPCODE_TEXT, &[0u8; 126])[..4],
+            [0x01, 126, 0, 126]
+        );
+        assert_eq!(
+            encode_frame(true, OPCODE_BINARY, &[0u8; 65535])[..4],
+            [0x82, 126, 0xFF, 0xFF]
+        );
+        let frame = encode_frame(true, OPCODE_BINARY, &[0u8; 65536]);
+        assert_eq!(frame[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 65536);
+    }
+
+    #[test]
+    fn fragments_are_reassembled_around_control_frames() {
+        let mut frames = masked_frame(false, OPCODE_TEXT, b"Hello");
+        frames.extend(masked_frame(true, OPCODE_PING, b"ping"));
+        frames.extend(masked_frame(false, OPCODE_CONTINUATION, b", "));
+        frames.extend(masked_frame(true, OPCODE_PONG, b""));
+        frames.extend(masked_frame(true, OPCODE_CONTINUATION, b"world"));
+        let (mut socket, mut client) = connect(frames, &WebSocketConfig::default());
+        socket.ping_sent = Some(Instant::now());
+
+        match socket.recv() {
+            Some(Message::Text(text)) => assert_eq!(text, "Hello, world"),
+            _ => panic!("the message was not reassembled"),
+        }
+        assert!(socket.ping_sent.is_none());
+        assert_eq!(
+            read_frame(&mut client),
+            (0x80 | OPCODE_PONG, b"ping".to_vec())
+        );
+    }
+
+    #[test]
+    fn close_frames_are_echoed_with_their_code() {
+        let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let frame = masked_frame(true, OPCODE_CLOSE, &payload);
+        let (mut socket, mut client) = connect(frame, &WebSocketConfig::default());
+        assert!(socket.recv().is_none());
+        assert_eq!(socket.close_code, Some(CLOSE_GOING_AWAY));
+        assert_eq!(
+            read_frame(&mut client),
+            (0x80 | OPCODE_CLOSE, CLOSE_GOING_AWAY.to_be_bytes().to_vec())
+        );
+
+        // 1005 only reports a close without a code, it is never sent
+        let frame = masked_frame(true, OPCODE_CLOSE, &1005u16.to_be_bytes());
+        let (mut socket, _client) = connect(frame, &WebSocketConfig::default());
+        assert!(socket.recv().is_none());
+        assert_eq!(socket.close_code, Some(CLOSE_PROTOCOL_ERROR));
+
+        let (mut socket, _client) = connect(
+            masked_frame(true, OPCODE_CLOSE, &[]),
+            &WebSocketConfig::default(),
+        );
+        assert!(socket.recv().is_none());
+        assert_eq!(socket.close_code, Some(CLOSE_NORMAL));
+    }
+
+    #[test]
+    fn oversize_messages_are_closed_with_1009() {
+        let config = WebSocketConfig {
+            max_message_bytes: 8,
+            ..WebSocketConfig::default()
+        };
+        // every fragment fits, the message doesn't
+        let mut frames = masked_frame(false, OPCODE_BINARY, b"12345");
+        frames.extend(masked_frame(true, OPCODE_CONTINUATION, b"67890"));
+        let (mut socket, mut client) = connect(frames, &config);
+        assert!(socket.recv().is_none());
+        assert_eq!(socket.close_code, Some(CLOSE_MESSAGE_TOO_BIG));
+        let (opcode, payload) = read_frame(&mut client);
+        assert_eq!(opcode, 0x80 | OPCODE_CLOSE);
+        assert_eq!(payload[..2], CLOSE_MESSAGE_TOO_BIG.to_be_bytes());
+    }
+
+    #[test]
+    fn invalid_utf8_text_is_closed_with_1007() {
+        let frame = masked_frame(true, OPCODE_TEXT, &[b'o', b'k', 0xC3, 0x28]);
+        let (mut socket, mut client) = connect(frame, &WebSocketConfig::default());
+        assert!(socket.recv().is_none());
+        assert_eq!(socket.close_code, Some(CLOSE_INVALID_PAYLOAD));
+        let (opcode, payload) = read_frame(&mut client);
+        assert_eq!(opcode, 0x80 | OPCODE_CLOSE);
+        assert_eq!(payload[..2], CLOSE_INVALID_PAYLOAD.to_be_bytes());
+
+        // binary messages aren't checked
+        let frame = masked_frame(true, OPCODE_BINARY, &[0xC3, 0x28]);
+        let (mut socket, _client) = connect(frame, &WebSocketConfig::default());
+        assert!(matches!(socket.recv(), Some(Message::Binary(_))));
+    }
+
+    #[test]
+    fn frames_sent_with_the_handshake_are_received() {
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "/ws/echo".to_string(),
+            version: "1.1".to_string(),
+            headers: [
+                ("Connection", "Upgrade"),
+                ("Upgrade", "websocket"),
+                ("Sec-WebSocket-Version", "13"),
+                ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        };
+        let req = head.into_request(RequestBody::from_bytes(Vec::new()));
+        let mut resp = WebSocketUpgrade::new(Echo, &WebSocketConfig::default()).handle(&req);
+        assert_eq!(resp.status, 101);
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let stream = Stream::Plain(Socket::new(server.into(), Duration::from_millis(100)));
+        let upgrade = resp.upgrade.take().unwrap();
+        // the frame arrived in the same read as the handshake, nothing else is sent
+        let socket_thread = thread::spawn(move || upgrade(stream, masked_text_frame("hi")));
+
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, [0x81, 2, b'h', b'i']);
+        drop(client);
+        socket_thread.join().unwrap();
+    }
+}